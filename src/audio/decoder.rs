@@ -0,0 +1,974 @@
+//! Audio decoding
+//!
+//! Every RTP payload carries exactly one codec frame. A [`Decoder`] turns a
+//! frame into interleaved PCM written into a caller-supplied buffer, so the
+//! playback path can reuse one output buffer for the whole session.
+//!
+//! Samples are left-justified: `i32` output uses the full 32-bit range
+//! whatever the stream's bit depth, and `i16` output keeps the top 16 bits.
+
+use symphonia::core::audio::{AudioBufferRef, AudioPlanes, Signal};
+use symphonia::core::codecs::{
+    CodecParameters, Decoder as _, DecoderOptions, CODEC_TYPE_AAC, CODEC_TYPE_ALAC,
+};
+use symphonia::core::conv::FromSample;
+use symphonia::core::formats::Packet;
+use symphonia::core::sample::Sample;
+use thiserror::Error;
+use tracing::debug;
+
+use super::format::{AudioFormat, Codec};
+use super::pcm::PcmDecoder;
+
+/// Decoder errors
+#[derive(Debug, Error)]
+pub enum DecoderError {
+    /// The codec configuration (fmtp, magic cookie) is malformed
+    #[error("Invalid decoder configuration: {0}")]
+    InvalidConfig(String),
+
+    /// The codec or configuration is not supported
+    #[error("Unsupported audio format: {0}")]
+    Unsupported(String),
+
+    /// The frame is malformed
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(String),
+
+    /// The frame could not be decoded
+    #[error("Decode error: {0}")]
+    Decode(#[from] symphonia::core::errors::Error),
+
+    /// The output buffer cannot hold the decoded frame
+    #[error("Output buffer too small: need {needed} samples, have {available}")]
+    BufferTooSmall { needed: usize, available: usize },
+}
+
+/// A codec that decodes single frames into interleaved PCM
+pub trait Decoder: Send {
+    /// Output sample rate in Hz
+    fn sample_rate(&self) -> u32;
+
+    /// Number of interleaved output channels
+    fn channels(&self) -> u16;
+
+    /// Frames per packet (`spf`); the output buffer needs `channels` times this
+    fn frames_per_packet(&self) -> usize;
+
+    /// Decode one frame into `out`, returning the number of frames written
+    fn decode_i16(&mut self, frame: &[u8], out: &mut [i16]) -> Result<usize, DecoderError>;
+
+    /// Decode one frame into `out`, returning the number of frames written
+    fn decode_i32(&mut self, frame: &[u8], out: &mut [i32]) -> Result<usize, DecoderError>;
+
+    /// Drop any inter-frame state, e.g. after a flush
+    fn reset(&mut self);
+}
+
+/// Copy planar decoder output into an interleaved buffer
+pub(crate) fn interleave<S>(
+    decoded: &AudioBufferRef<'_>,
+    out: &mut [S],
+) -> Result<usize, DecoderError>
+where
+    S: FromSample<i32> + FromSample<f32> + Copy,
+{
+    fn copy<T: Sample, S: Copy>(
+        planes: AudioPlanes<'_, T>,
+        frames: usize,
+        out: &mut [S],
+        convert: impl Fn(T) -> S,
+    ) -> Result<usize, DecoderError> {
+        let planes = planes.planes();
+        let needed = frames * planes.len();
+        if out.len() < needed {
+            return Err(DecoderError::BufferTooSmall {
+                needed,
+                available: out.len(),
+            });
+        }
+        for (ch, plane) in planes.iter().enumerate() {
+            for (dst, &src) in out[ch..needed].iter_mut().step_by(planes.len()).zip(*plane) {
+                *dst = convert(src);
+            }
+        }
+        Ok(frames)
+    }
+
+    match decoded {
+        AudioBufferRef::S32(buf) => copy(buf.planes(), buf.frames(), out, S::from_sample),
+        AudioBufferRef::F32(buf) => copy(buf.planes(), buf.frames(), out, S::from_sample),
+        _ => Err(DecoderError::Unsupported(
+            "unexpected decoder sample format".to_string(),
+        )),
+    }
+}
+
+/// Byte read as the end-of-frame element by both ALAC and AAC (`0b111`)
+const END_PADDING: u8 = 0xff;
+
+/// A symphonia packet refilled with each frame
+///
+/// symphonia packets own their data, so decoding straight from the network
+/// buffer would mean allocating a copy of every frame. The packet is
+/// allocated once and only grows for an unusually large frame; the bytes past
+/// the current frame are padding read as the end-of-frame element.
+struct FramePacket {
+    packet: Packet,
+    len: usize,
+}
+
+impl FramePacket {
+    fn new(capacity: usize, frames: u32) -> Self {
+        let data = vec![END_PADDING; capacity].into_boxed_slice();
+        Self {
+            packet: Packet::new_from_boxed_slice(0, 0, u64::from(frames), data),
+            len: 0,
+        }
+    }
+
+    fn fill(&mut self, frame: &[u8]) -> &Packet {
+        let data = &mut self.packet.data;
+        if frame.len() > data.len() {
+            *data = vec![END_PADDING; frame.len()].into_boxed_slice();
+        } else if frame.len() < self.len {
+            data[frame.len()..self.len].fill(END_PADDING);
+        }
+        data[..frame.len()].copy_from_slice(frame);
+        self.len = frame.len();
+        &self.packet
+    }
+}
+
+/// ALAC codec configuration
+///
+/// Carried either as the 24-byte magic cookie or as the equivalent `a=fmtp`
+/// parameter list, e.g. `96 352 0 16 40 10 14 2 255 0 0 44100`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlacConfig {
+    pub frame_length: u32,
+    pub compatible_version: u8,
+    pub bit_depth: u8,
+    pub pb: u8,
+    pub mb: u8,
+    pub kb: u8,
+    pub channels: u8,
+    pub max_run: u16,
+    pub max_frame_bytes: u32,
+    pub avg_bit_rate: u32,
+    pub sample_rate: u32,
+}
+
+impl AlacConfig {
+    /// Size of the magic cookie
+    pub const COOKIE_LEN: usize = 24;
+
+    /// Configuration with the encoder's default tuning parameters
+    ///
+    /// Used when a sender announces ALAC through the SETUP `audioFormat`
+    /// rather than an fmtp line.
+    pub fn new(sample_rate: u32, bit_depth: u8, channels: u8, frame_length: u32) -> Self {
+        Self {
+            frame_length,
+            compatible_version: 0,
+            bit_depth,
+            pb: 40,
+            mb: 10,
+            kb: 14,
+            channels,
+            max_run: 255,
+            max_frame_bytes: 0,
+            avg_bit_rate: 0,
+            sample_rate,
+        }
+    }
+
+    /// Parse the `a=fmtp` parameters, with or without the leading payload type
+    pub fn from_fmtp(fmtp: &str) -> Result<Self, DecoderError> {
+        let fields: Vec<u32> = fmtp
+            .split_whitespace()
+            .map(|f| f.parse::<u32>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| DecoderError::InvalidConfig(format!("bad ALAC fmtp '{}'", fmtp)))?;
+        let fields = match fields.len() {
+            11 => &fields[..],
+            12 => &fields[1..],
+            n => {
+                return Err(DecoderError::InvalidConfig(format!(
+                    "ALAC fmtp has {} fields, expected 11",
+                    n
+                )))
+            }
+        };
+
+        let narrow = |value: u32, name: &str| {
+            u8::try_from(value)
+                .map_err(|_| DecoderError::InvalidConfig(format!("ALAC {} out of range", name)))
+        };
+        let config = Self {
+            frame_length: fields[0],
+            compatible_version: narrow(fields[1], "compatibleVersion")?,
+            bit_depth: narrow(fields[2], "bitDepth")?,
+            pb: narrow(fields[3], "pb")?,
+            mb: narrow(fields[4], "mb")?,
+            kb: narrow(fields[5], "kb")?,
+            channels: narrow(fields[6], "numChannels")?,
+            max_run: u16::try_from(fields[7])
+                .map_err(|_| DecoderError::InvalidConfig("ALAC maxRun out of range".into()))?,
+            max_frame_bytes: fields[8],
+            avg_bit_rate: fields[9],
+            sample_rate: fields[10],
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse a magic cookie
+    ///
+    /// Accepts the bare 24-byte cookie as well as one still wrapped in its
+    /// `frma`/`alac` atom headers.
+    pub fn from_magic_cookie(cookie: &[u8]) -> Result<Self, DecoderError> {
+        let mut cookie = cookie;
+        while cookie.len() >= 12 && matches!(&cookie[4..8], b"frma" | b"alac") {
+            // 'frma' is followed by the format name, 'alac' by version/flags
+            cookie = &cookie[12..];
+        }
+        if cookie.len() < Self::COOKIE_LEN {
+            return Err(DecoderError::InvalidConfig(format!(
+                "ALAC magic cookie is {} bytes",
+                cookie.len()
+            )));
+        }
+
+        let u16_at = |i: usize| u16::from_be_bytes([cookie[i], cookie[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_be_bytes([cookie[i], cookie[i + 1], cookie[i + 2], cookie[i + 3]]);
+        let config = Self {
+            frame_length: u32_at(0),
+            compatible_version: cookie[4],
+            bit_depth: cookie[5],
+            pb: cookie[6],
+            mb: cookie[7],
+            kb: cookie[8],
+            channels: cookie[9],
+            max_run: u16_at(10),
+            max_frame_bytes: u32_at(12),
+            avg_bit_rate: u32_at(16),
+            sample_rate: u32_at(20),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Upper bound of an encoded frame: uncompressed samples plus headers
+    pub fn max_frame_len(&self) -> usize {
+        let samples = self.frame_length as usize * usize::from(self.channels);
+        let bytes = samples * usize::from(self.bit_depth).div_ceil(8) + 16;
+        bytes.max(self.max_frame_bytes as usize)
+    }
+
+    /// Serialize to the 24-byte magic cookie
+    pub fn to_magic_cookie(&self) -> [u8; Self::COOKIE_LEN] {
+        let mut cookie = [0u8; Self::COOKIE_LEN];
+        cookie[0..4].copy_from_slice(&self.frame_length.to_be_bytes());
+        cookie[4] = self.compatible_version;
+        cookie[5] = self.bit_depth;
+        cookie[6] = self.pb;
+        cookie[7] = self.mb;
+        cookie[8] = self.kb;
+        cookie[9] = self.channels;
+        cookie[10..12].copy_from_slice(&self.max_run.to_be_bytes());
+        cookie[12..16].copy_from_slice(&self.max_frame_bytes.to_be_bytes());
+        cookie[16..20].copy_from_slice(&self.avg_bit_rate.to_be_bytes());
+        cookie[20..24].copy_from_slice(&self.sample_rate.to_be_bytes());
+        cookie
+    }
+
+    fn validate(&self) -> Result<(), DecoderError> {
+        if self.frame_length == 0 || self.sample_rate == 0 {
+            return Err(DecoderError::InvalidConfig(
+                "ALAC frame length and sample rate must be non-zero".to_string(),
+            ));
+        }
+        if !matches!(self.bit_depth, 16 | 20 | 24 | 32) {
+            return Err(DecoderError::Unsupported(format!(
+                "ALAC bit depth {}",
+                self.bit_depth
+            )));
+        }
+        if !(1..=2).contains(&self.channels) {
+            return Err(DecoderError::Unsupported(format!(
+                "ALAC with {} channels",
+                self.channels
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// ALAC decoder backed by symphonia
+pub struct AlacDecoder {
+    config: AlacConfig,
+    inner: symphonia::default::codecs::AlacDecoder,
+    packet: FramePacket,
+}
+
+impl AlacDecoder {
+    /// Create a decoder for `config`
+    pub fn new(config: AlacConfig) -> Result<Self, DecoderError> {
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_ALAC)
+            .with_sample_rate(config.sample_rate)
+            .with_max_frames_per_packet(u64::from(config.frame_length))
+            .with_extra_data(Box::new(config.to_magic_cookie()));
+        let inner =
+            symphonia::default::codecs::AlacDecoder::try_new(&params, &DecoderOptions::default())?;
+        let packet = FramePacket::new(config.max_frame_len(), config.frame_length);
+        Ok(Self {
+            config,
+            inner,
+            packet,
+        })
+    }
+
+    /// Create a decoder from the ANNOUNCE `a=fmtp` parameters
+    pub fn from_fmtp(fmtp: &str) -> Result<Self, DecoderError> {
+        Self::new(AlacConfig::from_fmtp(fmtp)?)
+    }
+
+    /// Create a decoder from a SETUP or SETMAGICCOOKIE magic cookie
+    pub fn from_magic_cookie(cookie: &[u8]) -> Result<Self, DecoderError> {
+        Self::new(AlacConfig::from_magic_cookie(cookie)?)
+    }
+
+    /// The stream configuration
+    pub fn config(&self) -> &AlacConfig {
+        &self.config
+    }
+
+    fn decode_into<S>(&mut self, frame: &[u8], out: &mut [S]) -> Result<usize, DecoderError>
+    where
+        S: FromSample<i32> + FromSample<f32> + Copy,
+    {
+        let packet = self.packet.fill(frame);
+        let decoded = self.inner.decode(packet)?;
+        interleave(&decoded, out)
+    }
+}
+
+impl Decoder for AlacDecoder {
+    fn sample_rate(&self) -> u32 {
+        self.config.sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        u16::from(self.config.channels)
+    }
+
+    fn frames_per_packet(&self) -> usize {
+        self.config.frame_length as usize
+    }
+
+    fn decode_i16(&mut self, frame: &[u8], out: &mut [i16]) -> Result<usize, DecoderError> {
+        self.decode_into(frame, out)
+    }
+
+    fn decode_i32(&mut self, frame: &[u8], out: &mut [i32]) -> Result<usize, DecoderError> {
+        self.decode_into(frame, out)
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+}
+
+impl std::fmt::Debug for AlacDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AlacDecoder")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+/// MPEG-4 audio object types used by AirPlay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AacProfile {
+    /// AAC Low Complexity (object type 2)
+    Lc,
+    /// Enhanced Low Delay AAC (object type 39)
+    Eld,
+}
+
+impl AacProfile {
+    /// MPEG-4 audio object type
+    pub fn object_type(self) -> u8 {
+        match self {
+            AacProfile::Lc => 2,
+            AacProfile::Eld => 39,
+        }
+    }
+}
+
+/// Sampling frequency table from ISO/IEC 14496-3
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// AAC stream configuration
+///
+/// AirPlay sends raw access units without ADTS headers, so the decoder is
+/// primed with an AudioSpecificConfig derived from the SETUP `audioFormat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AacConfig {
+    pub profile: AacProfile,
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub frame_length: u32,
+}
+
+impl AacConfig {
+    /// Configuration for a negotiated AAC format
+    pub fn from_format(format: &AudioFormat) -> Result<Self, DecoderError> {
+        let profile = match format.codec {
+            Codec::AacLc => AacProfile::Lc,
+            Codec::AacEld => AacProfile::Eld,
+            other => {
+                return Err(DecoderError::InvalidConfig(format!(
+                    "{} is not an AAC format",
+                    other.name()
+                )))
+            }
+        };
+        Ok(Self {
+            profile,
+            sample_rate: format.sample_rate,
+            channels: format.channels,
+            frame_length: format.frames_per_packet,
+        })
+    }
+
+    /// Encode the AudioSpecificConfig
+    pub fn audio_specific_config(&self) -> Result<Vec<u8>, DecoderError> {
+        let rate_index = AAC_SAMPLE_RATES
+            .iter()
+            .position(|&r| r == self.sample_rate)
+            .ok_or_else(|| {
+                DecoderError::Unsupported(format!("AAC sample rate {}", self.sample_rate))
+            })?;
+        if !(1..=2).contains(&self.channels) {
+            return Err(DecoderError::Unsupported(format!(
+                "AAC with {} channels",
+                self.channels
+            )));
+        }
+
+        let mut bits = 0u64;
+        let mut len = 0u32;
+        let mut put = |value: u64, width: u32| {
+            bits = (bits << width) | value;
+            len += width;
+        };
+
+        let object_type = u64::from(self.profile.object_type());
+        if object_type < 31 {
+            put(object_type, 5);
+        } else {
+            put(31, 5);
+            put(object_type - 32, 6);
+        }
+        put(rate_index as u64, 4);
+        put(u64::from(self.channels), 4);
+
+        match (self.profile, self.frame_length) {
+            // GASpecificConfig: frameLengthFlag, dependsOnCoreCoder, extensionFlag
+            (AacProfile::Lc, 1024) => put(0b000, 3),
+            (AacProfile::Lc, 960) => put(0b100, 3),
+            // ELDSpecificConfig: frameLengthFlag, three resilience flags,
+            // ldSbrPresentFlag and ELDEXT_TERM
+            (AacProfile::Eld, 480) => {
+                put(0b10000, 5);
+                put(0, 4);
+            }
+            (AacProfile::Eld, 512) => {
+                put(0b00000, 5);
+                put(0, 4);
+            }
+            (profile, frames) => {
+                return Err(DecoderError::Unsupported(format!(
+                    "{:?} with {} samples per frame",
+                    profile, frames
+                )))
+            }
+        }
+
+        let padding = (8 - len % 8) % 8;
+        let bytes = (len + padding) / 8;
+        let bits = bits << padding;
+        Ok((0..bytes).rev().map(|i| (bits >> (8 * i)) as u8).collect())
+    }
+}
+
+/// AAC-LC decoder backed by symphonia
+///
+/// symphonia has no AAC-ELD support; ELD needs another [`DecoderBackend`].
+pub struct AacDecoder {
+    config: AacConfig,
+    inner: symphonia::default::codecs::AacDecoder,
+    packet: FramePacket,
+}
+
+impl AacDecoder {
+    /// Create a decoder for `config`
+    pub fn new(config: AacConfig) -> Result<Self, DecoderError> {
+        if config.profile != AacProfile::Lc {
+            return Err(DecoderError::Unsupported(format!(
+                "{:?} is not supported by the symphonia AAC decoder",
+                config.profile
+            )));
+        }
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_AAC)
+            .with_sample_rate(config.sample_rate)
+            .with_max_frames_per_packet(u64::from(config.frame_length))
+            .with_extra_data(config.audio_specific_config()?.into_boxed_slice());
+        let inner =
+            symphonia::default::codecs::AacDecoder::try_new(&params, &DecoderOptions::default())?;
+        // 6144 bits per channel is the AAC buffer requirement
+        let packet = FramePacket::new(768 * usize::from(config.channels), config.frame_length);
+        Ok(Self {
+            config,
+            inner,
+            packet,
+        })
+    }
+
+    /// The stream configuration
+    pub fn config(&self) -> &AacConfig {
+        &self.config
+    }
+
+    fn decode_into<S>(&mut self, frame: &[u8], out: &mut [S]) -> Result<usize, DecoderError>
+    where
+        S: FromSample<i32> + FromSample<f32> + Copy,
+    {
+        let packet = self.packet.fill(frame);
+        let decoded = self.inner.decode(packet)?;
+        interleave(&decoded, out)
+    }
+}
+
+impl Decoder for AacDecoder {
+    fn sample_rate(&self) -> u32 {
+        self.config.sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        u16::from(self.config.channels)
+    }
+
+    fn frames_per_packet(&self) -> usize {
+        self.config.frame_length as usize
+    }
+
+    fn decode_i16(&mut self, frame: &[u8], out: &mut [i16]) -> Result<usize, DecoderError> {
+        self.decode_into(frame, out)
+    }
+
+    fn decode_i32(&mut self, frame: &[u8], out: &mut [i32]) -> Result<usize, DecoderError> {
+        self.decode_into(frame, out)
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+}
+
+impl std::fmt::Debug for AacDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AacDecoder")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A source of decoder implementations
+///
+/// Formats no registered backend supports are refused at SETUP rather than
+/// played as noise. A backend wrapping a native codec library (e.g. for
+/// AAC-ELD) can be registered alongside the built-in one.
+pub trait DecoderBackend: Send + Sync {
+    /// Backend name for logging
+    fn name(&self) -> &'static str;
+
+    /// Whether this backend can decode `format`
+    fn supports(&self, format: &AudioFormat) -> bool;
+
+    /// Create a decoder for `format`
+    fn create(&self, format: &AudioFormat) -> Result<Box<dyn Decoder>, DecoderError>;
+}
+
+/// Decoders built into the receiver: PCM, plus ALAC and AAC-LC via symphonia
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BuiltinBackend;
+
+impl DecoderBackend for BuiltinBackend {
+    fn name(&self) -> &'static str {
+        "builtin"
+    }
+
+    fn supports(&self, format: &AudioFormat) -> bool {
+        match format.codec {
+            Codec::Pcm => matches!(format.bits_per_sample, 16 | 24),
+            Codec::Alac | Codec::AacLc => true,
+            Codec::AacEld | Codec::Opus => false,
+        }
+    }
+
+    fn create(&self, format: &AudioFormat) -> Result<Box<dyn Decoder>, DecoderError> {
+        match format.codec {
+            Codec::Pcm => Ok(Box::new(PcmDecoder::new(*format)?)),
+            Codec::Alac => Ok(Box::new(AlacDecoder::new(AlacConfig::new(
+                format.sample_rate,
+                format.bits_per_sample,
+                format.channels,
+                format.frames_per_packet,
+            ))?)),
+            Codec::AacLc => Ok(Box::new(AacDecoder::new(AacConfig::from_format(format)?)?)),
+            _ => Err(DecoderError::Unsupported(format.to_string())),
+        }
+    }
+}
+
+/// Ordered set of decoder backends
+///
+/// Backends registered later take precedence over earlier ones, so an
+/// external backend can replace a built-in decoder.
+pub struct DecoderRegistry {
+    backends: Vec<Box<dyn DecoderBackend>>,
+}
+
+impl DecoderRegistry {
+    /// A registry with no backends
+    pub fn empty() -> Self {
+        Self {
+            backends: Vec::new(),
+        }
+    }
+
+    /// Add a backend, taking precedence over those already registered
+    pub fn register(&mut self, backend: Box<dyn DecoderBackend>) {
+        self.backends.insert(0, backend);
+    }
+
+    /// Whether any backend can decode `format`
+    pub fn supports(&self, format: &AudioFormat) -> bool {
+        self.backends.iter().any(|b| b.supports(format))
+    }
+
+    /// Create a decoder for `format` from the first backend supporting it
+    pub fn create(&self, format: &AudioFormat) -> Result<Box<dyn Decoder>, DecoderError> {
+        let backend = self
+            .backends
+            .iter()
+            .find(|b| b.supports(format))
+            .ok_or_else(|| {
+                DecoderError::Unsupported(format!("no decoder backend for {}", format))
+            })?;
+        debug!("Using {} decoder backend for {}", backend.name(), format);
+        backend.create(format)
+    }
+}
+
+impl Default for DecoderRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register(Box::new(BuiltinBackend));
+        registry
+    }
+}
+
+impl std::fmt::Debug for DecoderRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.backends.iter().map(|b| b.name()))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FMTP: &str = "96 352 0 16 40 10 14 2 255 0 0 44100";
+
+    /// MSB-first bit writer for building ALAC frames
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn put(&mut self, value: u32, width: u32) {
+            for i in (0..width).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                if (value >> i) & 1 == 1 {
+                    *self.bytes.last_mut().unwrap() |= 0x80 >> (self.bits % 8);
+                }
+                self.bits += 1;
+            }
+        }
+
+        fn element_header(&mut self, tag: u32, partial: Option<u32>, uncompressed: bool) {
+            self.put(tag, 3);
+            self.put(0, 4); // instance
+            self.put(0, 12); // unused
+            self.put(partial.is_some() as u32, 1);
+            self.put(0, 2); // shift
+            self.put(uncompressed as u32, 1);
+            if let Some(samples) = partial {
+                self.put(samples, 32);
+            }
+        }
+
+        /// Residuals for one channel, every value escape-coded at `pred_bits`
+        fn escaped_residuals(&mut self, samples: &[i32], pred_bits: u32, config: &AlacConfig) {
+            let pb_factor = (4 * u32::from(config.pb)) >> 2;
+            let mut mb = u32::from(config.mb);
+            for &s in samples {
+                let val = if s >= 0 {
+                    2 * s as u32
+                } else {
+                    (-2 * s - 1) as u32
+                };
+                self.put(0x1ff, 9);
+                self.put(val, pred_bits);
+                if val > 0xffff {
+                    mb = 0xffff;
+                } else {
+                    mb -= (pb_factor * mb) >> 9;
+                    mb += pb_factor * val;
+                }
+                assert!(mb >= 128, "fixture would need a zero run");
+            }
+        }
+
+        fn finish(mut self) -> Vec<u8> {
+            self.put(7, 3); // END
+            self.bytes
+        }
+    }
+
+    /// Deterministic sawtooth pair, kept away from zero so no zero runs occur
+    fn stereo_fixture(frames: usize) -> (Vec<i32>, Vec<i32>) {
+        let nonzero = |s: i32| if s.abs() < 4 { s + 8 } else { s };
+        let left = (0..frames as i32)
+            .map(|i| nonzero(i * 181 % 30000 - 15000))
+            .collect();
+        let right = (0..frames as i32)
+            .map(|i| nonzero(16000 - i * 733 % 32000))
+            .collect();
+        (left, right)
+    }
+
+    #[test]
+    fn test_alac_config_from_fmtp_and_cookie() {
+        let config = AlacConfig::from_fmtp(FMTP).unwrap();
+        assert_eq!(config, AlacConfig::new(44100, 16, 2, 352));
+        assert_eq!(
+            config.to_magic_cookie(),
+            [
+                0x00, 0x00, 0x01, 0x60, 0x00, 0x10, 0x28, 0x0a, 0x0e, 0x02, 0x00, 0xff, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xac, 0x44,
+            ]
+        );
+
+        // Cookie wrapped in its 'alac' atom header
+        let mut atom = vec![0x00, 0x00, 0x00, 0x24, b'a', b'l', b'a', b'c', 0, 0, 0, 0];
+        atom.extend_from_slice(&config.to_magic_cookie());
+        assert_eq!(AlacConfig::from_magic_cookie(&atom).unwrap(), config);
+
+        assert!(AlacConfig::from_fmtp("96 352 0 16").is_err());
+        assert!(AlacConfig::from_magic_cookie(&[0u8; 10]).is_err());
+        assert!(matches!(
+            AlacConfig::from_fmtp("352 0 16 40 10 14 6 255 0 0 44100"),
+            Err(DecoderError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_alac_decodes_compressed_frame() {
+        let mut decoder = AlacDecoder::from_fmtp(FMTP).unwrap();
+        assert_eq!(decoder.frames_per_packet(), 352);
+        let config = *decoder.config();
+        let (left, right) = stereo_fixture(352);
+
+        // CPE, no mid/side mixing, order-0 predictor for both channels
+        let mut w = BitWriter::default();
+        w.element_header(1, None, false);
+        w.put(0, 8); // mid/side shift
+        w.put(0, 8); // mid/side weight
+        for _ in 0..2 {
+            w.put(0, 4); // mode
+            w.put(0, 4); // prediction shift
+            w.put(4, 3); // pb factor
+            w.put(0, 5); // lpc order
+        }
+        w.escaped_residuals(&left, 17, &config);
+        w.escaped_residuals(&right, 17, &config);
+        let frame = w.finish();
+
+        let mut out = vec![0i16; 352 * 2];
+        assert_eq!(decoder.decode_i16(&frame, &mut out).unwrap(), 352);
+        for (i, pair) in out.chunks_exact(2).enumerate() {
+            assert_eq!(pair, [left[i] as i16, right[i] as i16], "frame {}", i);
+        }
+
+        let mut wide = vec![0i32; 352 * 2];
+        assert_eq!(decoder.decode_i32(&frame, &mut wide).unwrap(), 352);
+        assert_eq!(wide[0], left[0] << 16);
+        assert_eq!(wide[703], right[351] << 16);
+
+        let mut short = vec![0i16; 100];
+        assert!(matches!(
+            decoder.decode_i16(&frame, &mut short),
+            Err(DecoderError::BufferTooSmall { needed: 704, .. })
+        ));
+    }
+
+    #[test]
+    fn test_alac_decodes_encoder_output() {
+        // One 352-frame packet from Apple's reference encoder (via the
+        // alac-encoder port): mid/side mixing and order-4 LPC on both channels
+        const COOKIE: &[u8] = include_bytes!("testdata/alac_44100_s16_stereo.cookie");
+        const FRAME: &[u8] = include_bytes!("testdata/alac_44100_s16_stereo.frame");
+        const PCM: &[u8] = include_bytes!("testdata/alac_44100_s16_stereo.pcm");
+
+        let mut decoder = AlacDecoder::from_magic_cookie(COOKIE).unwrap();
+        let expected: Vec<i16> = PCM
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        let data = decoder.packet.packet.data.as_ptr();
+
+        let mut out = vec![0i16; 352 * 2];
+        for _ in 0..2 {
+            assert_eq!(decoder.decode_i16(FRAME, &mut out).unwrap(), 352);
+            assert_eq!(out, expected);
+        }
+        // A shorter frame reuses the packet and sees padding, not stale bytes
+        let mut w = BitWriter::default();
+        w.element_header(1, Some(1), true);
+        w.put(0x1234, 16);
+        w.put(0x4321, 16);
+        assert_eq!(decoder.decode_i16(&w.finish(), &mut out).unwrap(), 1);
+        assert_eq!(out[..2], [0x1234, 0x4321]);
+        assert_eq!(decoder.packet.packet.data.as_ptr(), data);
+    }
+
+    #[test]
+    fn test_alac_decodes_uncompressed_partial_frame() {
+        let config = AlacConfig::new(48000, 24, 2, 4096);
+        let mut decoder = AlacDecoder::from_magic_cookie(&config.to_magic_cookie()).unwrap();
+        let (left, right) = stereo_fixture(1000);
+        let (left, right): (Vec<i32>, Vec<i32>) = (
+            left.iter().map(|s| s * 200).collect(),
+            right.iter().map(|s| s * 200).collect(),
+        );
+
+        let mut w = BitWriter::default();
+        w.element_header(1, Some(1000), true);
+        for (l, r) in left.iter().zip(&right) {
+            w.put(*l as u32 & 0xff_ffff, 24);
+            w.put(*r as u32 & 0xff_ffff, 24);
+        }
+        let frame = w.finish();
+
+        let mut out = vec![0i32; 4096 * 2];
+        assert_eq!(decoder.decode_i32(&frame, &mut out).unwrap(), 1000);
+        for (i, pair) in out[..2000].chunks_exact(2).enumerate() {
+            assert_eq!(pair, [left[i] << 8, right[i] << 8], "frame {}", i);
+        }
+    }
+
+    #[test]
+    fn test_aac_audio_specific_config() {
+        let lc = AudioFormat::from_bitmask(1 << 22).unwrap();
+        let config = AacConfig::from_format(&lc).unwrap();
+        assert_eq!(config.audio_specific_config().unwrap(), [0x12, 0x10]);
+
+        let lc48 = AudioFormat::from_bitmask(1 << 23).unwrap();
+        let config = AacConfig::from_format(&lc48).unwrap();
+        assert_eq!(config.audio_specific_config().unwrap(), [0x11, 0x90]);
+
+        let eld = AudioFormat::from_bitmask(1 << 24).unwrap();
+        let config = AacConfig::from_format(&eld).unwrap();
+        assert_eq!(
+            config.audio_specific_config().unwrap(),
+            [0xf8, 0xe8, 0x50, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_aac_lc_decodes_raw_access_unit() {
+        let format = AudioFormat::from_bitmask(1 << 22).unwrap();
+        let mut decoder = DecoderRegistry::default().create(&format).unwrap();
+        assert_eq!(decoder.frames_per_packet(), 1024);
+
+        // Silent CPE: common long window, no scale factor bands
+        let mut w = BitWriter::default();
+        w.put(1, 3); // ID_CPE
+        w.put(0, 4); // instance
+        w.put(1, 1); // common_window
+        w.put(0, 1); // ics_reserved_bit
+        w.put(0, 2); // ONLY_LONG_SEQUENCE
+        w.put(0, 1); // window shape
+        w.put(0, 6); // max_sfb
+        w.put(0, 1); // predictor data
+        w.put(0, 2); // ms_mask_present
+        for _ in 0..2 {
+            w.put(100, 8); // global gain
+            w.put(0, 3); // pulse, tns, gain control
+        }
+        let frame = w.finish();
+
+        let mut out = vec![1i16; 2048];
+        assert_eq!(decoder.decode_i16(&frame, &mut out).unwrap(), 1024);
+        assert!(out.iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn test_registry_refuses_eld_without_backend() {
+        struct EldBackend;
+
+        impl DecoderBackend for EldBackend {
+            fn name(&self) -> &'static str {
+                "eld"
+            }
+
+            fn supports(&self, format: &AudioFormat) -> bool {
+                format.codec == Codec::AacEld
+            }
+
+            fn create(&self, format: &AudioFormat) -> Result<Box<dyn Decoder>, DecoderError> {
+                Err(DecoderError::Unsupported(format!("stub for {}", format)))
+            }
+        }
+
+        let eld = AudioFormat::from_bitmask(1 << 24).unwrap();
+        let mut registry = DecoderRegistry::default();
+        assert!(!registry.supports(&eld));
+        let err = registry.create(&eld).err().unwrap();
+        assert!(err.to_string().contains("no decoder backend for AAC-ELD"));
+
+        registry.register(Box::new(EldBackend));
+        assert!(registry.supports(&eld));
+        let err = registry.create(&eld).err().unwrap();
+        assert!(err.to_string().contains("stub"));
+        assert!(AacDecoder::new(AacConfig::from_format(&eld).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+//! Audio stream formats
+//!
+//! Buffered (AirPlay 2) senders choose the stream format in SETUP: `ct` is
+//! the compression type and `audioFormat` a single bit that fixes codec,
+//! sample rate, bit depth and channel count. Realtime senders describe the
+//! stream with SDP instead (see [`crate::protocol::sdp`]).
+
+use plist::Dictionary;
+use thiserror::Error;
+
+use super::decoder::AlacConfig;
+use crate::protocol::sdp::SessionDescription;
+use crate::streaming::session::plist_u64;
+
+/// Format negotiation errors
+#[derive(Debug, Error)]
+pub enum FormatError {
+    /// A required SETUP field is missing
+    #[error("Missing stream field: {0}")]
+    MissingField(&'static str),
+
+    /// The `audioFormat` value is not a known single format bit
+    #[error("Unknown audioFormat 0x{0:x}")]
+    UnknownFormat(u64),
+
+    /// The `ct` value is not a known compression type
+    #[error("Unknown compression type {0}")]
+    UnknownCompressionType(u64),
+
+    /// The ANNOUNCE session description is unusable
+    #[error("Invalid SDP: {0}")]
+    InvalidSdp(String),
+
+    /// The SDP `rtpmap` names an encoding the receiver does not handle
+    #[error("Unsupported encoding '{0}'")]
+    UnsupportedEncoding(String),
+
+    /// `ct` and `audioFormat` name different codecs
+    #[error("Compression type {ct} does not match audioFormat 0x{format:x}")]
+    Mismatch { ct: u64, format: u64 },
+}
+
+/// Audio codec
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Codec {
+    Pcm,
+    Alac,
+    AacLc,
+    AacEld,
+    Opus,
+}
+
+impl Codec {
+    /// Codec for a SETUP `ct` value
+    pub fn from_compression_type(ct: u64) -> Option<Self> {
+        match ct {
+            1 => Some(Codec::Pcm),
+            2 => Some(Codec::Alac),
+            4 => Some(Codec::AacLc),
+            8 => Some(Codec::AacEld),
+            32 => Some(Codec::Opus),
+            _ => None,
+        }
+    }
+
+    /// SETUP `ct` value for this codec
+    pub fn compression_type(self) -> u64 {
+        match self {
+            Codec::Pcm => 1,
+            Codec::Alac => 2,
+            Codec::AacLc => 4,
+            Codec::AacEld => 8,
+            Codec::Opus => 32,
+        }
+    }
+
+    /// Human readable name
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Pcm => "PCM",
+            Codec::Alac => "ALAC",
+            Codec::AacLc => "AAC-LC",
+            Codec::AacEld => "AAC-ELD",
+            Codec::Opus => "Opus",
+        }
+    }
+}
+
+/// `audioFormat` bits: (bit, codec, sample rate, bits per sample, channels)
+const AUDIO_FORMATS: &[(u32, Codec, u32, u8, u8)] = &[
+    (2, Codec::Pcm, 8000, 16, 1),
+    (3, Codec::Pcm, 8000, 16, 2),
+    (4, Codec::Pcm, 16000, 16, 1),
+    (5, Codec::Pcm, 16000, 16, 2),
+    (6, Codec::Pcm, 24000, 16, 1),
+    (7, Codec::Pcm, 24000, 16, 2),
+    (8, Codec::Pcm, 32000, 16, 1),
+    (9, Codec::Pcm, 32000, 16, 2),
+    (10, Codec::Pcm, 44100, 16, 1),
+    (11, Codec::Pcm, 44100, 16, 2),
+    (12, Codec::Pcm, 44100, 24, 1),
+    (13, Codec::Pcm, 44100, 24, 2),
+    (14, Codec::Pcm, 48000, 16, 1),
+    (15, Codec::Pcm, 48000, 16, 2),
+    (16, Codec::Pcm, 48000, 24, 1),
+    (17, Codec::Pcm, 48000, 24, 2),
+    (18, Codec::Alac, 44100, 16, 2),
+    (19, Codec::Alac, 44100, 24, 2),
+    (20, Codec::Alac, 48000, 16, 2),
+    (21, Codec::Alac, 48000, 24, 2),
+    (22, Codec::AacLc, 44100, 16, 2),
+    (23, Codec::AacLc, 48000, 16, 2),
+    (24, Codec::AacEld, 44100, 16, 2),
+    (25, Codec::AacEld, 48000, 16, 2),
+    (26, Codec::AacEld, 16000, 16, 1),
+    (27, Codec::AacEld, 24000, 16, 1),
+    (28, Codec::Opus, 16000, 16, 1),
+    (29, Codec::Opus, 24000, 16, 1),
+    (30, Codec::Opus, 48000, 16, 1),
+    (31, Codec::AacEld, 44100, 16, 1),
+    (32, Codec::AacEld, 48000, 16, 1),
+];
+
+/// A negotiated stream format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub codec: Codec,
+    pub sample_rate: u32,
+    pub bits_per_sample: u8,
+    pub channels: u8,
+    /// Frames per packet (`spf`)
+    pub frames_per_packet: u32,
+}
+
+impl AudioFormat {
+    /// Look up a single `audioFormat` bit
+    ///
+    /// The packet size defaults to the codec's usual frame length; buffered
+    /// senders override it with `spf`.
+    pub fn from_bitmask(mask: u64) -> Option<Self> {
+        if !mask.is_power_of_two() {
+            return None;
+        }
+        let bit = mask.trailing_zeros();
+        AUDIO_FORMATS.iter().find(|(b, ..)| *b == bit).map(
+            |&(_, codec, sample_rate, bits_per_sample, channels)| Self {
+                codec,
+                sample_rate,
+                bits_per_sample,
+                channels,
+                frames_per_packet: match codec {
+                    Codec::Pcm | Codec::Alac => 352,
+                    Codec::AacLc => 1024,
+                    Codec::AacEld => 480,
+                    // 20 ms
+                    Codec::Opus => sample_rate / 50,
+                },
+            },
+        )
+    }
+
+    /// The `audioFormat` bit for this format, if it has one
+    pub fn bitmask(&self) -> Option<u64> {
+        AUDIO_FORMATS
+            .iter()
+            .find(|&&(_, codec, rate, bits, channels)| {
+                codec == self.codec
+                    && rate == self.sample_rate
+                    && bits == self.bits_per_sample
+                    && channels == self.channels
+            })
+            .map(|(bit, ..)| 1 << bit)
+    }
+
+    /// Parse the format from a SETUP stream description
+    ///
+    /// Uses `audioFormat`, checks it against `ct` when both are present and
+    /// applies `spf`.
+    pub fn from_stream_plist(stream: &Dictionary) -> Result<Self, FormatError> {
+        let mask =
+            plist_u64(stream.get("audioFormat")).ok_or(FormatError::MissingField("audioFormat"))?;
+        let mut format = Self::from_bitmask(mask).ok_or(FormatError::UnknownFormat(mask))?;
+
+        if let Some(ct) = plist_u64(stream.get("ct")) {
+            let codec =
+                Codec::from_compression_type(ct).ok_or(FormatError::UnknownCompressionType(ct))?;
+            if codec != format.codec {
+                return Err(FormatError::Mismatch { ct, format: mask });
+            }
+        }
+        if let Some(spf) = plist_u64(stream.get("spf")).filter(|&spf| spf > 0) {
+            format.frames_per_packet = spf as u32;
+        }
+        Ok(format)
+    }
+}
+
+impl AudioFormat {
+    /// Parse the format from an ANNOUNCE session description
+    ///
+    /// Handles `L16`/`L24` PCM and ALAC (`AppleLossless` plus its fmtp).
+    pub fn from_sdp(sdp: &SessionDescription) -> Result<Self, FormatError> {
+        let rtpmap = sdp
+            .rtpmap()
+            .ok_or(FormatError::MissingField("rtpmap"))?
+            .map_err(|e| FormatError::InvalidSdp(e.to_string()))?;
+
+        match rtpmap.encoding.to_ascii_uppercase().as_str() {
+            encoding @ ("L16" | "L24") => {
+                let sample_rate = rtpmap
+                    .clock_rate
+                    .ok_or(FormatError::MissingField("rtpmap clock rate"))?;
+                if sample_rate == 0 {
+                    return Err(FormatError::InvalidSdp("zero clock rate".to_string()));
+                }
+                // RFC 3551: one channel unless stated otherwise
+                let channels = u8::try_from(rtpmap.channels.unwrap_or(1))
+                    .map_err(|_| FormatError::InvalidSdp("too many channels".to_string()))?;
+                Ok(Self {
+                    codec: Codec::Pcm,
+                    sample_rate,
+                    bits_per_sample: if encoding == "L16" { 16 } else { 24 },
+                    channels,
+                    frames_per_packet: 352,
+                })
+            }
+            "APPLELOSSLESS" => {
+                let fmtp = sdp.fmtp().ok_or(FormatError::MissingField("fmtp"))?;
+                let config = AlacConfig::from_fmtp(fmtp)
+                    .map_err(|e| FormatError::InvalidSdp(e.to_string()))?;
+                Ok(Self {
+                    codec: Codec::Alac,
+                    sample_rate: config.sample_rate,
+                    bits_per_sample: config.bit_depth,
+                    channels: config.channels,
+                    frames_per_packet: config.frame_length,
+                })
+            }
+            _ => Err(FormatError::UnsupportedEncoding(rtpmap.encoding)),
+        }
+    }
+}
+
+impl std::fmt::Display for AudioFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}/{}",
+            self.codec.name(),
+            self.sample_rate,
+            self.bits_per_sample,
+            self.channels
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plist::Value;
+
+    #[test]
+    fn test_audio_format_bitmask() {
+        let lc = AudioFormat::from_bitmask(1 << 22).unwrap();
+        assert_eq!(lc.codec, Codec::AacLc);
+        assert_eq!((lc.sample_rate, lc.channels), (44100, 2));
+        assert_eq!(lc.frames_per_packet, 1024);
+        assert_eq!(lc.bitmask(), Some(1 << 22));
+        assert_eq!(lc.to_string(), "AAC-LC/44100/16/2");
+
+        let eld = AudioFormat::from_bitmask(1 << 32).unwrap();
+        assert_eq!(
+            (eld.codec, eld.sample_rate, eld.channels),
+            (Codec::AacEld, 48000, 1)
+        );
+
+        assert!(AudioFormat::from_bitmask(0).is_none());
+        assert!(AudioFormat::from_bitmask((1 << 22) | (1 << 23)).is_none());
+        assert!(AudioFormat::from_bitmask(1).is_none());
+    }
+
+    #[test]
+    fn test_audio_format_from_setup() {
+        let mut stream = Dictionary::new();
+        stream.insert("audioFormat".into(), Value::Integer((1u64 << 18).into()));
+        stream.insert("ct".into(), Value::Integer(2.into()));
+        stream.insert("spf".into(), Value::Integer(4096.into()));
+        let format = AudioFormat::from_stream_plist(&stream).unwrap();
+        assert_eq!(format.codec, Codec::Alac);
+        assert_eq!(format.frames_per_packet, 4096);
+
+        stream.insert("ct".into(), Value::Integer(4.into()));
+        assert!(matches!(
+            AudioFormat::from_stream_plist(&stream),
+            Err(FormatError::Mismatch { ct: 4, .. })
+        ));
+
+        stream.remove("audioFormat");
+        assert!(AudioFormat::from_stream_plist(&stream).is_err());
+
+        stream.insert("audioFormat".into(), Value::Integer((1u64 << 13).into()));
+        stream.insert("ct".into(), Value::Integer(1.into()));
+        let pcm = AudioFormat::from_stream_plist(&stream).unwrap();
+        assert_eq!((pcm.codec, pcm.bits_per_sample), (Codec::Pcm, 24));
+    }
+
+    #[test]
+    fn test_audio_format_from_sdp() {
+        let announce = |attrs: &str| {
+            SessionDescription::parse(&format!("v=0\r\nm=audio 0 RTP/AVP 96\r\n{}", attrs)).unwrap()
+        };
+
+        let l16 = AudioFormat::from_sdp(&announce("a=rtpmap:96 L16/44100/2\r\n")).unwrap();
+        assert_eq!(l16.bitmask(), Some(1 << 11));
+        let l16 = AudioFormat::from_sdp(&announce("a=rtpmap:96 L16/48000/2\r\n")).unwrap();
+        assert_eq!(l16.bitmask(), Some(1 << 15));
+        let l24 = AudioFormat::from_sdp(&announce("a=rtpmap:96 L24/48000/2\r\n")).unwrap();
+        assert_eq!(l24.bitmask(), Some(1 << 17));
+
+        let alac = AudioFormat::from_sdp(&announce(
+            "a=rtpmap:96 AppleLossless\r\na=fmtp:96 352 0 16 40 10 14 2 255 0 0 44100\r\n",
+        ))
+        .unwrap();
+        assert_eq!(alac.bitmask(), Some(1 << 18));
+
+        assert!(matches!(
+            AudioFormat::from_sdp(&announce("a=rtpmap:96 L16/0/2\r\n")),
+            Err(FormatError::InvalidSdp(_))
+        ));
+        assert!(matches!(
+            AudioFormat::from_sdp(&announce("a=rtpmap:96 mpeg4-generic/44100/2\r\n")),
+            Err(FormatError::UnsupportedEncoding(_))
+        ));
+    }
+}
@@ -0,0 +1,213 @@
+//! Hardware volume via the ALSA simple mixer
+//!
+//! DAC boards usually expose a hardware volume control ("Digital", "PCM",
+//! ...). Moving it instead of scaling samples keeps the full bit depth. The
+//! AirPlay range of -30 to 0 dB is spread over the control's own dB range,
+//! cut to its top 60 dB, and mute uses the control's switch when it has one.
+//!
+//! Only built with the `alsa-mixer` feature.
+
+use alsa::mixer::{Mixer, SelemId};
+use alsa::Round;
+use tracing::debug;
+
+use super::volume::{is_mute, VolumeControl, VolumeError, MAX_DB, MIN_DB};
+
+/// Widest part of a control's dB range that AirPlay volume is spread over
+///
+/// Many controls reach -100 dB or below; mapped linearly, most of the slider
+/// would then be inaudible.
+pub const MAX_MIXER_SPAN_DB: f32 = 60.0;
+
+/// The part of a control's (min, max) dB range used for volume
+pub fn usable_range(min: f32, max: f32) -> (f32, f32) {
+    (min.max(max - MAX_MIXER_SPAN_DB), max)
+}
+
+/// Mixer control value for an AirPlay volume, in dB
+///
+/// `range` is the control's (min, max) in dB. Returns `None` for mute.
+pub fn mixer_db(airplay_db: f32, range: (f32, f32)) -> Option<f32> {
+    if is_mute(airplay_db) {
+        return None;
+    }
+    let position = (airplay_db.clamp(MIN_DB, MAX_DB) - MIN_DB) / (MAX_DB - MIN_DB);
+    Some(range.0 + position * (range.1 - range.0))
+}
+
+/// Volume control driving an ALSA simple mixer element
+pub struct AlsaMixer {
+    mixer: Mixer,
+    id: SelemId,
+    control: String,
+    range: (f32, f32),
+    has_switch: bool,
+    airplay_db: f32,
+}
+
+impl std::fmt::Debug for AlsaMixer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AlsaMixer")
+            .field("control", &self.control)
+            .field("range", &self.range)
+            .field("has_switch", &self.has_switch)
+            .field("airplay_db", &self.airplay_db)
+            .finish()
+    }
+}
+
+impl AlsaMixer {
+    /// Open `control` (e.g. "Digital") on mixer `device` (e.g. "hw:0")
+    pub fn open(device: &str, control: &str) -> Result<Self, VolumeError> {
+        let mixer = Mixer::new(device, false)
+            .map_err(|e| VolumeError::Mixer(format!("{}: {}", device, e)))?;
+        let id = SelemId::new(control, 0);
+        let selem = mixer
+            .find_selem(&id)
+            .ok_or_else(|| VolumeError::Mixer(format!("no control '{}' on {}", control, device)))?;
+        if !selem.has_playback_volume() {
+            return Err(VolumeError::Mixer(format!(
+                "'{}' has no playback volume",
+                control
+            )));
+        }
+        let (min, max) = selem.get_playback_db_range();
+        let range = usable_range(min.to_db(), max.to_db());
+        if range.0 >= range.1 {
+            return Err(VolumeError::Mixer(format!(
+                "'{}' has no usable dB range",
+                control
+            )));
+        }
+        let has_switch = selem.has_playback_switch();
+        debug!(
+            "Mixer {} '{}': {} to {} dB, switch: {}",
+            device, control, range.0, range.1, has_switch
+        );
+
+        Ok(Self {
+            mixer,
+            id,
+            control: control.to_string(),
+            range,
+            has_switch,
+            airplay_db: MAX_DB,
+        })
+    }
+
+    /// The control's dB range used for volume
+    pub fn range(&self) -> (f32, f32) {
+        self.range
+    }
+
+    /// Current control value in dB
+    pub fn mixer_volume_db(&self) -> Result<f32, VolumeError> {
+        let selem = self.selem()?;
+        selem
+            .get_playback_vol_db(alsa::mixer::SelemChannelId::FrontLeft)
+            .map(|v| v.to_db())
+            .map_err(|e| VolumeError::Mixer(e.to_string()))
+    }
+
+    fn selem(&self) -> Result<alsa::mixer::Selem<'_>, VolumeError> {
+        self.mixer
+            .find_selem(&self.id)
+            .ok_or_else(|| VolumeError::Mixer(format!("control '{}' disappeared", self.control)))
+    }
+}
+
+impl VolumeControl for AlsaMixer {
+    fn name(&self) -> &str {
+        "alsa-mixer"
+    }
+
+    fn set_volume(&mut self, airplay_db: f32) -> Result<(), VolumeError> {
+        if !airplay_db.is_finite() || airplay_db > MAX_DB {
+            return Err(VolumeError::InvalidVolume(airplay_db.to_string()));
+        }
+        let selem = self.selem()?;
+        let mixer_err = |e: alsa::Error| VolumeError::Mixer(e.to_string());
+        match mixer_db(airplay_db, self.range) {
+            Some(db) => {
+                selem
+                    .set_playback_db_all(alsa::mixer::MilliBel::from_db(db), Round::Floor)
+                    .map_err(mixer_err)?;
+                if self.has_switch {
+                    selem.set_playback_switch_all(1).map_err(mixer_err)?;
+                }
+            }
+            None if self.has_switch => selem.set_playback_switch_all(0).map_err(mixer_err)?,
+            None => selem
+                .set_playback_db_all(alsa::mixer::MilliBel::from_db(self.range.0), Round::Floor)
+                .map_err(mixer_err)?,
+        }
+        self.airplay_db = airplay_db;
+        Ok(())
+    }
+
+    fn volume(&self) -> f32 {
+        self.airplay_db
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::volume::MUTE_DB;
+
+    #[test]
+    fn test_mixer_db_mapping() {
+        let range = (-51.0, 0.0);
+        assert_eq!(mixer_db(0.0, range), Some(0.0));
+        assert_eq!(mixer_db(-30.0, range), Some(-51.0));
+        assert_eq!(mixer_db(-10.0, range), Some(-17.0));
+        assert_eq!(mixer_db(-40.0, range), None);
+        assert_eq!(mixer_db(MUTE_DB, range), None);
+        // Controls with gain above 0 dB use it for the top of the range
+        assert_eq!(mixer_db(0.0, usable_range(-100.0, 6.0)), Some(6.0));
+
+        // Deep controls are cut to the top 60 dB
+        let range = usable_range(-102.0, 0.0);
+        assert_eq!(range, (-60.0, 0.0));
+        assert_eq!(mixer_db(-30.0, range), Some(-60.0));
+        assert_eq!(mixer_db(-15.0, range), Some(-30.0));
+        assert_eq!(usable_range(-51.0, 0.0), (-51.0, 0.0));
+    }
+
+    /// Uses the softvol control from `testdata/asound.conf`, which needs a
+    /// sound card (`snd-dummy` will do); skipped without one.
+    #[test]
+    fn test_softvol_control() {
+        if alsa::card::Iter::new().next().is_none() {
+            eprintln!("no sound card, skipping test_softvol_control");
+            return;
+        }
+        let fixture = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/audio/testdata/asound.conf"
+        );
+        std::env::set_var("ALSA_CONFIG_PATH", fixture);
+        // The control is created when the PCM is first opened
+        alsa::PCM::new("airplay2_test_softvol", alsa::Direction::Playback, false).unwrap();
+
+        let mut mixer = AlsaMixer::open("hw:0", "AirPlay Test").unwrap();
+        let (min, max) = mixer.range();
+        assert!((min - (max - MAX_MIXER_SPAN_DB)).abs() < 0.5);
+
+        mixer.set_volume(0.0).unwrap();
+        assert!((mixer.mixer_volume_db().unwrap() - max).abs() < 0.5);
+        mixer.set_volume(-15.0).unwrap();
+        let mid = mixer.mixer_volume_db().unwrap();
+        assert!(mid > min && mid < max);
+        mixer.set_volume(MUTE_DB).unwrap();
+        assert_eq!(mixer.volume(), MUTE_DB);
+    }
+
+    #[test]
+    fn test_missing_mixer() {
+        assert!(matches!(
+            AlsaMixer::open("airplay2-no-such-mixer", "Digital"),
+            Err(VolumeError::Mixer(_))
+        ));
+    }
+}
@@ -1,11 +1,27 @@
 //! Audio decoding and output
 //!
 //! This module handles audio processing:
-//! - Audio decoding (ALAC, AAC)
-//! - Audio output (cross-platform via cpal)
+//! - Audio decoding (ALAC, AAC, PCM)
+//! - Audio output (cross-platform via cpal, or to files and pipes)
+//! - Sample-rate conversion and clock drift compensation
 //! - Volume control
 
-// TODO: Implement audio modules
-// pub mod decoder;
-// pub mod output;
-// pub mod volume;
+pub mod decoder;
+pub mod format;
+#[cfg(feature = "alsa-mixer")]
+pub mod mixer;
+pub mod null;
+pub mod output;
+pub mod pcm;
+pub mod resample;
+pub mod sinks;
+pub mod volume;
+
+pub use decoder::{AacDecoder, AlacDecoder, Decoder, DecoderRegistry};
+pub use format::{AudioFormat, Codec};
+pub use null::{NullSink, SimulatedClock};
+pub use output::{AudioSink, CpalSink, PcmSpec};
+pub use pcm::PcmDecoder;
+pub use resample::{DriftCorrection, Resampler};
+pub use sinks::{create_sink, SinkKind};
+pub use volume::{SoftwareVolume, VolumeControl, VolumeCurve};
@@ -0,0 +1,353 @@
+//! Measurement sink
+//!
+//! [`NullSink`] discards audio but records the local instant at which every
+//! frame would have been heard, on a [`SimulatedClock`] rather than wall
+//! time. Tests drive playback through it to check sync accuracy against the
+//! SETRATEANCHORTIME target and gap-free output on machines without audio
+//! hardware.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::output::{AudioSink, OutputError, PcmSpec};
+use crate::streaming::clock::local_nanos;
+
+/// A clock that only moves when told to
+///
+/// [`NullSink`] also advances it while a writer would be blocked on a full
+/// device buffer.
+#[derive(Debug, Clone)]
+pub struct SimulatedClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl SimulatedClock {
+    /// Create a clock reading `start`
+    pub fn new(start: Instant) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    /// Current simulated time
+    pub fn now(&self) -> Instant {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Move the clock forward
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) += by;
+    }
+
+    /// Move the clock forward to `instant` (never backwards)
+    pub fn advance_to(&self, instant: Instant) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now = (*now).max(instant);
+    }
+}
+
+impl Default for SimulatedClock {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+/// A discontinuity in recorded playback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+    /// Index of the last frame before the gap
+    pub after_frame: usize,
+    /// Silence between that frame ending and the next one starting
+    pub duration: Duration,
+}
+
+/// Frames played by a [`NullSink`]
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    spec: Option<PcmSpec>,
+    instants: Vec<Instant>,
+    samples: Vec<i32>,
+    underruns: usize,
+}
+
+impl Recording {
+    /// Number of frames played
+    pub fn len(&self) -> usize {
+        self.instants.len()
+    }
+
+    /// Whether nothing was played
+    pub fn is_empty(&self) -> bool {
+        self.instants.is_empty()
+    }
+
+    /// Format of the last stream
+    pub fn spec(&self) -> Option<PcmSpec> {
+        self.spec
+    }
+
+    /// When frame `index` was heard
+    pub fn instant(&self, index: usize) -> Option<Instant> {
+        self.instants.get(index).copied()
+    }
+
+    /// Samples of frame `index`
+    pub fn frame(&self, index: usize) -> Option<&[i32]> {
+        let channels = usize::from(self.spec?.channels);
+        self.samples.get(index * channels..(index + 1) * channels)
+    }
+
+    /// Index of the first frame matching `pred`
+    pub fn find(&self, pred: impl Fn(&[i32]) -> bool) -> Option<usize> {
+        (0..self.len()).find(|&i| self.frame(i).is_some_and(&pred))
+    }
+
+    /// Signed error of frame `index` against its `target` time, in nanoseconds
+    ///
+    /// Positive when the frame played late.
+    pub fn offset_nanos(&self, index: usize, target: Instant) -> Option<i128> {
+        Some(local_nanos(self.instant(index)?) - local_nanos(target))
+    }
+
+    /// Times the output ran dry between frames
+    pub fn underruns(&self) -> usize {
+        self.underruns
+    }
+
+    /// Discontinuities longer than one microsecond between consecutive frames
+    pub fn gaps(&self) -> Vec<Gap> {
+        let Some(spec) = self.spec else {
+            return Vec::new();
+        };
+        let frame = Duration::from_nanos(1_000_000_000 / u64::from(spec.sample_rate.max(1)));
+        self.instants
+            .windows(2)
+            .enumerate()
+            .filter_map(|(i, pair)| {
+                let step = pair[1].saturating_duration_since(pair[0]);
+                let silence = step.saturating_sub(frame);
+                (silence > Duration::from_micros(1)).then_some(Gap {
+                    after_frame: i,
+                    duration: silence,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Sink that records play times instead of producing sound
+///
+/// Frames play back to back from the moment the first one is written, each
+/// `latency` after it was handed over. Writing further ahead than `buffer`
+/// advances the simulated clock, as a blocked writer would wait. Writing too
+/// late counts as an underrun.
+#[derive(Debug)]
+pub struct NullSink {
+    clock: SimulatedClock,
+    latency: Duration,
+    buffer: Duration,
+    spec: Option<PcmSpec>,
+    /// Play time of frame `frames` after `base`; `None` when idle
+    playhead: Option<(Instant, u64)>,
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl NullSink {
+    /// Create a sink on `clock` with the given output latency and buffer size
+    pub fn new(clock: SimulatedClock, latency: Duration, buffer: Duration) -> Self {
+        Self {
+            clock,
+            latency,
+            buffer,
+            spec: None,
+            playhead: None,
+            recording: Arc::new(Mutex::new(Recording::default())),
+        }
+    }
+
+    /// Shared view of what has been played
+    pub fn recording(&self) -> Arc<Mutex<Recording>> {
+        self.recording.clone()
+    }
+
+    fn frame_time(spec: PcmSpec, base: Instant, frames: u64) -> Instant {
+        base + Duration::from_nanos(frames * 1_000_000_000 / u64::from(spec.sample_rate))
+    }
+}
+
+impl AudioSink for NullSink {
+    fn name(&self) -> &str {
+        "null"
+    }
+
+    fn start(&mut self, spec: PcmSpec) -> Result<(), OutputError> {
+        if spec.sample_rate == 0 || spec.channels == 0 {
+            return Err(OutputError::UnsupportedFormat(format!("{:?}", spec)));
+        }
+        self.spec = Some(spec);
+        self.playhead = None;
+        self.recording
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .spec = Some(spec);
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[i32]) -> Result<(), OutputError> {
+        let spec = self.spec.ok_or(OutputError::NotStarted)?;
+        let channels = usize::from(spec.channels);
+        let earliest = self.clock.now() + self.latency;
+
+        let mut recording = self.recording.lock().unwrap_or_else(|e| e.into_inner());
+        let (base, mut frames) = match self.playhead {
+            Some((base, frames)) if Self::frame_time(spec, base, frames) >= earliest => {
+                (base, frames)
+            }
+            Some(_) => {
+                recording.underruns += 1;
+                (earliest, 0)
+            }
+            None => (earliest, 0),
+        };
+
+        for frame in samples.chunks_exact(channels) {
+            recording
+                .instants
+                .push(Self::frame_time(spec, base, frames));
+            recording.samples.extend_from_slice(frame);
+            frames += 1;
+        }
+        self.playhead = Some((base, frames));
+
+        // Block (in simulated time) until the device buffer has room again
+        let end = Self::frame_time(spec, base, frames);
+        if let Some(wait_until) = end.checked_sub(self.latency + self.buffer) {
+            self.clock.advance_to(wait_until);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        let cut = self.clock.now() + self.latency;
+        let mut recording = self.recording.lock().unwrap_or_else(|e| e.into_inner());
+        let keep = recording.instants.partition_point(|&t| t < cut);
+        let channels = self.spec.map_or(0, |s| usize::from(s.channels));
+        recording.instants.truncate(keep);
+        recording.samples.truncate(keep * channels);
+        self.playhead = None;
+    }
+
+    fn stop(&mut self) -> Result<(), OutputError> {
+        self.playhead = None;
+        Ok(())
+    }
+
+    fn latency(&self) -> Duration {
+        self.latency
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streaming::clock::{MasterClock, OffsetClock};
+    use crate::streaming::session::{Anchor, PlaybackTimeline, SetRateAnchorTime};
+
+    const RATE: u32 = 44100;
+    const SPEC: PcmSpec = PcmSpec {
+        sample_rate: RATE,
+        channels: 2,
+    };
+    const PACKET: u32 = 352;
+
+    /// Write `packets` packets tagged with their RTP time, starting at `rtp`
+    fn write_packets(sink: &mut NullSink, rtp: u32, packets: u32) {
+        let mut buf = Vec::with_capacity(PACKET as usize * 2);
+        for p in 0..packets {
+            buf.clear();
+            for f in 0..PACKET {
+                let tag = (rtp + p * PACKET + f) as i32;
+                buf.extend_from_slice(&[tag, -tag]);
+            }
+            sink.write(&buf).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_playback_lands_on_anchor() {
+        let clock = Arc::new(OffsetClock::new());
+        clock.update(7, 1_000_000_000_000);
+        let sim = SimulatedClock::default();
+        let mut timeline = PlaybackTimeline::new(RATE, clock.clone()).unwrap();
+
+        // The sender wants RTP 50_000 heard 500 ms from now
+        let anchor_net = clock.master_time_at(sim.now()).unwrap() + 500_000_000;
+        timeline.apply(&SetRateAnchorTime {
+            rate: 1.0,
+            anchor: Some(Anchor {
+                rtp_time: 50_000,
+                network_time_nanos: anchor_net,
+                timeline_id: 7,
+            }),
+        });
+
+        let mut sink = NullSink::new(
+            sim.clone(),
+            Duration::from_millis(20),
+            Duration::from_millis(100),
+        );
+        let recording = sink.recording();
+        sink.start(SPEC).unwrap();
+
+        // Buffer starts 100 ms ahead of the anchor frame
+        let first_rtp = 50_000 - 4410;
+        let start = timeline
+            .start_point(first_rtp, sim.now() + sink.latency())
+            .unwrap();
+        assert_eq!(start.skip_frames, 0);
+        sink.write(&vec![0; start.silence_frames as usize * 2])
+            .unwrap();
+        write_packets(&mut sink, first_rtp, 400);
+
+        let recording = recording.lock().unwrap();
+        assert!(recording.gaps().is_empty());
+        assert_eq!(recording.underruns(), 0);
+        for rtp in [50_000, 50_000 + RATE, first_rtp + 399 * PACKET] {
+            let index = recording.find(|f| f[0] == rtp as i32).unwrap();
+            let target = timeline.local_time_of(rtp).unwrap();
+            let error = recording.offset_nanos(index, target).unwrap();
+            assert!(error.abs() <= 1_000_000, "rtp {} off by {} ns", rtp, error);
+        }
+        // The writer was paced by the simulated device buffer
+        assert!(sim.now() > timeline.local_time_of(50_000).unwrap());
+    }
+
+    #[test]
+    fn test_stall_and_flush() {
+        let sim = SimulatedClock::default();
+        let mut sink = NullSink::new(sim.clone(), Duration::ZERO, Duration::from_millis(50));
+        let recording = sink.recording();
+        sink.start(SPEC).unwrap();
+
+        write_packets(&mut sink, 0, 10);
+        // Writer stalls well past the end of the buffered audio
+        sim.advance(Duration::from_millis(200));
+        write_packets(&mut sink, 3520, 10);
+        {
+            let recording = recording.lock().unwrap();
+            assert_eq!(recording.underruns(), 1);
+            let gaps = recording.gaps();
+            assert_eq!(gaps.len(), 1);
+            assert_eq!(gaps[0].after_frame, 3519);
+            assert!(gaps[0].duration > Duration::from_millis(100));
+        }
+
+        // Flushing drops everything not yet heard
+        let before = recording.lock().unwrap().len();
+        sink.flush();
+        let after = recording.lock().unwrap().len();
+        assert!(after < before);
+        let last = recording.lock().unwrap().instant(after - 1).unwrap();
+        assert!(last < sim.now());
+    }
+}
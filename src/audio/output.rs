@@ -0,0 +1,598 @@
+//! Audio output
+//!
+//! Decoded audio is handed to an [`AudioSink`] as interleaved, left-justified
+//! `i32` samples (see [`super::decoder`]). The sink reports how long it takes
+//! for a written sample to become audible; that figure is advertised to
+//! senders as `outputLatencyMicros` in `/info`.
+//!
+//! [`CpalSink`] plays through the system audio API. The cpal stream lives on
+//! its own thread (streams are not `Send` on every platform) and is fed from
+//! a lock-free single-producer/single-consumer ring.
+//!
+//! Sinks are started with [`start_sink`], which publishes the device latency
+//! once the output is running.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{
+    FromSample, OutputCallbackInfo, SampleFormat, SampleRate, SizedSample, SupportedStreamConfig,
+    SupportedStreamConfigRange,
+};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use crate::config::SharedConfig;
+
+/// How much audio the ring between the writer and the device callback holds
+const RING_DURATION: Duration = Duration::from_millis(250);
+
+/// How long a blocked writer sleeps before re-checking for ring space
+const WRITE_RETRY_INTERVAL: Duration = Duration::from_millis(2);
+
+/// How long `start` waits for the first callback to report the device latency
+const LATENCY_PROBE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Output errors
+#[derive(Debug, Error)]
+pub enum OutputError {
+    /// No output device matches the requested name
+    #[error("Output device not found: {0}")]
+    DeviceNotFound(String),
+
+    /// The audio backend reported an error
+    #[error("Audio device error: {0}")]
+    Device(String),
+
+    /// The device cannot play the stream's format
+    #[error("Unsupported output format: {0}")]
+    UnsupportedFormat(String),
+
+    /// The sink was written to before being started
+    #[error("Sink is not started")]
+    NotStarted,
+
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Format of the PCM handed to a sink
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcmSpec {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl PcmSpec {
+    /// Duration of `samples` interleaved samples
+    pub fn duration_of(&self, samples: usize) -> Duration {
+        let frames = samples as u64 / u64::from(self.channels.max(1));
+        Duration::from_nanos(frames * 1_000_000_000 / u64::from(self.sample_rate.max(1)))
+    }
+}
+
+/// Destination for decoded audio
+pub trait AudioSink: Send {
+    /// Short description for logs
+    fn name(&self) -> &str;
+
+    /// Prepare the sink for a stream of `spec` audio
+    fn start(&mut self, spec: PcmSpec) -> Result<(), OutputError>;
+
+    /// Queue interleaved samples, blocking while the sink is full
+    fn write(&mut self, samples: &[i32]) -> Result<(), OutputError>;
+
+    /// Drop audio queued but not yet played (FLUSH, pause)
+    fn flush(&mut self);
+
+    /// Finish the stream (TEARDOWN)
+    fn stop(&mut self) -> Result<(), OutputError>;
+
+    /// Time from `write` until the samples are audible
+    fn latency(&self) -> Duration;
+
+    /// Latency of the output device alone, without audio queued in the sink
+    ///
+    /// This is the figure advertised to senders; sinks that queue audio
+    /// override it.
+    fn device_latency(&self) -> Duration {
+        self.latency()
+    }
+}
+
+/// Start `sink` for `spec` and advertise its device latency
+pub fn start_sink(
+    config: &SharedConfig,
+    sink: &mut dyn AudioSink,
+    spec: PcmSpec,
+) -> Result<(), OutputError> {
+    sink.start(spec)?;
+    publish_latency(config, sink);
+    Ok(())
+}
+
+/// Record a sink's device latency so `/info` advertises it
+pub fn publish_latency(config: &SharedConfig, sink: &dyn AudioSink) {
+    let latency = sink.device_latency();
+    let mut config = config.write().unwrap_or_else(|e| e.into_inner());
+    if config.output_latency != latency {
+        debug!("Output latency of {} is {:?}", sink.name(), latency);
+        config.output_latency = latency;
+    }
+}
+
+/// Names of the output devices of the default host
+pub fn output_device_names() -> Result<Vec<String>, OutputError> {
+    let host = cpal::default_host();
+    let devices = host
+        .output_devices()
+        .map_err(|e| OutputError::Device(e.to_string()))?;
+    Ok(devices.filter_map(|d| d.name().ok()).collect())
+}
+
+/// Find an output device by exact name, falling back to a case-insensitive
+/// substring match
+fn find_device(host: &cpal::Host, name: Option<&str>) -> Result<cpal::Device, OutputError> {
+    let Some(name) = name else {
+        return host
+            .default_output_device()
+            .ok_or_else(|| OutputError::DeviceNotFound("default".to_string()));
+    };
+
+    let devices: Vec<_> = host
+        .output_devices()
+        .map_err(|e| OutputError::Device(e.to_string()))?
+        .filter_map(|d| d.name().ok().map(|n| (n, d)))
+        .collect();
+    let needle = name.to_lowercase();
+    let position = devices.iter().position(|(n, _)| n == name).or_else(|| {
+        devices
+            .iter()
+            .position(|(n, _)| n.to_lowercase().contains(&needle))
+    });
+    match position {
+        Some(i) => Ok(devices.into_iter().nth(i).map(|(_, d)| d).unwrap()),
+        None => Err(OutputError::DeviceNotFound(format!(
+            "'{}' (available: {})",
+            name,
+            devices
+                .iter()
+                .map(|(n, _)| n.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+/// Preference order for device sample formats, best first
+fn format_rank(format: SampleFormat) -> Option<u8> {
+    match format {
+        SampleFormat::I32 => Some(0),
+        SampleFormat::F32 => Some(1),
+        SampleFormat::I16 => Some(2),
+        SampleFormat::U16 => Some(3),
+        _ => None,
+    }
+}
+
+/// Pick the device configuration for `spec`
+///
+/// The channel count and sample rate must match exactly; among those the
+/// sample format with the most headroom wins.
+fn choose_config(
+    ranges: impl IntoIterator<Item = SupportedStreamConfigRange>,
+    spec: PcmSpec,
+) -> Option<SupportedStreamConfig> {
+    let rate = SampleRate(spec.sample_rate);
+    ranges
+        .into_iter()
+        .filter(|r| r.channels() == spec.channels)
+        .filter(|r| r.min_sample_rate() <= rate && rate <= r.max_sample_rate())
+        .filter_map(|r| format_rank(r.sample_format()).map(|rank| (rank, r)))
+        .min_by_key(|(rank, _)| *rank)
+        .map(|(_, r)| r.with_sample_rate(rate))
+}
+
+/// State shared between the writer and the device callback
+#[derive(Default)]
+struct StreamShared {
+    /// Callback-to-playback delay reported by the backend
+    device_latency_nanos: AtomicU64,
+    /// Ask the callback to discard everything queued
+    flush: AtomicBool,
+    /// The backend reported a stream error
+    failed: AtomicBool,
+    /// Callbacks that ran out of queued audio
+    underruns: AtomicU64,
+}
+
+struct ActiveStream {
+    spec: PcmSpec,
+    producer: rtrb::Producer<i32>,
+    shared: Arc<StreamShared>,
+    stop: mpsc::Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Output through the system audio API via cpal
+pub struct CpalSink {
+    device_name: Option<String>,
+    description: String,
+    stream: Option<ActiveStream>,
+}
+
+impl CpalSink {
+    /// Create a sink for the named device, or the default device
+    ///
+    /// The device is opened on [`AudioSink::start`], once the stream format
+    /// is known.
+    pub fn new(device_name: Option<String>) -> Self {
+        let description = match &device_name {
+            Some(name) => format!("cpal:{}", name),
+            None => "cpal:default".to_string(),
+        };
+        Self {
+            device_name,
+            description,
+            stream: None,
+        }
+    }
+
+    /// Callbacks that found the ring empty since the stream started
+    pub fn underruns(&self) -> u64 {
+        self.stream
+            .as_ref()
+            .map_or(0, |s| s.shared.underruns.load(Ordering::Relaxed))
+    }
+
+    fn build_stream<T>(
+        device: &cpal::Device,
+        config: &cpal::StreamConfig,
+        mut consumer: rtrb::Consumer<i32>,
+        shared: Arc<StreamShared>,
+    ) -> Result<cpal::Stream, OutputError>
+    where
+        T: SizedSample + FromSample<i32>,
+    {
+        let error_shared = shared.clone();
+        // Only count underruns once audio has started flowing
+        let mut primed = false;
+        device
+            .build_output_stream(
+                config,
+                move |data: &mut [T], info: &OutputCallbackInfo| {
+                    let ts = info.timestamp();
+                    if let Some(delay) = ts.playback.duration_since(&ts.callback) {
+                        shared
+                            .device_latency_nanos
+                            .store(delay.as_nanos() as u64, Ordering::Relaxed);
+                    }
+                    if shared.flush.swap(false, Ordering::AcqRel) {
+                        let queued = consumer.slots();
+                        if let Ok(chunk) = consumer.read_chunk(queued) {
+                            chunk.commit_all();
+                        }
+                        primed = false;
+                    }
+
+                    let available = consumer.slots().min(data.len());
+                    if let Ok(chunk) = consumer.read_chunk(available) {
+                        let (first, second) = chunk.as_slices();
+                        for (out, &sample) in data.iter_mut().zip(first.iter().chain(second)) {
+                            *out = T::from_sample(sample);
+                        }
+                        chunk.commit_all();
+                    }
+                    if available < data.len() {
+                        if primed {
+                            shared.underruns.fetch_add(1, Ordering::Relaxed);
+                        }
+                        data[available..].fill(T::EQUILIBRIUM);
+                    }
+                    primed |= available > 0;
+                },
+                move |err| {
+                    warn!("Audio output stream error: {}", err);
+                    error_shared.failed.store(true, Ordering::Relaxed);
+                },
+                None,
+            )
+            .map_err(|e| OutputError::Device(e.to_string()))
+    }
+
+    /// Open the device and run the stream until `stop` fires
+    fn run_stream(
+        device_name: Option<String>,
+        spec: PcmSpec,
+        consumer: rtrb::Consumer<i32>,
+        shared: Arc<StreamShared>,
+        ready: mpsc::Sender<Result<String, OutputError>>,
+        stop: mpsc::Receiver<()>,
+    ) {
+        let opened = (|| {
+            let host = cpal::default_host();
+            let device = find_device(&host, device_name.as_deref())?;
+            let name = device.name().unwrap_or_else(|_| "unknown".to_string());
+            let ranges = device
+                .supported_output_configs()
+                .map_err(|e| OutputError::Device(e.to_string()))?;
+            let supported = choose_config(ranges, spec).ok_or_else(|| {
+                OutputError::UnsupportedFormat(format!(
+                    "{} does not support {} Hz with {} channels",
+                    name, spec.sample_rate, spec.channels
+                ))
+            })?;
+            let format = supported.sample_format();
+            let config = supported.config();
+            let stream = match format {
+                SampleFormat::I32 => Self::build_stream::<i32>(&device, &config, consumer, shared),
+                SampleFormat::F32 => Self::build_stream::<f32>(&device, &config, consumer, shared),
+                SampleFormat::I16 => Self::build_stream::<i16>(&device, &config, consumer, shared),
+                SampleFormat::U16 => Self::build_stream::<u16>(&device, &config, consumer, shared),
+                other => Err(OutputError::UnsupportedFormat(other.to_string())),
+            }?;
+            stream
+                .play()
+                .map_err(|e| OutputError::Device(e.to_string()))?;
+            Ok((stream, format!("{} ({})", name, format)))
+        })();
+
+        match opened {
+            Ok((stream, description)) => {
+                let _ = ready.send(Ok(description));
+                // Runs until the sink is stopped or dropped
+                let _ = stop.recv();
+                drop(stream);
+            }
+            Err(e) => {
+                let _ = ready.send(Err(e));
+            }
+        }
+    }
+}
+
+impl AudioSink for CpalSink {
+    fn name(&self) -> &str {
+        &self.description
+    }
+
+    fn start(&mut self, spec: PcmSpec) -> Result<(), OutputError> {
+        if self.stream.as_ref().is_some_and(|s| s.spec == spec) {
+            return Ok(());
+        }
+        self.stop()?;
+
+        let capacity = (RING_DURATION.as_millis() as usize * spec.sample_rate as usize / 1000)
+            * usize::from(spec.channels);
+        let (producer, consumer) = rtrb::RingBuffer::new(capacity);
+        let shared = Arc::new(StreamShared::default());
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel();
+
+        let device_name = self.device_name.clone();
+        let thread_shared = shared.clone();
+        let thread = std::thread::Builder::new()
+            .name("audio-output".to_string())
+            .spawn(move || {
+                Self::run_stream(
+                    device_name,
+                    spec,
+                    consumer,
+                    thread_shared,
+                    ready_tx,
+                    stop_rx,
+                )
+            })?;
+
+        let opened = ready_rx
+            .recv()
+            .unwrap_or_else(|_| Err(OutputError::Device("output thread exited".to_string())));
+        match opened {
+            Ok(description) => {
+                info!(
+                    "Audio output on {} at {} Hz, {} channels",
+                    description, spec.sample_rate, spec.channels
+                );
+                // The backend reports its latency from the first callback
+                let probe_start = std::time::Instant::now();
+                while shared.device_latency_nanos.load(Ordering::Relaxed) == 0
+                    && probe_start.elapsed() < LATENCY_PROBE_TIMEOUT
+                {
+                    std::thread::sleep(WRITE_RETRY_INTERVAL);
+                }
+                self.description = format!("cpal:{}", description);
+                self.stream = Some(ActiveStream {
+                    spec,
+                    producer,
+                    shared,
+                    stop: stop_tx,
+                    thread: Some(thread),
+                });
+                Ok(())
+            }
+            Err(e) => {
+                let _ = thread.join();
+                Err(e)
+            }
+        }
+    }
+
+    fn write(&mut self, mut samples: &[i32]) -> Result<(), OutputError> {
+        let stream = self.stream.as_mut().ok_or(OutputError::NotStarted)?;
+        while !samples.is_empty() {
+            if stream.shared.failed.load(Ordering::Relaxed) || stream.producer.is_abandoned() {
+                return Err(OutputError::Device("output stream failed".to_string()));
+            }
+            let n = stream.producer.slots().min(samples.len());
+            if n == 0 {
+                std::thread::sleep(WRITE_RETRY_INTERVAL);
+                continue;
+            }
+            if let Ok(mut chunk) = stream.producer.write_chunk(n) {
+                let (first, second) = chunk.as_mut_slices();
+                first.copy_from_slice(&samples[..first.len()]);
+                second.copy_from_slice(&samples[first.len()..n]);
+                chunk.commit_all();
+            }
+            samples = &samples[n..];
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Some(stream) = &self.stream {
+            stream.shared.flush.store(true, Ordering::Release);
+        }
+    }
+
+    fn stop(&mut self) -> Result<(), OutputError> {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = stream.stop.send(());
+            if let Some(thread) = stream.thread.take() {
+                let _ = thread.join();
+            }
+        }
+        Ok(())
+    }
+
+    fn latency(&self) -> Duration {
+        let Some(stream) = &self.stream else {
+            return Duration::ZERO;
+        };
+        let device =
+            Duration::from_nanos(stream.shared.device_latency_nanos.load(Ordering::Relaxed));
+        let queued = stream.producer.buffer().capacity() - stream.producer.slots();
+        device + stream.spec.duration_of(queued)
+    }
+
+    fn device_latency(&self) -> Duration {
+        self.stream.as_ref().map_or(Duration::ZERO, |stream| {
+            Duration::from_nanos(stream.shared.device_latency_nanos.load(Ordering::Relaxed))
+        })
+    }
+}
+
+impl Drop for CpalSink {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+impl std::fmt::Debug for CpalSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CpalSink")
+            .field("device", &self.description)
+            .field("spec", &self.stream.as_ref().map(|s| s.spec))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DeviceConfig;
+    use cpal::SupportedBufferSize;
+    use std::sync::RwLock;
+
+    fn range(
+        channels: u16,
+        min: u32,
+        max: u32,
+        format: SampleFormat,
+    ) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(min),
+            SampleRate(max),
+            SupportedBufferSize::Unknown,
+            format,
+        )
+    }
+
+    #[test]
+    fn test_choose_config_prefers_headroom() {
+        let spec = PcmSpec {
+            sample_rate: 44100,
+            channels: 2,
+        };
+        let ranges = vec![
+            range(2, 8000, 192000, SampleFormat::I16),
+            range(2, 8000, 192000, SampleFormat::F32),
+            range(6, 8000, 192000, SampleFormat::I32),
+            range(2, 48000, 48000, SampleFormat::I32),
+            range(2, 8000, 192000, SampleFormat::U8),
+        ];
+        let chosen = choose_config(ranges.clone(), spec).unwrap();
+        assert_eq!(chosen.sample_format(), SampleFormat::F32);
+        assert_eq!(chosen.sample_rate(), SampleRate(44100));
+        assert_eq!(chosen.channels(), 2);
+
+        let spec48 = PcmSpec {
+            sample_rate: 48000,
+            ..spec
+        };
+        let chosen = choose_config(ranges.clone(), spec48).unwrap();
+        assert_eq!(chosen.sample_format(), SampleFormat::I32);
+
+        let mono = PcmSpec {
+            channels: 1,
+            ..spec
+        };
+        assert!(choose_config(ranges, mono).is_none());
+    }
+
+    #[test]
+    fn test_start_publishes_device_latency() {
+        /// Device latency once started, plus whatever was written
+        #[derive(Default)]
+        struct QueueingSink {
+            started: bool,
+            queued: Duration,
+        }
+
+        impl AudioSink for QueueingSink {
+            fn name(&self) -> &str {
+                "queueing"
+            }
+            fn start(&mut self, _: PcmSpec) -> Result<(), OutputError> {
+                self.started = true;
+                Ok(())
+            }
+            fn write(&mut self, _: &[i32]) -> Result<(), OutputError> {
+                self.queued += Duration::from_millis(10);
+                Ok(())
+            }
+            fn flush(&mut self) {}
+            fn stop(&mut self) -> Result<(), OutputError> {
+                Ok(())
+            }
+            fn latency(&self) -> Duration {
+                self.device_latency() + self.queued
+            }
+            fn device_latency(&self) -> Duration {
+                if self.started {
+                    Duration::from_micros(23_220)
+                } else {
+                    Duration::ZERO
+                }
+            }
+        }
+
+        let config = Arc::new(RwLock::new(DeviceConfig::default()));
+        let mut sink = QueueingSink::default();
+        sink.write(&[0; 4]).unwrap();
+        let spec = PcmSpec {
+            sample_rate: 44100,
+            channels: 2,
+        };
+        start_sink(&config, &mut sink, spec).unwrap();
+        let info = crate::config::DeviceInfo::from_config(&config.read().unwrap());
+        assert_eq!(info.audio_latencies[0].output_latency_micros, 23_220);
+
+        assert_eq!(spec.duration_of(88200), Duration::from_secs(1));
+    }
+}
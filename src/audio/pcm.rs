@@ -0,0 +1,172 @@
+//! Uncompressed PCM streams
+//!
+//! Senders that negotiate `L16` (or `L24` for hi-res) send interleaved
+//! big-endian samples. Decoding is a byte swap into the native-endian,
+//! left-justified samples every [`Decoder`] produces.
+//!
+//! The conversion loops walk fixed-size chunks with no indexing, which lets
+//! the compiler drop bounds checks and vectorize them.
+
+use super::decoder::{Decoder, DecoderError};
+use super::format::{AudioFormat, Codec};
+
+/// Convert big-endian 16-bit samples
+pub fn l16_to_i16(src: &[u8], dst: &mut [i16]) {
+    for (out, s) in dst.iter_mut().zip(src.chunks_exact(2)) {
+        *out = i16::from_be_bytes([s[0], s[1]]);
+    }
+}
+
+/// Convert big-endian 16-bit samples to left-justified 32-bit
+pub fn l16_to_i32(src: &[u8], dst: &mut [i32]) {
+    for (out, s) in dst.iter_mut().zip(src.chunks_exact(2)) {
+        *out = i32::from_be_bytes([s[0], s[1], 0, 0]);
+    }
+}
+
+/// Convert packed big-endian 24-bit samples, keeping the top 16 bits
+pub fn l24_to_i16(src: &[u8], dst: &mut [i16]) {
+    for (out, s) in dst.iter_mut().zip(src.chunks_exact(3)) {
+        *out = i16::from_be_bytes([s[0], s[1]]);
+    }
+}
+
+/// Convert packed big-endian 24-bit samples to left-justified 32-bit
+pub fn l24_to_i32(src: &[u8], dst: &mut [i32]) {
+    for (out, s) in dst.iter_mut().zip(src.chunks_exact(3)) {
+        *out = i32::from_be_bytes([s[0], s[1], s[2], 0]);
+    }
+}
+
+/// Decoder for L16/L24 PCM payloads
+#[derive(Debug, Clone)]
+pub struct PcmDecoder {
+    format: AudioFormat,
+}
+
+impl PcmDecoder {
+    /// Create a decoder for a PCM `format`
+    pub fn new(format: AudioFormat) -> Result<Self, DecoderError> {
+        if format.codec != Codec::Pcm {
+            return Err(DecoderError::InvalidConfig(format!(
+                "{} is not a PCM format",
+                format
+            )));
+        }
+        if !matches!(format.bits_per_sample, 16 | 24)
+            || format.channels == 0
+            || format.sample_rate == 0
+        {
+            return Err(DecoderError::Unsupported(format.to_string()));
+        }
+        Ok(Self { format })
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        usize::from(self.format.bits_per_sample / 8)
+    }
+
+    /// Validate `frame` and return (frames, samples) it holds
+    fn frame_size(&self, frame: &[u8], available: usize) -> Result<(usize, usize), DecoderError> {
+        let frame_bytes = self.bytes_per_sample() * usize::from(self.format.channels);
+        if !frame.len().is_multiple_of(frame_bytes) {
+            return Err(DecoderError::InvalidFrame(format!(
+                "{} byte PCM payload is not a whole number of {} byte frames",
+                frame.len(),
+                frame_bytes
+            )));
+        }
+        let samples = frame.len() / self.bytes_per_sample();
+        if available < samples {
+            return Err(DecoderError::BufferTooSmall {
+                needed: samples,
+                available,
+            });
+        }
+        Ok((frame.len() / frame_bytes, samples))
+    }
+}
+
+impl Decoder for PcmDecoder {
+    fn sample_rate(&self) -> u32 {
+        self.format.sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        u16::from(self.format.channels)
+    }
+
+    fn frames_per_packet(&self) -> usize {
+        self.format.frames_per_packet as usize
+    }
+
+    fn decode_i16(&mut self, frame: &[u8], out: &mut [i16]) -> Result<usize, DecoderError> {
+        let (frames, samples) = self.frame_size(frame, out.len())?;
+        match self.format.bits_per_sample {
+            16 => l16_to_i16(frame, &mut out[..samples]),
+            _ => l24_to_i16(frame, &mut out[..samples]),
+        }
+        Ok(frames)
+    }
+
+    fn decode_i32(&mut self, frame: &[u8], out: &mut [i32]) -> Result<usize, DecoderError> {
+        let (frames, samples) = self.frame_size(frame, out.len())?;
+        match self.format.bits_per_sample {
+            16 => l16_to_i32(frame, &mut out[..samples]),
+            _ => l24_to_i32(frame, &mut out[..samples]),
+        }
+        Ok(frames)
+    }
+
+    fn reset(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::DecoderRegistry;
+
+    #[test]
+    fn test_l16_stereo_decode() {
+        let format = AudioFormat::from_bitmask(1 << 11).unwrap();
+        let mut decoder = DecoderRegistry::default().create(&format).unwrap();
+
+        let payload = [0x12, 0x34, 0xff, 0xfe, 0x80, 0x00, 0x7f, 0xff];
+        let mut out = [0i16; 4];
+        assert_eq!(decoder.decode_i16(&payload, &mut out).unwrap(), 2);
+        assert_eq!(out, [0x1234, -2, i16::MIN, i16::MAX]);
+
+        let mut wide = [0i32; 4];
+        assert_eq!(decoder.decode_i32(&payload, &mut wide).unwrap(), 2);
+        assert_eq!(wide, [0x1234_0000, -2 << 16, i32::MIN, 0x7fff_0000]);
+
+        assert!(decoder.decode_i16(&payload[..3], &mut out).is_err());
+        assert!(matches!(
+            decoder.decode_i16(&payload, &mut out[..2]),
+            Err(DecoderError::BufferTooSmall { needed: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn test_l24_stereo_decode() {
+        let format = AudioFormat::from_bitmask(1 << 17).unwrap();
+        assert_eq!((format.sample_rate, format.bits_per_sample), (48000, 24));
+        assert!(matches!(
+            PcmDecoder::new(AudioFormat {
+                sample_rate: 0,
+                ..format
+            }),
+            Err(DecoderError::Unsupported(_))
+        ));
+        let mut decoder = PcmDecoder::new(format).unwrap();
+
+        let payload = [0x12, 0x34, 0x56, 0xff, 0xff, 0xfe];
+        let mut wide = [0i32; 2];
+        assert_eq!(decoder.decode_i32(&payload, &mut wide).unwrap(), 1);
+        assert_eq!(wide, [0x1234_5600, -2 << 8]);
+
+        let mut out = [0i16; 2];
+        decoder.decode_i16(&payload, &mut out).unwrap();
+        assert_eq!(out, [0x1234, -1]);
+    }
+}
@@ -0,0 +1,426 @@
+//! Sample-rate conversion and clock drift compensation
+//!
+//! The sender's clock and the DAC's clock never run at exactly the same
+//! speed; tens of ppm apart is normal. Left alone the output buffer slowly
+//! drains or overflows and sync is lost. [`Resampler`] converts between the
+//! stream rate and the device rate (44.1 kHz to 48 kHz for devices without
+//! 44.1 kHz support) and nudges the conversion ratio so the measured error
+//! between the anchor clock and the output clock stays at zero.
+//!
+//! Two strategies are available:
+//! - [`DriftCorrection::Resample`] runs a band-limited sinc resampler whose
+//!   ratio changes continuously, which is inaudible
+//! - [`DriftCorrection::StuffDrop`] converts rates by linear interpolation
+//!   and corrects drift by dropping or repeating single frames, which is
+//!   cheap enough for low-end ARM boards
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rubato::{
+    Resampler as _, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+};
+use thiserror::Error;
+
+/// Largest correction applied to the conversion ratio (500 ppm)
+pub const MAX_CORRECTION: f64 = 500e-6;
+
+/// Frames handed to the sinc resampler at a time
+const CHUNK_FRAMES: usize = 256;
+
+/// Full scale of a left-justified `i32` sample
+const FULL_SCALE: f32 = 2_147_483_648.0;
+
+/// Resampling errors
+#[derive(Debug, Error)]
+pub enum ResampleError {
+    #[error("Unsupported conversion: {0}")]
+    Unsupported(String),
+
+    #[error("Resampler error: {0}")]
+    Resampler(String),
+}
+
+/// How clock drift is corrected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DriftCorrection {
+    /// Continuously adjusted sinc resampling
+    #[default]
+    Resample,
+    /// Linear rate conversion, dropping or repeating single frames
+    StuffDrop,
+}
+
+impl FromStr for DriftCorrection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resample" => Ok(Self::Resample),
+            "stuff" => Ok(Self::StuffDrop),
+            other => Err(format!(
+                "unknown drift correction '{}' (expected resample or stuff)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DriftCorrection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Resample => "resample",
+            Self::StuffDrop => "stuff",
+        })
+    }
+}
+
+/// PI controller turning sync error into a rate correction
+///
+/// The correction is the fraction by which input should be consumed faster
+/// than nominal; positive when output is late.
+#[derive(Debug, Clone, Default)]
+pub struct DriftController {
+    integral: f64,
+    correction: f64,
+}
+
+impl DriftController {
+    /// Proportional gain, per second (1 ms late gives 20 ppm)
+    const KP: f64 = 0.02;
+    /// Integral gain, per second squared (critically damped with `KP`)
+    const KI: f64 = Self::KP * Self::KP / 4.0;
+
+    /// Feed a measured error and the time since the previous measurement
+    ///
+    /// `error_nanos` is positive when audio is heard after its target time.
+    pub fn update(&mut self, error_nanos: i64, elapsed: Duration) -> f64 {
+        let error = error_nanos as f64 / 1e9;
+        let limit = MAX_CORRECTION / Self::KI;
+        self.integral = (self.integral + error * elapsed.as_secs_f64()).clamp(-limit, limit);
+        self.correction =
+            (Self::KP * error + Self::KI * self.integral).clamp(-MAX_CORRECTION, MAX_CORRECTION);
+        self.correction
+    }
+
+    /// Current correction
+    pub fn correction(&self) -> f64 {
+        self.correction
+    }
+
+    /// Forget accumulated state
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+enum Engine {
+    Sinc {
+        resampler: Box<SincFixedIn<f32>>,
+        pending: Vec<Vec<f32>>,
+        out: Vec<Vec<f32>>,
+    },
+    Linear {
+        step: f64,
+        phase: f64,
+        last: Vec<i32>,
+        owed: f64,
+    },
+}
+
+/// Rate converter with drift compensation
+///
+/// Works on interleaved, left-justified `i32` samples like the decoders and
+/// sinks.
+pub struct Resampler {
+    input_rate: u32,
+    output_rate: u32,
+    channels: usize,
+    controller: DriftController,
+    engine: Engine,
+}
+
+impl std::fmt::Debug for Resampler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resampler")
+            .field("input_rate", &self.input_rate)
+            .field("output_rate", &self.output_rate)
+            .field("channels", &self.channels)
+            .field("mode", &self.mode())
+            .field("correction", &self.controller.correction())
+            .finish()
+    }
+}
+
+impl Resampler {
+    /// Create a converter from `input_rate` to `output_rate`
+    pub fn new(
+        input_rate: u32,
+        output_rate: u32,
+        channels: u16,
+        mode: DriftCorrection,
+    ) -> Result<Self, ResampleError> {
+        if input_rate == 0 || output_rate == 0 || channels == 0 {
+            return Err(ResampleError::Unsupported(format!(
+                "{} Hz to {} Hz, {} channels",
+                input_rate, output_rate, channels
+            )));
+        }
+        let channels = usize::from(channels);
+        let engine = match mode {
+            DriftCorrection::Resample => {
+                let parameters = SincInterpolationParameters {
+                    sinc_len: 128,
+                    f_cutoff: 0.95,
+                    oversampling_factor: 128,
+                    interpolation: SincInterpolationType::Linear,
+                    window: WindowFunction::BlackmanHarris2,
+                };
+                let resampler = SincFixedIn::new(
+                    f64::from(output_rate) / f64::from(input_rate),
+                    1.0 + 2.0 * MAX_CORRECTION,
+                    parameters,
+                    CHUNK_FRAMES,
+                    channels,
+                )
+                .map_err(|e| ResampleError::Resampler(e.to_string()))?;
+                let out = vec![vec![0.0; resampler.output_frames_max()]; channels];
+                Engine::Sinc {
+                    resampler: Box::new(resampler),
+                    pending: vec![Vec::with_capacity(CHUNK_FRAMES * 2); channels],
+                    out,
+                }
+            }
+            DriftCorrection::StuffDrop => Engine::Linear {
+                step: f64::from(input_rate) / f64::from(output_rate),
+                phase: 0.0,
+                last: vec![0; channels],
+                owed: 0.0,
+            },
+        };
+        Ok(Self {
+            input_rate,
+            output_rate,
+            channels,
+            controller: DriftController::default(),
+            engine,
+        })
+    }
+
+    /// Input sample rate
+    pub fn input_rate(&self) -> u32 {
+        self.input_rate
+    }
+
+    /// Output sample rate
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    /// Drift correction strategy
+    pub fn mode(&self) -> DriftCorrection {
+        match self.engine {
+            Engine::Sinc { .. } => DriftCorrection::Resample,
+            Engine::Linear { .. } => DriftCorrection::StuffDrop,
+        }
+    }
+
+    /// Current correction in ppm; positive when input is consumed faster
+    pub fn correction_ppm(&self) -> f64 {
+        self.controller.correction() * 1e6
+    }
+
+    /// Report the sync error between the anchor clock and the output clock
+    ///
+    /// `error_nanos` is positive when audio is heard after its target time;
+    /// `elapsed` is the time since the previous report.
+    pub fn update_drift(&mut self, error_nanos: i64, elapsed: Duration) {
+        self.controller.update(error_nanos, elapsed);
+        self.apply_correction();
+    }
+
+    fn apply_correction(&mut self) {
+        if let Engine::Sinc { resampler, .. } = &mut self.engine {
+            // Ratio is output over input: consuming faster means fewer frames out
+            let relative = 1.0 / (1.0 + self.controller.correction());
+            // Only fails outside the range configured in `new`
+            let _ = resampler.set_resample_ratio_relative(relative, true);
+        }
+    }
+
+    /// Convert `input` and append the result to `output`
+    ///
+    /// The sinc engine holds back up to one chunk of input until enough has
+    /// arrived.
+    pub fn process(&mut self, input: &[i32], output: &mut Vec<i32>) -> Result<(), ResampleError> {
+        let channels = self.channels;
+        match &mut self.engine {
+            Engine::Sinc {
+                resampler,
+                pending,
+                out,
+            } => {
+                for frame in input.chunks_exact(channels) {
+                    for (ch, &s) in pending.iter_mut().zip(frame) {
+                        ch.push(s as f32 / FULL_SCALE);
+                    }
+                }
+                while pending[0].len() >= resampler.input_frames_next() {
+                    let (used, produced) = resampler
+                        .process_into_buffer(pending, out, None)
+                        .map_err(|e| ResampleError::Resampler(e.to_string()))?;
+                    output.reserve(produced * channels);
+                    for i in 0..produced {
+                        // `as` saturates, so full-scale overshoot clips cleanly
+                        output.extend(out.iter().map(|ch| (ch[i] * FULL_SCALE) as i32));
+                    }
+                    for ch in pending.iter_mut() {
+                        ch.drain(..used);
+                    }
+                }
+            }
+            Engine::Linear {
+                step,
+                phase,
+                last,
+                owed,
+            } => {
+                let correction = self.controller.correction();
+                for frame in input.chunks_exact(channels) {
+                    *owed += correction;
+                    let repeats = if *owed >= 1.0 {
+                        *owed -= 1.0;
+                        0
+                    } else if *owed <= -1.0 {
+                        *owed += 1.0;
+                        2
+                    } else {
+                        1
+                    };
+                    for _ in 0..repeats {
+                        while *phase < 1.0 {
+                            output.extend(last.iter().zip(frame).map(|(&a, &b)| {
+                                let delta = (i64::from(b) - i64::from(a)) as f64 * *phase;
+                                (i64::from(a) + delta as i64) as i32
+                            }));
+                            *phase += *step;
+                        }
+                        *phase -= 1.0;
+                        last.copy_from_slice(frame);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop buffered audio and drift state, e.g. after a FLUSH
+    pub fn reset(&mut self) {
+        self.controller.reset();
+        match &mut self.engine {
+            Engine::Sinc {
+                resampler, pending, ..
+            } => {
+                resampler.reset();
+                pending.iter_mut().for_each(Vec::clear);
+            }
+            Engine::Linear {
+                phase, last, owed, ..
+            } => {
+                *phase = 0.0;
+                last.fill(0);
+                *owed = 0.0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(rate: u32, freq: f64, frames: usize) -> Vec<i32> {
+        (0..frames)
+            .flat_map(|i| {
+                let t = i as f64 / f64::from(rate);
+                let s =
+                    ((2.0 * std::f64::consts::PI * freq * t).sin() * 0.5 * 2f64.powi(31)) as i32;
+                [s, s]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_44k1_to_48k_keeps_pitch() {
+        let mut resampler = Resampler::new(44100, 48000, 2, DriftCorrection::Resample).unwrap();
+        let input = sine(44100, 1000.0, 88200);
+        let mut output = Vec::new();
+        for packet in input.chunks(352 * 2) {
+            resampler.process(packet, &mut output).unwrap();
+        }
+
+        let frames = output.len() / 2;
+        assert!(
+            (96000 - frames as i64).abs() < 2 * CHUNK_FRAMES as i64,
+            "{}",
+            frames
+        );
+        assert!(output.chunks_exact(2).all(|f| f[0] == f[1]));
+
+        // One second of steady state, clear of the filter delay
+        let left: Vec<i32> = output
+            .iter()
+            .step_by(2)
+            .skip(4800)
+            .take(48000)
+            .copied()
+            .collect();
+        let crossings = left.windows(2).filter(|w| w[0] < 0 && w[1] >= 0).count();
+        assert!((999..=1001).contains(&crossings), "{}", crossings);
+        let peak = left.iter().map(|s| s.unsigned_abs()).max().unwrap();
+        assert!((peak as f64 / 2f64.powi(30) - 1.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_controller_tracks_drift() {
+        // Sender runs 50 ppm fast; the buffer (and latency) grows until the
+        // correction matches the drift
+        let mut controller = DriftController::default();
+        let tick = Duration::from_millis(100);
+        let mut error = 0.0f64;
+        for _ in 0..6000 {
+            error += tick.as_secs_f64() * (50e-6 - controller.correction());
+            controller.update((error * 1e9) as i64, tick);
+        }
+        assert!((controller.correction() - 50e-6).abs() < 1e-6);
+        assert!(error.abs() < 100e-6, "{}", error);
+
+        // Corrections never become audible
+        controller.update(10_000_000_000, tick);
+        assert_eq!(controller.correction(), MAX_CORRECTION);
+    }
+
+    #[test]
+    fn test_stuff_drop_single_frames() {
+        let mut resampler = Resampler::new(44100, 44100, 2, DriftCorrection::StuffDrop).unwrap();
+        let input: Vec<i32> = (0..100_000).flat_map(|i| [i, -i]).collect();
+        let mut output = Vec::new();
+        resampler.process(&input[..2000], &mut output).unwrap();
+        // Same rate without correction is a one-frame delayed passthrough
+        assert_eq!(&output[2..], &input[..1998]);
+
+        resampler.controller.correction = 100e-6;
+        output.clear();
+        for packet in input[2000..].chunks(352 * 2) {
+            resampler.process(packet, &mut output).unwrap();
+        }
+        assert_eq!(output.len() / 2, 99_000 - 9);
+
+        resampler.reset();
+        resampler.controller.correction = -105e-6;
+        output.clear();
+        resampler.process(&input, &mut output).unwrap();
+        assert_eq!(output.len() / 2, 100_000 + 10);
+    }
+}
@@ -0,0 +1,486 @@
+//! Headless audio sinks
+//!
+//! Receivers feeding Snapcast, Icecast or an ffmpeg pipeline (and CI
+//! machines without a sound card) write PCM to a stream instead of a device:
+//! raw little-endian samples to stdout, a named pipe or a file, or a WAV file
+//! whose header is completed when the stream stops.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+use super::output::{AudioSink, CpalSink, OutputError, PcmSpec};
+
+/// Sample encoding written by the headless sinks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SampleEncoding {
+    /// Signed 16-bit little-endian
+    #[default]
+    S16Le,
+    /// Signed 24-bit little-endian, packed in 3 bytes
+    S24Le,
+    /// Signed 32-bit little-endian
+    S32Le,
+}
+
+impl SampleEncoding {
+    /// Encoding for a bit depth of 16, 24 or 32
+    pub fn from_bits(bits: u16) -> Option<Self> {
+        match bits {
+            16 => Some(SampleEncoding::S16Le),
+            24 => Some(SampleEncoding::S24Le),
+            32 => Some(SampleEncoding::S32Le),
+            _ => None,
+        }
+    }
+
+    /// Bits per sample
+    pub fn bits(self) -> u16 {
+        self.bytes() as u16 * 8
+    }
+
+    /// Bytes per sample
+    pub fn bytes(self) -> usize {
+        match self {
+            SampleEncoding::S16Le => 2,
+            SampleEncoding::S24Le => 3,
+            SampleEncoding::S32Le => 4,
+        }
+    }
+
+    /// Encode left-justified samples into `out`, replacing its contents
+    pub fn encode(self, samples: &[i32], out: &mut Vec<u8>) {
+        out.clear();
+        out.reserve(samples.len() * self.bytes());
+        match self {
+            SampleEncoding::S16Le => {
+                for s in samples {
+                    out.extend_from_slice(&s.to_le_bytes()[2..]);
+                }
+            }
+            SampleEncoding::S24Le => {
+                for s in samples {
+                    out.extend_from_slice(&s.to_le_bytes()[1..]);
+                }
+            }
+            SampleEncoding::S32Le => {
+                for s in samples {
+                    out.extend_from_slice(&s.to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// Output selected on the command line
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum SinkKind {
+    /// System audio device via cpal
+    #[default]
+    Cpal,
+    /// Raw PCM on standard output
+    Stdout,
+    /// Raw PCM into an existing named pipe
+    Pipe(PathBuf),
+    /// Raw PCM into a file
+    File(PathBuf),
+    /// WAV file
+    Wav(PathBuf),
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    /// Parse `cpal`, `stdout`, `pipe:<path>`, `file:<path>` or `wav:<path>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, path) = match s.split_once(':') {
+            Some((kind, path)) => (kind, Some(path)),
+            None => (s, None),
+        };
+        let path = || match path {
+            Some(p) if !p.is_empty() => Ok(PathBuf::from(p)),
+            _ => Err(format!(
+                "output '{}' needs a path, e.g. {}:/tmp/audio",
+                kind, kind
+            )),
+        };
+        match kind {
+            "cpal" | "device" => Ok(SinkKind::Cpal),
+            "stdout" | "-" => Ok(SinkKind::Stdout),
+            "pipe" => Ok(SinkKind::Pipe(path()?)),
+            "file" => Ok(SinkKind::File(path()?)),
+            "wav" => Ok(SinkKind::Wav(path()?)),
+            other => Err(format!(
+                "unknown output '{}' (expected cpal, stdout, pipe:PATH, file:PATH or wav:PATH)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SinkKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkKind::Cpal => f.write_str("cpal"),
+            SinkKind::Stdout => f.write_str("stdout"),
+            SinkKind::Pipe(path) => write!(f, "pipe:{}", path.display()),
+            SinkKind::File(path) => write!(f, "file:{}", path.display()),
+            SinkKind::Wav(path) => write!(f, "wav:{}", path.display()),
+        }
+    }
+}
+
+/// Create the sink for `kind`
+///
+/// `device` selects the cpal output device; `encoding` applies to the
+/// headless sinks.
+pub fn create_sink(
+    kind: &SinkKind,
+    device: Option<String>,
+    encoding: SampleEncoding,
+) -> Result<Box<dyn AudioSink>, OutputError> {
+    Ok(match kind {
+        SinkKind::Cpal => Box::new(CpalSink::new(device)),
+        SinkKind::Stdout => Box::new(RawSink::stdout(encoding)),
+        SinkKind::Pipe(path) => Box::new(RawSink::pipe(path, encoding)?),
+        SinkKind::File(path) => Box::new(RawSink::file(path, encoding)),
+        SinkKind::Wav(path) => Box::new(WavSink::new(path, encoding)),
+    })
+}
+
+/// Where a [`RawSink`] writes
+enum RawTarget {
+    Stdout,
+    Pipe(PathBuf),
+    File(PathBuf),
+}
+
+/// Raw interleaved PCM to stdout, a named pipe or a file
+pub struct RawSink {
+    target: RawTarget,
+    name: String,
+    encoding: SampleEncoding,
+    writer: Option<Box<dyn Write + Send>>,
+    scratch: Vec<u8>,
+}
+
+impl RawSink {
+    fn with_target(target: RawTarget, name: String, encoding: SampleEncoding) -> Self {
+        Self {
+            target,
+            name,
+            encoding,
+            writer: None,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Write to standard output
+    pub fn stdout(encoding: SampleEncoding) -> Self {
+        Self::with_target(RawTarget::Stdout, "stdout".to_string(), encoding)
+    }
+
+    /// Write into an existing named pipe
+    ///
+    /// The pipe is opened on the first stream, which blocks until a reader
+    /// is attached. If the reader goes away it is reopened for the next stream.
+    pub fn pipe(path: impl AsRef<Path>, encoding: SampleEncoding) -> Result<Self, OutputError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(OutputError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist (create it with mkfifo)", path.display()),
+            )));
+        }
+        Ok(Self::with_target(
+            RawTarget::Pipe(path.to_path_buf()),
+            format!("pipe:{}", path.display()),
+            encoding,
+        ))
+    }
+
+    /// Write into a file, truncated at the start of each stream
+    pub fn file(path: impl AsRef<Path>, encoding: SampleEncoding) -> Self {
+        let path = path.as_ref();
+        Self::with_target(
+            RawTarget::File(path.to_path_buf()),
+            format!("file:{}", path.display()),
+            encoding,
+        )
+    }
+
+    fn open(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(match &self.target {
+            RawTarget::Stdout => Box::new(io::stdout()),
+            RawTarget::Pipe(path) => Box::new(OpenOptions::new().write(true).open(path)?),
+            RawTarget::File(path) => Box::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+}
+
+impl AudioSink for RawSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn start(&mut self, spec: PcmSpec) -> Result<(), OutputError> {
+        let reopen = matches!(self.target, RawTarget::File(_)) || self.writer.is_none();
+        if reopen {
+            self.writer = Some(self.open()?);
+        }
+        info!(
+            "Writing {}-bit PCM at {} Hz, {} channels to {}",
+            self.encoding.bits(),
+            spec.sample_rate,
+            spec.channels,
+            self.name
+        );
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[i32]) -> Result<(), OutputError> {
+        let writer = self.writer.as_mut().ok_or(OutputError::NotStarted)?;
+        self.encoding.encode(samples, &mut self.scratch);
+        if let Err(e) = writer.write_all(&self.scratch) {
+            if e.kind() == io::ErrorKind::BrokenPipe {
+                warn!("Reader of {} went away", self.name);
+                self.writer = None;
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        // Written audio cannot be taken back
+    }
+
+    fn stop(&mut self) -> Result<(), OutputError> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+        }
+        if matches!(self.target, RawTarget::File(_)) {
+            self.writer = None;
+        }
+        Ok(())
+    }
+
+    fn latency(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// Size of the canonical PCM WAV header
+const WAV_HEADER_LEN: u32 = 44;
+
+/// WAV file output
+///
+/// The header is written with placeholder sizes when the stream starts and
+/// completed on [`AudioSink::stop`] (TEARDOWN) or when the sink is dropped.
+pub struct WavSink {
+    path: PathBuf,
+    name: String,
+    encoding: SampleEncoding,
+    file: Option<BufWriter<File>>,
+    data_len: u64,
+    scratch: Vec<u8>,
+}
+
+impl WavSink {
+    /// Write to `path`, overwritten at the start of each stream
+    pub fn new(path: impl AsRef<Path>, encoding: SampleEncoding) -> Self {
+        let path = path.as_ref().to_path_buf();
+        Self {
+            name: format!("wav:{}", path.display()),
+            path,
+            encoding,
+            file: None,
+            data_len: 0,
+            scratch: Vec::new(),
+        }
+    }
+
+    fn header(&self, spec: PcmSpec, data_len: u32) -> [u8; WAV_HEADER_LEN as usize] {
+        let block_align = spec.channels * self.encoding.bytes() as u16;
+        let byte_rate = spec.sample_rate * u32::from(block_align);
+
+        let mut header = [0u8; WAV_HEADER_LEN as usize];
+        header[0..4].copy_from_slice(b"RIFF");
+        header[4..8].copy_from_slice(&(data_len.saturating_add(WAV_HEADER_LEN - 8)).to_le_bytes());
+        header[8..12].copy_from_slice(b"WAVE");
+        header[12..16].copy_from_slice(b"fmt ");
+        header[16..20].copy_from_slice(&16u32.to_le_bytes());
+        header[20..22].copy_from_slice(&1u16.to_le_bytes()); // PCM
+        header[22..24].copy_from_slice(&spec.channels.to_le_bytes());
+        header[24..28].copy_from_slice(&spec.sample_rate.to_le_bytes());
+        header[28..32].copy_from_slice(&byte_rate.to_le_bytes());
+        header[32..34].copy_from_slice(&block_align.to_le_bytes());
+        header[34..36].copy_from_slice(&self.encoding.bits().to_le_bytes());
+        header[36..40].copy_from_slice(b"data");
+        header[40..44].copy_from_slice(&data_len.to_le_bytes());
+        header
+    }
+
+    /// Patch the RIFF and data chunk sizes and close the file
+    fn finalize(&mut self) -> io::Result<()> {
+        let Some(mut file) = self.file.take() else {
+            return Ok(());
+        };
+        // Sizes are 32-bit; an oversized file keeps the maximum
+        let data_len = u32::try_from(self.data_len).unwrap_or(u32::MAX - WAV_HEADER_LEN);
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&(data_len.saturating_add(WAV_HEADER_LEN - 8)).to_le_bytes())?;
+        file.seek(SeekFrom::Start(40))?;
+        file.write_all(&data_len.to_le_bytes())?;
+        file.flush()?;
+        debug!(
+            "Finalized {} with {} bytes of audio",
+            self.name, self.data_len
+        );
+        Ok(())
+    }
+}
+
+impl AudioSink for WavSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn start(&mut self, spec: PcmSpec) -> Result<(), OutputError> {
+        self.finalize()?;
+        let mut file = BufWriter::new(File::create(&self.path)?);
+        file.write_all(&self.header(spec, 0))?;
+        self.file = Some(file);
+        self.data_len = 0;
+        info!(
+            "Recording {}-bit WAV at {} Hz, {} channels to {}",
+            self.encoding.bits(),
+            spec.sample_rate,
+            spec.channels,
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[i32]) -> Result<(), OutputError> {
+        let file = self.file.as_mut().ok_or(OutputError::NotStarted)?;
+        self.encoding.encode(samples, &mut self.scratch);
+        file.write_all(&self.scratch)?;
+        self.data_len += self.scratch.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        // Written audio cannot be taken back
+    }
+
+    fn stop(&mut self) -> Result<(), OutputError> {
+        Ok(self.finalize()?)
+    }
+
+    fn latency(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.finalize() {
+            warn!("Failed to finalize {}: {}", self.name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("airplay2-{}-{}", std::process::id(), name))
+    }
+
+    const SPEC: PcmSpec = PcmSpec {
+        sample_rate: 44100,
+        channels: 2,
+    };
+
+    #[test]
+    fn test_sink_kind_parsing() {
+        assert_eq!("cpal".parse::<SinkKind>().unwrap(), SinkKind::Cpal);
+        assert_eq!("stdout".parse::<SinkKind>().unwrap(), SinkKind::Stdout);
+        assert_eq!(
+            "wav:/tmp/out.wav".parse::<SinkKind>().unwrap(),
+            SinkKind::Wav(PathBuf::from("/tmp/out.wav"))
+        );
+        assert_eq!(
+            "pipe:/tmp/snapfifo".parse::<SinkKind>().unwrap(),
+            SinkKind::Pipe(PathBuf::from("/tmp/snapfifo"))
+        );
+        assert!("file".parse::<SinkKind>().is_err());
+        assert!("alsa".parse::<SinkKind>().is_err());
+    }
+
+    #[test]
+    fn test_sample_encoding() {
+        let samples = [0x1234_5678, -0x100];
+        let mut out = Vec::new();
+        SampleEncoding::S16Le.encode(&samples, &mut out);
+        assert_eq!(out, [0x34, 0x12, 0xff, 0xff]);
+        SampleEncoding::S24Le.encode(&samples, &mut out);
+        assert_eq!(out, [0x56, 0x34, 0x12, 0xff, 0xff, 0xff]);
+        SampleEncoding::S32Le.encode(&samples[..1], &mut out);
+        assert_eq!(out, [0x78, 0x56, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn test_raw_file_sink() {
+        let path = temp_path("raw.pcm");
+        let mut sink =
+            create_sink(&SinkKind::File(path.clone()), None, SampleEncoding::S16Le).unwrap();
+        assert!(matches!(sink.write(&[0]), Err(OutputError::NotStarted)));
+        sink.start(SPEC).unwrap();
+        sink.write(&[1 << 16, -1 << 16]).unwrap();
+        sink.stop().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), [1, 0, 0xff, 0xff]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_wav_header_finalized_on_stop() {
+        let path = temp_path("out.wav");
+        let mut sink = WavSink::new(&path, SampleEncoding::S16Le);
+        sink.start(SPEC).unwrap();
+        sink.write(&[1 << 16, 2 << 16, 3 << 16, 4 << 16]).unwrap();
+        sink.write(&[5 << 16, 6 << 16]).unwrap();
+        sink.stop().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + 12);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 36 + 12);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u16::from_le_bytes([bytes[22], bytes[23]]), 2);
+        assert_eq!(u32::from_le_bytes(bytes[24..28].try_into().unwrap()), 44100);
+        assert_eq!(
+            u32::from_le_bytes(bytes[28..32].try_into().unwrap()),
+            176_400
+        );
+        assert_eq!(u16::from_le_bytes([bytes[34], bytes[35]]), 16);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 12);
+        assert_eq!(&bytes[44..48], [1, 0, 2, 0]);
+
+        // Dropping without stop still leaves a valid file
+        sink.start(SPEC).unwrap();
+        sink.write(&[7 << 16, 8 << 16]).unwrap();
+        drop(sink);
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
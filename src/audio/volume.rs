@@ -0,0 +1,469 @@
+//! Volume control
+//!
+//! Senders set volume with `SET_PARAMETER` and a `volume: <dB>` body, where
+//! the value runs from -30 (quietest) to 0 (full) and -144 means mute. A
+//! [`VolumeCurve`] turns that slider position into an output gain, and
+//! [`SoftwareVolume`] applies it to samples, ramping between levels so
+//! changes do not produce zipper noise.
+//!
+//! When volume control is disabled (`--no-volume`) the receiver does not
+//! advertise it and [`FixedVolume`] leaves audio untouched.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use thiserror::Error;
+use tracing::warn;
+
+use crate::config::{DeviceConfig, StateStore};
+
+/// AirPlay volume meaning mute
+pub const MUTE_DB: f32 = -144.0;
+
+/// Quietest unmuted AirPlay volume
+pub const MIN_DB: f32 = -30.0;
+
+/// Loudest AirPlay volume
+pub const MAX_DB: f32 = 0.0;
+
+/// Time taken to move between two volume levels
+pub const RAMP_TIME: Duration = Duration::from_millis(20);
+
+/// Volume errors
+#[derive(Debug, Error)]
+pub enum VolumeError {
+    #[error("Invalid volume: {0}")]
+    InvalidVolume(String),
+
+    #[error("Invalid volume curve: {0}")]
+    InvalidCurve(String),
+
+    #[error("Mixer error: {0}")]
+    Mixer(String),
+}
+
+/// Parse a `volume: <dB>` parameter body
+pub fn parse_volume_parameter(body: &str) -> Result<f32, VolumeError> {
+    let value = body
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("volume:"))
+        .ok_or_else(|| VolumeError::InvalidVolume(body.trim().to_string()))?
+        .trim();
+    let db: f32 = value
+        .parse()
+        .map_err(|_| VolumeError::InvalidVolume(value.to_string()))?;
+    if !db.is_finite() {
+        return Err(VolumeError::InvalidVolume(value.to_string()));
+    }
+    Ok(db)
+}
+
+/// Format a volume as a `GET_PARAMETER` response body
+pub fn format_volume_parameter(db: f32) -> String {
+    format!("volume: {:.6}\r\n", db)
+}
+
+/// Whether an AirPlay volume means mute
+pub fn is_mute(db: f32) -> bool {
+    db < MIN_DB
+}
+
+/// Limit an AirPlay volume to `max_db`, leaving mute alone
+pub fn clamp_volume(db: f32, max_db: f32) -> f32 {
+    if is_mute(db) {
+        db
+    } else {
+        db.min(max_db.clamp(MIN_DB, MAX_DB))
+    }
+}
+
+/// Mapping from AirPlay volume to output attenuation
+#[derive(Debug, Clone, PartialEq)]
+pub enum VolumeCurve {
+    /// Amplitude proportional to slider position
+    Linear,
+    /// Slider position spread evenly over `range_db` decibels of attenuation
+    Logarithmic { range_db: f32 },
+    /// Piecewise-linear `(airplay dB, output dB)` points, sorted by AirPlay dB
+    Table(Vec<(f32, f32)>),
+}
+
+impl Default for VolumeCurve {
+    fn default() -> Self {
+        Self::Logarithmic { range_db: 60.0 }
+    }
+}
+
+impl VolumeCurve {
+    /// Output attenuation in dB for an AirPlay volume (`-inf` when muted)
+    pub fn attenuation_db(&self, airplay_db: f32) -> f32 {
+        if is_mute(airplay_db) {
+            return f32::NEG_INFINITY;
+        }
+        let db = airplay_db.clamp(MIN_DB, MAX_DB);
+        let position = (db - MIN_DB) / (MAX_DB - MIN_DB);
+        match self {
+            VolumeCurve::Linear => 20.0 * position.log10(),
+            VolumeCurve::Logarithmic { range_db } => (position - 1.0) * range_db,
+            VolumeCurve::Table(points) => {
+                let upper = points.partition_point(|&(x, _)| x < db);
+                match (upper.checked_sub(1).map(|i| points[i]), points.get(upper)) {
+                    (Some((x0, y0)), Some(&(x1, y1))) => y0 + (y1 - y0) * (db - x0) / (x1 - x0),
+                    (Some((_, y)), None) | (None, Some(&(_, y))) => y,
+                    (None, None) => 0.0,
+                }
+            }
+        }
+    }
+
+    /// Output amplitude factor for an AirPlay volume, from 0.0 to 1.0
+    pub fn gain(&self, airplay_db: f32) -> f32 {
+        10f32.powf(self.attenuation_db(airplay_db) / 20.0).min(1.0)
+    }
+}
+
+impl FromStr for VolumeCurve {
+    type Err = VolumeError;
+
+    /// Parse `linear`, `log`, `log:<range dB>` or `table:<in>=<out>,...`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || VolumeError::InvalidCurve(s.to_string());
+        match s.split_once(':') {
+            None if s == "linear" => Ok(VolumeCurve::Linear),
+            None if s == "log" => Ok(VolumeCurve::default()),
+            Some(("log", range)) => {
+                let range_db: f32 = range.parse().map_err(|_| invalid())?;
+                if !(range_db > 0.0 && range_db <= 144.0) {
+                    return Err(invalid());
+                }
+                Ok(VolumeCurve::Logarithmic { range_db })
+            }
+            Some(("table", list)) => {
+                let mut points = list
+                    .split(',')
+                    .map(|point| {
+                        let (x, y) = point.split_once('=')?;
+                        Some((x.trim().parse().ok()?, y.trim().parse().ok()?))
+                    })
+                    .collect::<Option<Vec<(f32, f32)>>>()
+                    .ok_or_else(invalid)?;
+                points.sort_by(|a, b| a.0.total_cmp(&b.0));
+                if points.len() < 2 || points.windows(2).any(|w| w[0].0 == w[1].0) {
+                    return Err(invalid());
+                }
+                Ok(VolumeCurve::Table(points))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for VolumeCurve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VolumeCurve::Linear => f.write_str("linear"),
+            VolumeCurve::Logarithmic { range_db } => write!(f, "log:{}", range_db),
+            VolumeCurve::Table(points) => {
+                f.write_str("table:")?;
+                for (i, (x, y)) in points.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { "," };
+                    write!(f, "{}{}={}", sep, x, y)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Something that can follow AirPlay volume changes
+pub trait VolumeControl: Send {
+    /// Short name for logging
+    fn name(&self) -> &str;
+
+    /// Apply an AirPlay volume (-30 to 0 dB, or -144 for mute)
+    fn set_volume(&mut self, airplay_db: f32) -> Result<(), VolumeError>;
+
+    /// Last AirPlay volume set
+    fn volume(&self) -> f32;
+
+    /// Whether samples must pass through [`SoftwareVolume::apply`]
+    fn is_software(&self) -> bool {
+        false
+    }
+}
+
+/// Volume control that ignores the sender, used with `--no-volume`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FixedVolume;
+
+impl VolumeControl for FixedVolume {
+    fn name(&self) -> &str {
+        "fixed"
+    }
+
+    fn set_volume(&mut self, _airplay_db: f32) -> Result<(), VolumeError> {
+        Ok(())
+    }
+
+    fn volume(&self) -> f32 {
+        MAX_DB
+    }
+}
+
+/// Volume applied by scaling samples
+#[derive(Debug, Clone)]
+pub struct SoftwareVolume {
+    curve: VolumeCurve,
+    airplay_db: f32,
+    gain: f32,
+    target: f32,
+    /// Gain change per frame while ramping
+    step: f32,
+    ramp_frames: u32,
+}
+
+impl SoftwareVolume {
+    /// Create a control at full volume for audio at `sample_rate`
+    pub fn new(curve: VolumeCurve, sample_rate: u32) -> Self {
+        let ramp_frames = (u64::from(sample_rate) * RAMP_TIME.as_millis() as u64 / 1000).max(1);
+        Self {
+            curve,
+            airplay_db: MAX_DB,
+            gain: 1.0,
+            target: 1.0,
+            step: 0.0,
+            ramp_frames: ramp_frames as u32,
+        }
+    }
+
+    /// Current amplitude factor
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Whether a volume change is still being ramped in
+    pub fn is_ramping(&self) -> bool {
+        self.gain != self.target
+    }
+
+    /// Scale interleaved left-justified samples in place
+    ///
+    /// At full volume with no ramp in progress the samples are untouched.
+    pub fn apply(&mut self, samples: &mut [i32], channels: usize) {
+        if !self.is_ramping() {
+            if self.gain == 1.0 {
+                return;
+            }
+            let gain = f64::from(self.gain);
+            for s in samples.iter_mut() {
+                *s = (f64::from(*s) * gain) as i32;
+            }
+            return;
+        }
+        for frame in samples.chunks_exact_mut(channels.max(1)) {
+            if self.gain != self.target {
+                let next = self.gain + self.step;
+                let passed = (self.step > 0.0 && next >= self.target)
+                    || (self.step < 0.0 && next <= self.target);
+                self.gain = if passed { self.target } else { next };
+            }
+            let gain = f64::from(self.gain);
+            for s in frame.iter_mut() {
+                *s = (f64::from(*s) * gain) as i32;
+            }
+        }
+    }
+}
+
+impl VolumeControl for SoftwareVolume {
+    fn name(&self) -> &str {
+        "software"
+    }
+
+    fn set_volume(&mut self, airplay_db: f32) -> Result<(), VolumeError> {
+        if !airplay_db.is_finite() || airplay_db > MAX_DB {
+            return Err(VolumeError::InvalidVolume(airplay_db.to_string()));
+        }
+        self.airplay_db = airplay_db;
+        self.target = self.curve.gain(airplay_db);
+        self.step = (self.target - self.gain) / self.ramp_frames as f32;
+        Ok(())
+    }
+
+    fn volume(&self) -> f32 {
+        self.airplay_db
+    }
+
+    fn is_software(&self) -> bool {
+        true
+    }
+}
+
+/// State file holding the last volume
+const VOLUME_STATE: &str = "volume";
+
+/// Receiver-side volume rules: the max-volume cap and persistence
+///
+/// Every volume change from the sender goes through [`VolumePolicy::apply`],
+/// which clamps it, hands it to the active [`VolumeControl`] and remembers
+/// it in the state directory.
+#[derive(Debug, Clone)]
+pub struct VolumePolicy {
+    initial_db: Option<f32>,
+    max_db: f32,
+    store: Option<StateStore>,
+}
+
+impl VolumePolicy {
+    /// Policy for `config`, persisting to its state directory if set
+    pub fn from_config(config: &DeviceConfig) -> Self {
+        Self {
+            initial_db: config.initial_volume,
+            max_db: config.max_volume,
+            store: config.state_dir.clone().map(StateStore::new),
+        }
+    }
+
+    /// Volume a session starts at: the configured initial volume, otherwise
+    /// the last volume from before a restart
+    pub fn initial_volume(&self) -> Option<f32> {
+        self.initial_db
+            .or_else(|| self.last_volume())
+            .map(|db| clamp_volume(db, self.max_db))
+    }
+
+    /// Volume saved by a previous run
+    pub fn last_volume(&self) -> Option<f32> {
+        let value = self.store.as_ref()?.read(VOLUME_STATE).ok()??;
+        value
+            .parse()
+            .ok()
+            .filter(|db: &f32| db.is_finite() && *db <= MAX_DB)
+    }
+
+    /// Apply a requested volume, returning the volume actually set
+    pub fn apply(
+        &self,
+        control: &mut dyn VolumeControl,
+        requested_db: f32,
+    ) -> Result<f32, VolumeError> {
+        let db = clamp_volume(requested_db, self.max_db);
+        control.set_volume(db)?;
+        if let Some(ref store) = self.store {
+            if let Err(e) = store.write(VOLUME_STATE, &db.to_string()) {
+                warn!("Failed to save volume in {}: {}", store.dir().display(), e);
+            }
+        }
+        Ok(db)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_volume_parameter() {
+        assert_eq!(
+            parse_volume_parameter("volume: -11.123456\r\n").unwrap(),
+            -11.123456
+        );
+        assert_eq!(
+            parse_volume_parameter("volume: -144.000000\r\n").unwrap(),
+            MUTE_DB
+        );
+        assert!(parse_volume_parameter("progress: 1/2/3\r\n").is_err());
+        assert!(parse_volume_parameter("volume: loud").is_err());
+        assert_eq!(format_volume_parameter(-20.0), "volume: -20.000000\r\n");
+    }
+
+    #[test]
+    fn test_curves() {
+        let log = VolumeCurve::default();
+        assert_eq!(log.attenuation_db(0.0), 0.0);
+        assert_eq!(log.attenuation_db(-15.0), -30.0);
+        assert_eq!(log.attenuation_db(-30.0), -60.0);
+        assert_eq!(log.gain(MUTE_DB), 0.0);
+
+        let linear: VolumeCurve = "linear".parse().unwrap();
+        assert!((linear.gain(-15.0) - 0.5).abs() < 1e-6);
+        assert_eq!(linear.gain(-30.0), 0.0);
+
+        let table: VolumeCurve = "table:-30=-50,-10=-10,0=0".parse().unwrap();
+        assert_eq!(table.attenuation_db(-20.0), -30.0);
+        assert_eq!(table.attenuation_db(-5.0), -5.0);
+        assert_eq!(table.attenuation_db(-30.0), -50.0);
+
+        assert_eq!(
+            "log:40".parse::<VolumeCurve>().unwrap(),
+            VolumeCurve::Logarithmic { range_db: 40.0 }
+        );
+        assert!("log:0".parse::<VolumeCurve>().is_err());
+        assert!("table:-30=-50".parse::<VolumeCurve>().is_err());
+        assert!("cubic".parse::<VolumeCurve>().is_err());
+    }
+
+    #[test]
+    fn test_ramp_and_mute() {
+        let mut volume = SoftwareVolume::new(VolumeCurve::default(), 44100);
+
+        // Full volume passes audio through bit for bit
+        let original: Vec<i32> = (0..64).map(|i| i * 0x0101_0101).collect();
+        let mut samples = original.clone();
+        volume.apply(&mut samples, 2);
+        assert_eq!(samples, original);
+
+        // A change ramps over RAMP_TIME without jumps
+        volume.set_volume(-15.0).unwrap();
+        let mut samples = vec![1 << 30; 2 * 2000];
+        volume.apply(&mut samples, 2);
+        let left: Vec<i32> = samples.iter().step_by(2).copied().collect();
+        let max_step = left.windows(2).map(|w| (w[0] - w[1]).abs()).max().unwrap();
+        assert!(max_step < (1 << 30) / 500, "{}", max_step);
+        assert!(!volume.is_ramping());
+        assert!((volume.gain() - 10f32.powf(-1.5)).abs() < 1e-6);
+
+        volume.set_volume(MUTE_DB).unwrap();
+        assert_eq!(volume.volume(), MUTE_DB);
+        let mut samples = vec![1 << 30; 2 * 1000];
+        volume.apply(&mut samples, 2);
+        assert_eq!(samples[samples.len() - 1], 0);
+        assert!(samples[0] > 0);
+
+        assert!(volume.set_volume(3.0).is_err());
+    }
+
+    #[test]
+    fn test_policy_caps_and_persists() {
+        let dir = std::env::temp_dir().join(format!("airplay2-volume-{}", std::process::id()));
+        let mut config = DeviceConfig {
+            max_volume: -10.0,
+            state_dir: Some(dir.clone()),
+            ..DeviceConfig::default()
+        };
+        let policy = VolumePolicy::from_config(&config);
+        assert_eq!(policy.initial_volume(), None);
+
+        let mut volume = SoftwareVolume::new(VolumeCurve::default(), 44100);
+        assert_eq!(policy.apply(&mut volume, 0.0).unwrap(), -10.0);
+        assert_eq!(volume.volume(), -10.0);
+        assert_eq!(policy.apply(&mut volume, MUTE_DB).unwrap(), MUTE_DB);
+        assert_eq!(policy.apply(&mut volume, -22.5).unwrap(), -22.5);
+
+        // A restart picks up the last volume unless one is configured
+        assert_eq!(
+            VolumePolicy::from_config(&config).initial_volume(),
+            Some(-22.5)
+        );
+        let info = crate::config::DeviceInfo::from_config(&config);
+        assert_eq!(info.initial_volume, Some(-22.5));
+        config.initial_volume = Some(-5.0);
+        assert_eq!(
+            VolumePolicy::from_config(&config).initial_volume(),
+            Some(-10.0)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,29 +1,130 @@
 //! Device configuration and information
 
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use super::{FeatureFlags, StatusFlags};
+use super::{FeatureFlags, FeaturePreset, ProtocolProfile, StatusFlags};
+use crate::audio::resample::DriftCorrection;
+use crate::audio::sinks::{SampleEncoding, SinkKind};
+use crate::audio::volume::{VolumeCurve, VolumePolicy, MAX_DB};
 
 /// Device configuration
 #[derive(Debug, Clone)]
 pub struct DeviceConfig {
     /// Device name visible to clients
     pub name: String,
-    /// Device ID (MAC address format)
+    /// Device ID (MAC address format), also the serial number and RAOP prefix
     pub device_id: String,
     /// Public identifier (UUID)
     pub public_id: Uuid,
     /// Feature flags
     pub features: FeatureFlags,
+    /// Protocol generation presented to senders
+    pub protocol_profile: ProtocolProfile,
     /// Status flags
     pub status: StatusFlags,
     /// Network interface to bind to
     pub interface: Option<String>,
     /// Server port
     pub port: u16,
+    /// Password senders must supply
+    pub password: Option<Secret>,
     /// Volume control enabled
     pub volume_enabled: bool,
+    /// Mapping from AirPlay volume to output attenuation
+    pub volume_curve: VolumeCurve,
+    /// Volume each session starts at, in AirPlay dB (-30 to 0)
+    pub initial_volume: Option<f32>,
+    /// Highest volume a sender may set, in AirPlay dB
+    pub max_volume: f32,
+    /// ALSA mixer control for hardware volume; software volume when unset
+    pub mixer: Option<String>,
+    /// ALSA device holding the mixer control
+    pub mixer_device: String,
+    /// Directory for state kept across restarts; no persistence when unset
+    pub state_dir: Option<PathBuf>,
+    /// Port of the local control API; disabled when unset
+    pub api_port: Option<u16>,
+    /// Address the control API binds to
+    pub api_bind: IpAddr,
+    /// Tear down a session after this long without sender activity
+    pub session_idle_timeout: Duration,
+    /// What to do when a second sender connects during a session
+    pub session_policy: SessionPolicy,
+    /// Controller identifiers that always win a session conflict
+    pub priority_controllers: Vec<String>,
+    /// Audio output
+    pub output: SinkKind,
+    /// Sample encoding of the headless outputs
+    pub output_bits: SampleEncoding,
+    /// Audio output device name (default device when unset)
+    pub output_device: Option<String>,
+    /// Output latency advertised in `/info`, updated from the active sink
+    pub output_latency: Duration,
+    /// How clock drift between sender and output device is corrected
+    pub drift_correction: DriftCorrection,
+}
+
+/// Policy for a second sender connecting while a session is active
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SessionPolicy {
+    /// Refuse the newcomer with 453 Not Enough Bandwidth
+    #[default]
+    Reject,
+    /// Stop the current sender and hand the session to the newcomer
+    Preempt,
+}
+
+impl FromStr for SessionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(SessionPolicy::Reject),
+            "preempt" => Ok(SessionPolicy::Preempt),
+            other => Err(format!(
+                "unknown session policy '{}' (expected reject or preempt)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SessionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SessionPolicy::Reject => "reject",
+            SessionPolicy::Preempt => "preempt",
+        })
+    }
+}
+
+/// A configuration value kept out of logs and printed configuration
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Wrap `value`
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    /// The secret itself
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
 }
 
 impl Default for DeviceConfig {
@@ -32,15 +133,48 @@ impl Default for DeviceConfig {
             name: "AirPlay Receiver".to_string(),
             device_id: "00:00:00:00:00:00".to_string(),
             public_id: Uuid::new_v4(),
-            features: FeatureFlags::default_airplay2(),
+            features: FeaturePreset::default().flags(),
+            protocol_profile: ProtocolProfile::default(),
             status: StatusFlags::empty(),
             interface: None,
             port: crate::DEFAULT_PORT,
+            password: None,
             volume_enabled: true,
+            volume_curve: VolumeCurve::default(),
+            initial_volume: None,
+            max_volume: MAX_DB,
+            mixer: None,
+            mixer_device: "default".to_string(),
+            state_dir: None,
+            api_port: None,
+            api_bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            session_idle_timeout: Duration::from_secs(30),
+            session_policy: SessionPolicy::default(),
+            priority_controllers: Vec::new(),
+            output: SinkKind::default(),
+            output_bits: SampleEncoding::default(),
+            output_device: None,
+            output_latency: Duration::from_millis(400),
+            drift_correction: DriftCorrection::default(),
         }
     }
 }
 
+impl DeviceConfig {
+    /// Feature flags as advertised under the protocol profile
+    pub fn advertised_features(&self) -> FeatureFlags {
+        self.protocol_profile.features(self.features)
+    }
+
+    /// Source version for the protocol profile
+    pub fn server_version(&self) -> &'static str {
+        self.protocol_profile.server_version()
+    }
+}
+
+/// `volumeControlType` for a receiver that takes absolute dB volumes
+pub const VOLUME_CONTROL_ABSOLUTE: u32 = 4;
+
 /// Device information response for /info endpoint
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -73,6 +207,12 @@ pub struct DeviceInfo {
     pub source_version: String,
     /// Status flags as hex string
     pub status_flags: String,
+    /// Volume control type; absent when the sender must not control volume
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume_control_type: Option<u32>,
+    /// Volume a new session starts at, in AirPlay dB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_volume: Option<f32>,
 }
 
 /// Audio latency information
@@ -91,10 +231,10 @@ impl DeviceInfo {
         Self {
             audio_latencies: vec![AudioLatency {
                 input_latency_micros: 0,
-                output_latency_micros: 400_000,
+                output_latency_micros: config.output_latency.as_micros() as u32,
             }],
             device_id: config.device_id.clone(),
-            features: config.features.bits(),
+            features: config.advertised_features().bits(),
             keep_alive_low_power: true,
             keep_alive_send_stats_as_body: true,
             manufacturer: "OpenAirplay".to_string(),
@@ -104,8 +244,13 @@ impl DeviceInfo {
             pi: config.public_id.to_string(),
             protocol_version: crate::AIRPLAY_PROTOCOL_VERSION.to_string(),
             sdk: crate::AIRPLAY_SDK_VERSION.to_string(),
-            source_version: crate::SERVER_VERSION.to_string(),
+            source_version: config.server_version().to_string(),
             status_flags: config.status.to_hex_string(),
+            volume_control_type: config.volume_enabled.then_some(VOLUME_CONTROL_ABSOLUTE),
+            initial_volume: config
+                .volume_enabled
+                .then(|| VolumePolicy::from_config(config).initial_volume())
+                .flatten(),
         }
     }
 }
@@ -0,0 +1,526 @@
+//! Configuration file and environment overrides
+//!
+//! Settings come from up to three layers over the built-in defaults, each
+//! overriding the one before:
+//! 1. a TOML file (`--config`)
+//! 2. `AIRPLAY2_*` environment variables
+//! 3. command-line options
+//!
+//! Every layer is a [`FileConfig`] in which unset keys are `None`; layers are
+//! merged first and the result applied to a [`DeviceConfig`] once.
+//!
+//! ```toml
+//! name = "Living Room"
+//! password = "hunter2"
+//!
+//! [features]
+//! preset = "default_airplay2"
+//! xor = [20]
+//! protocol-profile = "ap2-buffered"
+//!
+//! [audio]
+//! output = "cpal"
+//!
+//! [volume]
+//! curve = "log:50"
+//! max = -6.0
+//!
+//! [session]
+//! idle-timeout = 30
+//! policy = "preempt"
+//! ```
+//!
+//! Environment variables name the same keys in upper case with `_` for both
+//! the section separator and dashes: `AIRPLAY2_VOLUME_MAX=-6`,
+//! `AIRPLAY2_STATE_DIR=/var/lib/airplay2`. Lists are comma-separated.
+
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+use super::{
+    parse_feature_bit, DeviceConfig, FeaturePreset, ProtocolProfile, Secret, SessionPolicy,
+};
+use crate::audio::resample::DriftCorrection;
+use crate::audio::sinks::{SampleEncoding, SinkKind};
+use crate::audio::volume::{VolumeCurve, MAX_DB, MIN_DB};
+use crate::utils::network::{format_mac, parse_mac};
+
+/// Prefix of environment overrides
+pub const ENV_PREFIX: &str = "AIRPLAY2_";
+
+/// Shown in place of secrets when printing the configuration
+const REDACTED: &str = "<redacted>";
+
+/// Table names, used to split environment variable names
+const SECTIONS: &[&str] = &["features", "audio", "volume", "session", "api"];
+
+/// Configuration errors
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid configuration file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("Unknown configuration key: {0}")]
+    UnknownKey(String),
+
+    #[error("Invalid value for {key}: {message}")]
+    Invalid { key: String, message: String },
+
+    #[error("Failed to format configuration: {0}")]
+    Format(#[from] toml::ser::Error),
+}
+
+/// `[features]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FeatureSection {
+    pub preset: Option<String>,
+    pub and: Option<Vec<u8>>,
+    pub or: Option<Vec<u8>>,
+    pub xor: Option<Vec<u8>>,
+    pub protocol_profile: Option<String>,
+}
+
+/// `[audio]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AudioSection {
+    pub output: Option<String>,
+    pub output_device: Option<String>,
+    pub output_bits: Option<u16>,
+    pub drift_correction: Option<String>,
+}
+
+/// `[volume]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct VolumeSection {
+    pub enabled: Option<bool>,
+    pub curve: Option<String>,
+    pub initial: Option<f32>,
+    pub max: Option<f32>,
+    pub mixer: Option<String>,
+    pub mixer_device: Option<String>,
+}
+
+/// `[session]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SessionSection {
+    /// Seconds without sender activity before a session is torn down
+    pub idle_timeout: Option<u64>,
+    pub policy: Option<String>,
+    pub priority_controllers: Option<Vec<String>>,
+}
+
+/// `[api]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ApiSection {
+    pub port: Option<u16>,
+    pub bind: Option<IpAddr>,
+}
+
+/// One configuration layer
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FileConfig {
+    pub name: Option<String>,
+    pub device_id: Option<String>,
+    pub interface: Option<String>,
+    pub port: Option<u16>,
+    pub password: Option<String>,
+    pub state_dir: Option<PathBuf>,
+    pub features: FeatureSection,
+    pub audio: AudioSection,
+    pub volume: VolumeSection,
+    pub session: SessionSection,
+    pub api: ApiSection,
+}
+
+/// Overwrite each listed field of `$base` that is set in `$over`
+macro_rules! merge_fields {
+    ($base:expr, $over:expr; $($field:ident),+) => {
+        $(if $over.$field.is_some() {
+            $base.$field = $over.$field;
+        })+
+    };
+}
+
+impl FileConfig {
+    /// Read a TOML file
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Collect `AIRPLAY2_*` overrides from `vars`
+    ///
+    /// Variables that name no key are logged and skipped.
+    pub fn from_env(vars: impl IntoIterator<Item = (String, String)>) -> Result<Self, ConfigError> {
+        let mut layer = Self::default();
+        for (name, value) in vars {
+            let Some(rest) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let rest = rest.to_ascii_lowercase();
+            let key = match rest.split_once('_') {
+                Some((section, key)) if SECTIONS.contains(&section) => {
+                    format!("{}.{}", section, key.replace('_', "-"))
+                }
+                _ => rest.replace('_', "-"),
+            };
+            match layer.set(&key, &value) {
+                Err(ConfigError::UnknownKey(_)) => warn!("Ignoring unknown variable {}", name),
+                result => result?,
+            }
+        }
+        Ok(layer)
+    }
+
+    /// Set the dotted `key` (e.g. `volume.max`) from its text form
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let text = || Some(value.to_string());
+        match key {
+            "name" => self.name = text(),
+            "device-id" => self.device_id = text(),
+            "interface" => self.interface = text(),
+            "port" => self.port = Some(parse(key, value)?),
+            "password" => self.password = text(),
+            "state-dir" => self.state_dir = Some(PathBuf::from(value)),
+            "features.preset" => self.features.preset = text(),
+            "features.and" => self.features.and = Some(parse_bits(key, value)?),
+            "features.or" => self.features.or = Some(parse_bits(key, value)?),
+            "features.xor" => self.features.xor = Some(parse_bits(key, value)?),
+            "features.protocol-profile" => self.features.protocol_profile = text(),
+            "audio.output" => self.audio.output = text(),
+            "audio.output-device" => self.audio.output_device = text(),
+            "audio.output-bits" => self.audio.output_bits = Some(parse(key, value)?),
+            "audio.drift-correction" => self.audio.drift_correction = text(),
+            "volume.enabled" => self.volume.enabled = Some(parse(key, value)?),
+            "volume.curve" => self.volume.curve = text(),
+            "volume.initial" => self.volume.initial = Some(parse(key, value)?),
+            "volume.max" => self.volume.max = Some(parse(key, value)?),
+            "volume.mixer" => self.volume.mixer = text(),
+            "volume.mixer-device" => self.volume.mixer_device = text(),
+            "session.idle-timeout" => self.session.idle_timeout = Some(parse(key, value)?),
+            "session.policy" => self.session.policy = text(),
+            "session.priority-controllers" => {
+                self.session.priority_controllers =
+                    Some(split_list(value).map(String::from).collect())
+            }
+            "api.port" => self.api.port = Some(parse(key, value)?),
+            "api.bind" => self.api.bind = Some(parse(key, value)?),
+            _ => return Err(ConfigError::UnknownKey(key.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Overlay `over`: every key it sets wins
+    pub fn merge(&mut self, over: FileConfig) {
+        merge_fields!(self, over; name, device_id, interface, port, password, state_dir);
+        merge_fields!(self.features, over.features; preset, and, or, xor, protocol_profile);
+        merge_fields!(self.audio, over.audio; output, output_device, output_bits, drift_correction);
+        merge_fields!(self.volume, over.volume; enabled, curve, initial, max, mixer, mixer_device);
+        merge_fields!(self.session, over.session; idle_timeout, policy, priority_controllers);
+        merge_fields!(self.api, over.api; port, bind);
+    }
+
+    /// Apply every key this layer sets to `config`
+    pub fn apply(&self, config: &mut DeviceConfig) -> Result<(), ConfigError> {
+        if let Some(name) = &self.name {
+            config.name = name.clone();
+        }
+        if let Some(device_id) = &self.device_id {
+            let mac = parse_mac(device_id).ok_or_else(|| {
+                invalid("device-id", "expected a MAC address like AA:BB:CC:DD:EE:FF")
+            })?;
+            config.device_id = format_mac(&mac);
+        }
+        if let Some(interface) = &self.interface {
+            config.interface = Some(interface.clone());
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(password) = &self.password {
+            config.password = Some(Secret::new(password.as_str()));
+        }
+        if let Some(dir) = &self.state_dir {
+            config.state_dir = Some(dir.clone());
+        }
+
+        let features = &self.features;
+        if let Some(profile) = &features.protocol_profile {
+            config.protocol_profile =
+                parse::<ProtocolProfile>("features.protocol-profile", profile)?;
+        }
+        if features.preset.is_some()
+            || features.and.is_some()
+            || features.or.is_some()
+            || features.xor.is_some()
+        {
+            let preset = match &features.preset {
+                Some(preset) => parse::<FeaturePreset>("features.preset", preset)?,
+                None => FeaturePreset::default(),
+            };
+            let bits = |list: &Option<Vec<u8>>| list.clone().unwrap_or_default();
+            config.features = preset
+                .flags()
+                .with_overrides(
+                    &bits(&features.and),
+                    &bits(&features.or),
+                    &bits(&features.xor),
+                )
+                .map_err(|e| invalid("features", e))?;
+        }
+
+        let audio = &self.audio;
+        if let Some(output) = &audio.output {
+            config.output = parse::<SinkKind>("audio.output", output)?;
+        }
+        if let Some(device) = &audio.output_device {
+            config.output_device = Some(device.clone());
+        }
+        if let Some(bits) = audio.output_bits {
+            config.output_bits = SampleEncoding::from_bits(bits)
+                .ok_or_else(|| invalid("audio.output-bits", "expected 16, 24 or 32"))?;
+        }
+        if let Some(mode) = &audio.drift_correction {
+            config.drift_correction = parse::<DriftCorrection>("audio.drift-correction", mode)?;
+        }
+
+        let volume = &self.volume;
+        if let Some(enabled) = volume.enabled {
+            config.volume_enabled = enabled;
+        }
+        if let Some(curve) = &volume.curve {
+            config.volume_curve = parse::<VolumeCurve>("volume.curve", curve)?;
+        }
+        if let Some(db) = volume.initial {
+            config.initial_volume = Some(volume_db("volume.initial", db)?);
+        }
+        if let Some(db) = volume.max {
+            config.max_volume = volume_db("volume.max", db)?;
+        }
+        if let Some(mixer) = &volume.mixer {
+            config.mixer = Some(mixer.clone());
+        }
+        if let Some(device) = &volume.mixer_device {
+            config.mixer_device = device.clone();
+        }
+
+        let session = &self.session;
+        if let Some(secs) = session.idle_timeout {
+            config.session_idle_timeout = Duration::from_secs(secs);
+        }
+        if let Some(policy) = &session.policy {
+            config.session_policy = parse::<SessionPolicy>("session.policy", policy)?;
+        }
+        if let Some(controllers) = &session.priority_controllers {
+            config.priority_controllers = controllers.clone();
+        }
+
+        if let Some(port) = self.api.port {
+            config.api_port = Some(port);
+        }
+        if let Some(bind) = self.api.bind {
+            config.api_bind = bind;
+        }
+        Ok(())
+    }
+
+    /// Every setting of `config` as a layer, with secrets redacted
+    ///
+    /// `config` does not remember how its feature flags were composed, so
+    /// the `[features]` keys are taken from `layer`, the merged layers it was
+    /// built from.
+    pub fn effective(config: &DeviceConfig, layer: &FileConfig) -> Self {
+        Self {
+            name: Some(config.name.clone()),
+            device_id: Some(config.device_id.clone()),
+            interface: config.interface.clone(),
+            port: Some(config.port),
+            password: config.password.as_ref().map(|_| REDACTED.to_string()),
+            state_dir: config.state_dir.clone(),
+            features: FeatureSection {
+                preset: Some(
+                    layer
+                        .features
+                        .preset
+                        .clone()
+                        .unwrap_or_else(|| FeaturePreset::default().to_string()),
+                ),
+                protocol_profile: Some(config.protocol_profile.to_string()),
+                ..layer.features.clone()
+            },
+            audio: AudioSection {
+                output: Some(config.output.to_string()),
+                output_device: config.output_device.clone(),
+                output_bits: Some(config.output_bits.bits()),
+                drift_correction: Some(config.drift_correction.to_string()),
+            },
+            volume: VolumeSection {
+                enabled: Some(config.volume_enabled),
+                curve: Some(config.volume_curve.to_string()),
+                initial: config.initial_volume,
+                max: Some(config.max_volume),
+                mixer: config.mixer.clone(),
+                mixer_device: Some(config.mixer_device.clone()),
+            },
+            session: SessionSection {
+                idle_timeout: Some(config.session_idle_timeout.as_secs()),
+                policy: Some(config.session_policy.to_string()),
+                priority_controllers: Some(config.priority_controllers.clone()),
+            },
+            api: ApiSection {
+                port: config.api_port,
+                bind: Some(config.api_bind),
+            },
+        }
+    }
+
+    /// Format as TOML
+    pub fn to_toml(&self) -> Result<String, ConfigError> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+fn invalid(key: &str, message: impl Display) -> ConfigError {
+    ConfigError::Invalid {
+        key: key.to_string(),
+        message: message.to_string(),
+    }
+}
+
+fn parse<T>(key: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value.trim().parse().map_err(|e| invalid(key, e))
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|item| !item.is_empty())
+}
+
+fn parse_bits(key: &str, value: &str) -> Result<Vec<u8>, ConfigError> {
+    split_list(value)
+        .map(|bit| parse_feature_bit(bit).map_err(|e| invalid(key, e)))
+        .collect()
+}
+
+fn volume_db(key: &str, db: f32) -> Result<f32, ConfigError> {
+    if (MIN_DB..=MAX_DB).contains(&db) {
+        Ok(db)
+    } else {
+        Err(invalid(key, format!("{} is not between -30 and 0 dB", db)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FeatureFlags;
+
+    const FILE: &str = r#"
+        name = "Kitchen"
+        password = "hunter2"
+
+        [features]
+        preset = "minimal_audio"
+        or = [16, 17]
+
+        [volume]
+        max = -6.0
+        curve = "linear"
+
+        [session]
+        idle-timeout = 10
+        policy = "preempt"
+    "#;
+
+    #[test]
+    fn test_layer_precedence() {
+        let mut layer: FileConfig = toml::from_str(FILE).unwrap();
+        let env = FileConfig::from_env([
+            ("AIRPLAY2_VOLUME_MAX".to_string(), "-3".to_string()),
+            ("AIRPLAY2_STATE_DIR".to_string(), "/tmp/state".to_string()),
+            ("AIRPLAY2_FEATURES_XOR".to_string(), "16,Ft17".to_string()),
+            ("AIRPLAY2_NOT_A_KEY".to_string(), "1".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ])
+        .unwrap();
+        let cli = FileConfig {
+            name: Some("Office".to_string()),
+            ..FileConfig::default()
+        };
+        layer.merge(env);
+        layer.merge(cli);
+
+        let mut config = DeviceConfig::default();
+        layer.apply(&mut config).unwrap();
+        assert_eq!(config.name, "Office");
+        assert_eq!(config.max_volume, -3.0);
+        assert_eq!(config.volume_curve, VolumeCurve::Linear);
+        assert_eq!(config.state_dir, Some(PathBuf::from("/tmp/state")));
+        assert_eq!(config.session_idle_timeout, Duration::from_secs(10));
+        assert_eq!(config.session_policy, SessionPolicy::Preempt);
+        assert_eq!(config.password.as_ref().unwrap().expose(), "hunter2");
+        // or [16, 17] from the file, then xor 16 17 from the environment
+        assert_eq!(config.features, FeatureFlags::minimal_audio());
+
+        assert!(toml::from_str::<FileConfig>("nmae = \"typo\"").is_err());
+        let bad = FileConfig::from_env([("AIRPLAY2_PORT".to_string(), "http".to_string())]);
+        assert!(matches!(bad, Err(ConfigError::Invalid { .. })));
+        let mut bad = FileConfig::default();
+        bad.volume.max = Some(6.0);
+        assert!(bad.apply(&mut DeviceConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_effective_config_redacts_secrets() {
+        let layer: FileConfig = toml::from_str(FILE).unwrap();
+        let mut config = DeviceConfig::default();
+        layer.apply(&mut config).unwrap();
+
+        let text = FileConfig::effective(&config, &layer).to_toml().unwrap();
+        assert!(!text.contains("hunter2"));
+        assert!(text.contains(REDACTED));
+
+        // The printed configuration loads back to the same settings
+        let mut reloaded: FileConfig = toml::from_str(&text).unwrap();
+        reloaded.password = layer.password.clone();
+        let mut again = DeviceConfig::default();
+        reloaded.apply(&mut again).unwrap();
+        assert_eq!(again.features, config.features);
+        assert_eq!(again.volume_curve, config.volume_curve);
+        assert_eq!(again.output, config.output);
+        assert_eq!(again.session_policy, config.session_policy);
+    }
+}
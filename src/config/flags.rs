@@ -7,11 +7,27 @@
 //! Feature flags are 64-bit values that indicate the capabilities supported by the receiver.
 //! The default AirPlay 2 configuration uses flags value: `0x0001c300405f4200`
 //!
+//! ## Composition
+//!
+//! The advertised set starts from a [`FeaturePreset`] and can be adjusted bit
+//! by bit, like the Python receiver's `-ftxor`: see
+//! [`FeatureFlags::with_overrides`]. [`FeatureFlags::conflicts`] reports
+//! combinations that would make the receiver promise something it can't do.
+//!
+//! Until this build can pair, the presets leave out the pairing bits
+//! (Ft46-Ft48), and setting them explicitly is refused.
+//!
 //! ## References
 //!
 //! Based on the Python ap2-receiver implementation and AirPlay 2 protocol documentation.
 
+use std::fmt;
+use std::str::FromStr;
+
 use bitflags::bitflags;
+use thiserror::Error;
+
+use crate::crypto::HAP_PAIRING_AVAILABLE;
 
 bitflags! {
     /// AirPlay 2 feature flags
@@ -203,6 +219,158 @@ impl FeatureFlags {
     pub fn supports_homekit(&self) -> bool {
         self.contains(Self::HOMEKIT_PAIRING)
     }
+
+    /// Ft46-Ft48, which promise HAP pairing
+    pub const PAIRING: Self = Self::HOMEKIT_PAIRING
+        .union(Self::PEER_MANAGEMENT)
+        .union(Self::TRANSIENT_PAIRING);
+
+    /// Get AirPlay 1 (RAOP) feature flags
+    ///
+    /// The minimal audio set plus metadata, without Ft30 so senders treat
+    /// the receiver as AirPlay 1.
+    pub fn airplay1() -> Self {
+        Self::minimal_audio() | Self::AUDIO_META_PROGRESS | Self::AUDIO_META_TXT_DAAP
+    }
+
+    /// The flag for bit number `bit` (0 to 63)
+    pub fn bit(bit: u8) -> Result<Self, FlagError> {
+        if bit < 64 {
+            Ok(Self::from_bits_retain(1 << bit))
+        } else {
+            Err(FlagError::InvalidBit(bit.to_string()))
+        }
+    }
+
+    /// Apply bit overrides in a fixed order: keep only the `and` bits (when
+    /// any are given), then set the `or` bits, then toggle the `xor` bits
+    pub fn with_overrides(self, and: &[u8], or: &[u8], xor: &[u8]) -> Result<Self, FlagError> {
+        let collect = |bits: &[u8]| {
+            bits.iter()
+                .try_fold(Self::empty(), |acc, &bit| Ok(acc | Self::bit(bit)?))
+        };
+        let mut flags = self;
+        if !and.is_empty() {
+            flags &= collect(and)?;
+        }
+        flags |= collect(or)?;
+        flags ^= collect(xor)?;
+        Ok(flags)
+    }
+
+    /// Combinations this receiver cannot honour
+    pub fn conflicts(&self) -> Vec<FlagConflict> {
+        let mut conflicts = Vec::new();
+        if self.contains(Self::BUFFERED_AUDIO) && !self.contains(Self::PTP_CLOCK) {
+            conflicts.push(FlagConflict::BufferedWithoutPtp);
+        }
+        if self.contains(Self::TRANSIENT_PAIRING) && !self.contains(Self::HOMEKIT_PAIRING) {
+            conflicts.push(FlagConflict::TransientWithoutHomeKit);
+        }
+        if self.contains(Self::HOMEKIT_PAIRING) && !HAP_PAIRING_AVAILABLE {
+            conflicts.push(FlagConflict::PairingUnavailable);
+        }
+        conflicts
+    }
+
+    /// Fail on the first conflict
+    pub fn validate(&self) -> Result<(), FlagConflict> {
+        match self.conflicts().into_iter().next() {
+            Some(conflict) => Err(conflict),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Feature flag parsing errors
+#[derive(Debug, Error)]
+pub enum FlagError {
+    #[error("Unknown feature preset: {0} (expected default_airplay2, minimal_audio or airplay1)")]
+    UnknownPreset(String),
+
+    #[error("Invalid feature bit: {0} (expected 0 to 63, optionally prefixed with Ft)")]
+    InvalidBit(String),
+}
+
+/// Feature combination that would mislead senders
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum FlagConflict {
+    /// `supports_buffered_audio()` needs both bits
+    #[error("Ft40 BufferedAudio requires Ft41 PTPClock")]
+    BufferedWithoutPtp,
+
+    /// Transient pairing is a HomeKit pairing mode
+    #[error("Ft48 TransientPairing requires Ft46 HomeKitPairing")]
+    TransientWithoutHomeKit,
+
+    /// Pairing bits were set explicitly but are not implemented yet
+    #[error("Ft46 HomeKitPairing is advertised but this build cannot pair")]
+    PairingUnavailable,
+}
+
+/// Named starting point for the advertised features
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FeaturePreset {
+    /// [`FeatureFlags::default_airplay2`]
+    #[default]
+    Airplay2,
+    /// [`FeatureFlags::minimal_audio`]
+    MinimalAudio,
+    /// [`FeatureFlags::airplay1`]
+    Airplay1,
+}
+
+impl FeaturePreset {
+    /// The preset's flags, without the pairing bits while this build cannot
+    /// pair
+    pub fn flags(self) -> FeatureFlags {
+        let flags = match self {
+            FeaturePreset::Airplay2 => FeatureFlags::default_airplay2(),
+            FeaturePreset::MinimalAudio => FeatureFlags::minimal_audio(),
+            FeaturePreset::Airplay1 => FeatureFlags::airplay1(),
+        };
+        if HAP_PAIRING_AVAILABLE {
+            flags
+        } else {
+            flags - FeatureFlags::PAIRING
+        }
+    }
+}
+
+impl FromStr for FeaturePreset {
+    type Err = FlagError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default_airplay2" | "airplay2" => Ok(FeaturePreset::Airplay2),
+            "minimal_audio" | "minimal" => Ok(FeaturePreset::MinimalAudio),
+            "airplay1" | "ap1" => Ok(FeaturePreset::Airplay1),
+            other => Err(FlagError::UnknownPreset(other.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for FeaturePreset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FeaturePreset::Airplay2 => "default_airplay2",
+            FeaturePreset::MinimalAudio => "minimal_audio",
+            FeaturePreset::Airplay1 => "airplay1",
+        })
+    }
+}
+
+/// Parse a feature bit number such as `40` or `Ft40`
+pub fn parse_feature_bit(s: &str) -> Result<u8, FlagError> {
+    let digits = s
+        .strip_prefix("Ft")
+        .or_else(|| s.strip_prefix("ft"))
+        .unwrap_or(s);
+    digits
+        .parse::<u8>()
+        .ok()
+        .filter(|&bit| bit < 64)
+        .ok_or_else(|| FlagError::InvalidBit(s.to_string()))
 }
 
 bitflags! {
@@ -385,6 +553,44 @@ mod tests {
         assert_eq!(flags.to_hex_string(), "0x7");
     }
 
+    #[test]
+    fn test_feature_overrides() {
+        assert_eq!(parse_feature_bit("Ft40").unwrap(), 40);
+        assert_eq!(parse_feature_bit("9").unwrap(), 9);
+        assert!(parse_feature_bit("64").is_err());
+        assert!(parse_feature_bit("Ftx").is_err());
+        assert_eq!(
+            "minimal".parse::<FeaturePreset>().unwrap(),
+            FeaturePreset::MinimalAudio
+        );
+        assert!("ap3".parse::<FeaturePreset>().is_err());
+
+        let base = FeaturePreset::Airplay2.flags();
+        assert_eq!(base.conflicts(), []);
+        assert_eq!(base | FeatureFlags::PAIRING, FeatureFlags::default_airplay2());
+        // Pairing can't be advertised until this build can pair
+        let flags = base.with_overrides(&[], &[], &[46, 47, 48]).unwrap();
+        assert!(flags.supports_homekit());
+        assert_eq!(flags.conflicts(), [FlagConflict::PairingUnavailable]);
+        assert_eq!(flags.validate(), Err(FlagConflict::PairingUnavailable));
+
+        // Keeping only the minimal bits reproduces the preset
+        let flags = base
+            .with_overrides(&[9, 14, 18, 19, 20, 22], &[], &[])
+            .unwrap();
+        assert_eq!(flags, FeatureFlags::minimal_audio());
+
+        let flags = flags.with_overrides(&[], &[40], &[]).unwrap();
+        assert!(!flags.supports_buffered_audio());
+        assert_eq!(flags.validate(), Err(FlagConflict::BufferedWithoutPtp));
+
+        let flags = FeatureFlags::airplay1()
+            .with_overrides(&[], &[48], &[])
+            .unwrap();
+        assert_eq!(flags.validate(), Err(FlagConflict::TransientWithoutHomeKit));
+        assert!(!FeatureFlags::airplay1().is_airplay2());
+    }
+
     #[test]
     fn test_feature_flags_are_copy() {
         // Verify that FeatureFlags implements Copy
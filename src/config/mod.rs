@@ -3,8 +3,23 @@
 //! This module contains device configuration, feature flags, and status flags
 //! used throughout the AirPlay 2 receiver.
 
+use std::sync::{Arc, RwLock};
+
 mod device;
+pub mod file;
 mod flags;
+mod profile;
+mod reload;
+mod state;
+
+pub use device::{DeviceConfig, DeviceInfo, Secret, SessionPolicy};
+pub use file::{ConfigError, FileConfig};
+pub use flags::{
+    parse_feature_bit, FeatureFlags, FeaturePreset, FlagConflict, FlagError, StatusFlags,
+};
+pub use profile::{ProfileError, ProtocolProfile};
+pub use reload::{ChangeEffect, ConfigChange, ConfigReloader, ConfigSource, ReloadReport};
+pub use state::StateStore;
 
-pub use device::{DeviceConfig, DeviceInfo};
-pub use flags::{FeatureFlags, StatusFlags};
+/// Device configuration shared between the server, sessions and mDNS
+pub type SharedConfig = Arc<RwLock<DeviceConfig>>;
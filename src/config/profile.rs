@@ -0,0 +1,154 @@
+//! Protocol profiles
+//!
+//! Senders pick their protocol from what the receiver advertises, mostly
+//! the source version (`srcvers`):
+//! - above 355 with Ft40/Ft41: AirPlay 2 buffered audio timed by PTP
+//! - 355 and below: AirPlay 2 realtime audio timed by NTP
+//! - 200: AirPlay 1, sessions start with `ANNOUNCE`
+//!
+//! A [`ProtocolProfile`] keeps the version, feature flags, mDNS services
+//! and accepted RTSP methods consistent with each other.
+
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use super::FeatureFlags;
+
+/// RTSP methods every profile accepts
+const COMMON_METHODS: &[&str] = &[
+    "OPTIONS",
+    "SETUP",
+    "RECORD",
+    "FLUSH",
+    "TEARDOWN",
+    "GET_PARAMETER",
+    "SET_PARAMETER",
+    "GET",
+    "POST",
+];
+
+/// Unknown profile name
+#[derive(Debug, Error)]
+#[error("Unknown protocol profile: {0} (expected ap2-buffered, ap2-realtime or ap1)")]
+pub struct ProfileError(String);
+
+/// Protocol generation presented to senders
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtocolProfile {
+    /// AirPlay 2 with buffered audio and PTP timing
+    #[default]
+    Ap2Buffered,
+    /// AirPlay 2 with realtime audio and NTP timing
+    Ap2Realtime,
+    /// AirPlay 1 (RAOP) with `ANNOUNCE` and SDP
+    Ap1Raop,
+}
+
+impl ProtocolProfile {
+    /// Source version advertised as `srcvers` and in `/info`
+    pub fn server_version(self) -> &'static str {
+        match self {
+            ProtocolProfile::Ap2Buffered => crate::SERVER_VERSION,
+            ProtocolProfile::Ap2Realtime => "355.0",
+            ProtocolProfile::Ap1Raop => "200.0",
+        }
+    }
+
+    /// `features` restricted to what the profile can serve
+    pub fn features(self, features: FeatureFlags) -> FeatureFlags {
+        let buffered = FeatureFlags::BUFFERED_AUDIO | FeatureFlags::PTP_CLOCK;
+        match self {
+            ProtocolProfile::Ap2Buffered => features,
+            ProtocolProfile::Ap2Realtime => features - buffered,
+            ProtocolProfile::Ap1Raop => {
+                features - buffered - FeatureFlags::UNIFIED_ADVERTISING_INFO - FeatureFlags::PAIRING
+            }
+        }
+    }
+
+    /// Whether `_airplay._tcp` is published; AirPlay 1 only uses `_raop._tcp`
+    pub fn advertises_airplay(self) -> bool {
+        self != ProtocolProfile::Ap1Raop
+    }
+
+    /// RTSP methods accepted from senders
+    pub fn rtsp_methods(self) -> Vec<&'static str> {
+        let mut methods = COMMON_METHODS.to_vec();
+        match self {
+            ProtocolProfile::Ap2Buffered => methods.extend([
+                "SETRATEANCHORTIME",
+                "FLUSHBUFFERED",
+                "SETPEERS",
+                "SETPEERSX",
+            ]),
+            ProtocolProfile::Ap2Realtime => {
+                methods.extend(["SETRATEANCHORTIME", "SETPEERS", "SETPEERSX"])
+            }
+            ProtocolProfile::Ap1Raop => methods.push("ANNOUNCE"),
+        }
+        methods
+    }
+
+    /// Whether `method` is accepted
+    pub fn accepts_method(self, method: &str) -> bool {
+        self.rtsp_methods().contains(&method)
+    }
+}
+
+impl FromStr for ProtocolProfile {
+    type Err = ProfileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ap2-buffered" | "ap2" => Ok(ProtocolProfile::Ap2Buffered),
+            "ap2-realtime" | "realtime" => Ok(ProtocolProfile::Ap2Realtime),
+            "ap1" | "raop" => Ok(ProtocolProfile::Ap1Raop),
+            other => Err(ProfileError(other.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProtocolProfile::Ap2Buffered => "ap2-buffered",
+            ProtocolProfile::Ap2Realtime => "ap2-realtime",
+            ProtocolProfile::Ap1Raop => "ap1",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles_are_consistent() {
+        let base = FeatureFlags::default_airplay2();
+
+        let ap2 = ProtocolProfile::default();
+        assert_eq!(ap2.features(base), base);
+        assert!(ap2.accepts_method("FLUSHBUFFERED"));
+        assert!(!ap2.accepts_method("ANNOUNCE"));
+
+        let realtime: ProtocolProfile = "ap2-realtime".parse().unwrap();
+        let features = realtime.features(base);
+        assert!(features.is_airplay2());
+        assert!(!features.supports_buffered_audio());
+        assert!(!realtime.accepts_method("FLUSHBUFFERED"));
+        let version: f32 = realtime.server_version().parse().unwrap();
+        assert!(version <= 355.0);
+
+        let ap1: ProtocolProfile = "raop".parse().unwrap();
+        let features = ap1.features(base);
+        assert!(!features.is_airplay2());
+        assert!(!features.supports_homekit());
+        assert!(features.validate().is_ok());
+        assert!(ap1.accepts_method("ANNOUNCE"));
+        assert!(!ap1.advertises_airplay());
+        assert_eq!(ap1.to_string().parse::<ProtocolProfile>().unwrap(), ap1);
+        assert!("ap3".parse::<ProtocolProfile>().is_err());
+    }
+}
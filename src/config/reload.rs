@@ -0,0 +1,322 @@
+//! Live configuration reload
+//!
+//! On `SIGHUP` or `POST /config/reload` the configuration file is read
+//! again, merged under the same environment and command-line overrides as at
+//! startup, and compared with the running [`DeviceConfig`]. Each changed key
+//! takes effect according to its [`ChangeEffect`]:
+//! - name and features: applied now and re-published over mDNS
+//! - session policy and idle timeout: applied now
+//! - volume, password and output: stored now, used from the next session
+//! - listeners, identity, state and mixer: reported, need a restart
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use super::{ConfigError, DeviceConfig, FileConfig, SharedConfig, StateStore};
+use crate::network::ServiceAdvertiser;
+use crate::utils::network::{device_id, format_mac};
+
+/// When a changed key takes effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeEffect {
+    /// Applied to the running receiver
+    Live,
+    /// Applied when the next session starts
+    NextSession,
+    /// Not applied; the receiver must be restarted
+    Restart,
+}
+
+/// A key whose value differs from the running configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigChange {
+    /// Dotted key as in the configuration file, e.g. `volume.max`
+    pub key: &'static str,
+    pub effect: ChangeEffect,
+}
+
+/// Outcome of a reload
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReloadReport {
+    pub changes: Vec<ConfigChange>,
+}
+
+impl ReloadReport {
+    /// Keys that were not applied
+    pub fn restart_required(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.changes
+            .iter()
+            .filter(|c| c.effect == ChangeEffect::Restart)
+            .map(|c| c.key)
+    }
+
+    /// Whether the mDNS records changed
+    pub fn republish(&self) -> bool {
+        self.changes
+            .iter()
+            .any(|c| matches!(c.key, "name" | "features"))
+    }
+}
+
+/// Compare `$current` with `$new`, copying every changed field that does not
+/// need a restart
+macro_rules! reconcile_fields {
+    ($current:ident, $new:ident, $changes:ident; $($field:ident => $key:literal, $effect:ident;)+) => {
+        $(if $current.$field != $new.$field {
+            $changes.push(ConfigChange {
+                key: $key,
+                effect: ChangeEffect::$effect,
+            });
+            if ChangeEffect::$effect != ChangeEffect::Restart {
+                $current.$field = $new.$field.clone();
+            }
+        })+
+    };
+}
+
+/// Bring `current` in line with `new` as far as possible without a restart
+///
+/// Runtime state such as the status flags and output latency is left alone.
+pub fn reconcile(current: &mut DeviceConfig, new: &DeviceConfig) -> ReloadReport {
+    let mut changes = Vec::new();
+    reconcile_fields!(current, new, changes;
+        name => "name", Live;
+        features => "features", Live;
+        session_idle_timeout => "session.idle-timeout", Live;
+        session_policy => "session.policy", Live;
+        priority_controllers => "session.priority-controllers", Live;
+        password => "password", NextSession;
+        volume_enabled => "volume.enabled", NextSession;
+        volume_curve => "volume.curve", NextSession;
+        initial_volume => "volume.initial", NextSession;
+        max_volume => "volume.max", NextSession;
+        output => "audio.output", NextSession;
+        output_bits => "audio.output-bits", NextSession;
+        output_device => "audio.output-device", NextSession;
+        drift_correction => "audio.drift-correction", NextSession;
+        device_id => "device-id", Restart;
+        interface => "interface", Restart;
+        port => "port", Restart;
+        state_dir => "state-dir", Restart;
+        protocol_profile => "features.protocol-profile", Restart;
+        mixer => "volume.mixer", Restart;
+        mixer_device => "volume.mixer-device", Restart;
+        api_port => "api.port", Restart;
+        api_bind => "api.bind", Restart;
+    );
+    ReloadReport { changes }
+}
+
+/// Where the configuration comes from
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    path: Option<PathBuf>,
+    overrides: FileConfig,
+    base: DeviceConfig,
+    /// Derived device ID, fixed for the life of the process
+    device_id: OnceLock<String>,
+}
+
+impl ConfigSource {
+    /// `path` layered under `overrides` (environment and command line), over
+    /// `base`
+    pub fn new(path: Option<PathBuf>, overrides: FileConfig, base: DeviceConfig) -> Self {
+        Self {
+            path,
+            overrides,
+            base,
+            device_id: OnceLock::new(),
+        }
+    }
+
+    /// The configuration file, if any
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Read the file and build the configuration, with the merged layer
+    ///
+    /// Unless `device-id` is set, the device ID is derived from the
+    /// interface's MAC address.
+    pub fn load(&self) -> Result<(DeviceConfig, FileConfig), ConfigError> {
+        let mut layer = match &self.path {
+            Some(path) => FileConfig::load(path)?,
+            None => FileConfig::default(),
+        };
+        layer.merge(self.overrides.clone());
+        let mut config = self.base.clone();
+        layer.apply(&mut config)?;
+        if layer.device_id.is_none() {
+            let derived = self.device_id.get_or_init(|| {
+                let store = config.state_dir.clone().map(StateStore::new);
+                format_mac(&device_id(config.interface.as_deref(), store.as_ref()))
+            });
+            config.device_id = derived.clone();
+        }
+        Ok((config, layer))
+    }
+}
+
+/// Re-reads the configuration into the running receiver
+pub struct ConfigReloader {
+    source: ConfigSource,
+    config: SharedConfig,
+    advertiser: Option<Arc<dyn ServiceAdvertiser>>,
+}
+
+impl ConfigReloader {
+    /// Create a reloader for `config`; `advertiser` is re-published on renames
+    pub fn new(
+        source: ConfigSource,
+        config: SharedConfig,
+        advertiser: Option<Arc<dyn ServiceAdvertiser>>,
+    ) -> Self {
+        Self {
+            source,
+            config,
+            advertiser,
+        }
+    }
+
+    /// Reload, apply what can be applied and report every change
+    ///
+    /// An invalid file, or one whose features conflict, leaves the running
+    /// configuration untouched.
+    pub fn reload(&self) -> Result<ReloadReport, ConfigError> {
+        let (new, _) = self.source.load()?;
+        let features = new.advertised_features();
+        features
+            .validate()
+            .map_err(|conflict| ConfigError::Invalid {
+                key: "features".to_string(),
+                message: format!("{}: {}", features.to_hex_string(), conflict),
+            })?;
+        let (report, snapshot) = {
+            let mut config = self.config.write().unwrap_or_else(|e| e.into_inner());
+            let report = reconcile(&mut config, &new);
+            (report, config.clone())
+        };
+
+        for change in &report.changes {
+            match change.effect {
+                ChangeEffect::Restart => warn!("{} changed; restart to apply", change.key),
+                effect => info!("{} changed ({:?})", change.key, effect),
+            }
+        }
+        if report.republish() {
+            if let Some(advertiser) = &self.advertiser {
+                if let Err(e) = advertiser.republish(&snapshot) {
+                    warn!("Failed to re-publish mDNS services: {}", e);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Reload on every `SIGHUP` until `cancel` fires
+    #[cfg(unix)]
+    pub async fn run_on_hangup(&self, cancel: CancellationToken) -> std::io::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => return Ok(()),
+                _ = hangup.recv() => {
+                    info!("SIGHUP: reloading configuration");
+                    if let Err(e) = self.reload() {
+                        warn!("Configuration reload failed: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DeviceInfo;
+    use crate::network::mdns::MdnsError;
+    use std::sync::{Mutex, RwLock};
+
+    #[derive(Default)]
+    struct RecordingAdvertiser {
+        names: Mutex<Vec<String>>,
+    }
+
+    impl ServiceAdvertiser for RecordingAdvertiser {
+        fn publish(&self, config: &DeviceConfig) -> Result<(), MdnsError> {
+            self.names.lock().unwrap().push(config.name.clone());
+            Ok(())
+        }
+
+        fn unpublish(&self) -> Result<(), MdnsError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reload_applies_live_changes() {
+        let path =
+            std::env::temp_dir().join(format!("airplay2-reload-{}.toml", std::process::id()));
+        std::fs::write(&path, "name = \"Kitchen\"\n").unwrap();
+
+        let mut overrides = FileConfig::default();
+        overrides.set("port", "7100").unwrap();
+        let source = ConfigSource::new(Some(path.clone()), overrides, DeviceConfig::default());
+        let (initial, _) = source.load().unwrap();
+        let config: SharedConfig = Arc::new(RwLock::new(initial));
+        let advertiser = Arc::new(RecordingAdvertiser::default());
+        let reloader = ConfigReloader::new(source, config.clone(), Some(advertiser.clone()));
+
+        assert_eq!(reloader.reload().unwrap(), ReloadReport::default());
+        assert!(advertiser.names.lock().unwrap().is_empty());
+
+        std::fs::write(
+            &path,
+            "name = \"Den\"\nport = 7200\n[volume]\nmax = -6.0\n[api]\nport = 8080\n",
+        )
+        .unwrap();
+        let report = reloader.reload().unwrap();
+        let effect = |key| {
+            report
+                .changes
+                .iter()
+                .find(|c| c.key == key)
+                .map(|c| c.effect)
+        };
+        assert_eq!(effect("name"), Some(ChangeEffect::Live));
+        assert_eq!(effect("volume.max"), Some(ChangeEffect::NextSession));
+        assert_eq!(effect("api.port"), Some(ChangeEffect::Restart));
+        // The command-line port still wins over the file
+        assert_eq!(effect("port"), None);
+        assert_eq!(report.restart_required().collect::<Vec<_>>(), ["api.port"]);
+
+        let current = config.read().unwrap().clone();
+        assert_eq!(DeviceInfo::from_config(&current).name, "Den");
+        assert_eq!(current.max_volume, -6.0);
+        assert_eq!(current.api_port, None);
+        assert_eq!(current.port, 7100);
+        assert_eq!(*advertiser.names.lock().unwrap(), ["Den"]);
+
+        std::fs::write(&path, "[volume]\nmax = \"loud\"\n").unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(config.read().unwrap().name, "Den");
+
+        // Buffered audio without PTP would make the receiver lie to senders
+        std::fs::write(&path, "name = \"Hall\"\n[features]\nxor = [41]\n").unwrap();
+        assert!(matches!(
+            reloader.reload(),
+            Err(ConfigError::Invalid { ref key, .. }) if key == "features"
+        ));
+        assert_eq!(config.read().unwrap().name, "Den");
+        assert_eq!(*advertiser.names.lock().unwrap(), ["Den"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
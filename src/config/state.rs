@@ -0,0 +1,77 @@
+//! State kept across restarts
+//!
+//! Small values such as the last volume live in one file each under a state
+//! directory, `$XDG_STATE_HOME/airplay2-receiver` by default. Files are
+//! replaced atomically so a crash never leaves a half-written value behind.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Directory name under the platform state directory
+const APP_DIR: &str = "airplay2-receiver";
+
+/// Files in a state directory
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    /// Use `dir` for state, creating it on first write
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// `$XDG_STATE_HOME/airplay2-receiver`, falling back to `~/.local/state`
+    pub fn default_dir() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_STATE_HOME")
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+            })?;
+        Some(base.join(APP_DIR))
+    }
+
+    /// The state directory
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Read the value `name`, if it exists
+    pub fn read(&self, name: &str) -> io::Result<Option<String>> {
+        match fs::read_to_string(self.dir.join(name)) {
+            Ok(value) => Ok(Some(value.trim().to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replace the value `name`
+    pub fn write(&self, name: &str, value: &str) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let tmp = self.dir.join(format!(".{}.tmp", name));
+        fs::write(&tmp, format!("{}\n", value))?;
+        fs::rename(&tmp, self.dir.join(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("airplay2-state-{}", std::process::id()));
+        let store = StateStore::new(dir.join("nested"));
+        assert_eq!(store.read("volume").unwrap(), None);
+
+        store.write("volume", "-12.5").unwrap();
+        assert_eq!(store.read("volume").unwrap().as_deref(), Some("-12.5"));
+        store.write("volume", "-3").unwrap();
+        assert_eq!(store.read("volume").unwrap().as_deref(), Some("-3"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
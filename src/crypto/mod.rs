@@ -6,6 +6,12 @@
 //! - Long-term public key management
 //! - Session encryption (ChaCha20-Poly1305)
 
+/// Whether HAP pair-setup and pair-verify are implemented in this build
+///
+/// Until then the feature presets leave out Ft46-Ft48, and validation refuses
+/// them when they are set explicitly.
+pub const HAP_PAIRING_AVAILABLE: bool = false;
+
 // TODO: Implement crypto modules
 // pub mod aes;
 // pub mod fairplay;
@@ -38,7 +38,7 @@ pub const AIRPLAY_PROTOCOL_VERSION: &str = "1.1";
 /// AirPlay SDK version string
 pub const AIRPLAY_SDK_VERSION: &str = "AirPlay;2.0.2";
 
-/// Default server version (affects client behavior)
+/// Server version of the default protocol profile (affects client behavior)
 /// - > 360: Triggers remote control
 /// - >= 355: Triggers PTP and buffered audio
 /// - <= 355: Triggers REALTIME and NTP
@@ -1,5 +1,24 @@
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use airplay2_receiver::audio::resample::DriftCorrection;
+use airplay2_receiver::audio::sinks::{create_sink, SampleEncoding, SinkKind};
+use airplay2_receiver::audio::volume::{SoftwareVolume, VolumeControl, VolumeCurve, VolumePolicy};
+use airplay2_receiver::config::{
+    parse_feature_bit, ConfigReloader, ConfigSource, DeviceConfig, FeaturePreset, FileConfig,
+    ProtocolProfile, SharedConfig, StateStore,
+};
+use airplay2_receiver::network::api::{ApiCommand, PlaybackStatus};
+use airplay2_receiver::network::{ControlApi, MdnsAdvertiser, ServiceAdvertiser};
+use airplay2_receiver::streaming::session::SessionManager;
+use airplay2_receiver::streaming::MetadataPublisher;
 use clap::Parser;
-use tracing::{info, Level};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn, Level};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::FmtSubscriber;
 
 #[derive(Parser, Debug)]
@@ -8,22 +27,104 @@ use tracing_subscriber::FmtSubscriber;
 #[command(version = "0.1.0")]
 #[command(about = "A high-performance AirPlay 2 audio receiver", long_about = None)]
 struct Args {
-    /// Device name visible to AirPlay clients
-    #[arg(short, long, default_value = "AirPlay Receiver")]
-    name: String,
+    /// Configuration file (TOML); environment variables and options override it
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Print the effective configuration, with secrets redacted, and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// Device name visible to AirPlay clients (default: "AirPlay Receiver")
+    #[arg(short, long)]
+    name: Option<String>,
 
     /// Network interface to bind to (e.g., "wlan0" or "192.168.1.100")
     #[arg(short, long)]
     interface: Option<String>,
 
     /// Server port (default: 7000 - standard AirPlay port)
-    #[arg(short, long, default_value_t = 7000)]
-    port: u16,
+    #[arg(short, long)]
+    port: Option<u16>,
 
     /// Disable volume control
     #[arg(long)]
     no_volume: bool,
 
+    /// Volume curve: linear, log, log:RANGE_DB or table:IN=OUT,... (default: log)
+    #[arg(long)]
+    volume_curve: Option<VolumeCurve>,
+
+    /// Volume each session starts at, in dB from -30 to 0
+    #[arg(long, allow_hyphen_values = true, value_parser = parse_volume)]
+    initial_volume: Option<f32>,
+
+    /// Highest volume senders may set, in dB from -30 to 0 (default: 0)
+    #[arg(long, allow_hyphen_values = true, value_parser = parse_volume)]
+    max_volume: Option<f32>,
+
+    /// Directory for state kept across restarts (default: $XDG_STATE_HOME/airplay2-receiver)
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+
+    /// ALSA mixer control for hardware volume (e.g. "Digital" or "PCM")
+    #[cfg(feature = "alsa-mixer")]
+    #[arg(long)]
+    mixer: Option<String>,
+
+    /// ALSA mixer device holding --mixer (default: "default")
+    #[cfg(feature = "alsa-mixer")]
+    #[arg(long)]
+    mixer_device: Option<String>,
+
+    /// Audio output: cpal, stdout, pipe:PATH, file:PATH or wav:PATH (default: cpal)
+    #[arg(short, long)]
+    output: Option<SinkKind>,
+
+    /// Sample size for stdout, pipe, file and wav output (default: 16)
+    #[arg(long, value_parser = parse_output_bits)]
+    output_bits: Option<SampleEncoding>,
+
+    /// Audio output device name (see --list-output-devices)
+    #[arg(long)]
+    output_device: Option<String>,
+
+    /// List audio output devices and exit
+    #[arg(long)]
+    list_output_devices: bool,
+
+    /// Clock drift correction: resample, or stuff (cheaper, for slow CPUs)
+    #[arg(long)]
+    drift_correction: Option<DriftCorrection>,
+
+    /// Serve the local HTTP control and status API on this port
+    #[arg(long)]
+    api_port: Option<u16>,
+
+    /// Address for the control API (default: loopback only)
+    #[arg(long)]
+    api_bind: Option<IpAddr>,
+
+    /// Protocol presented to senders: ap2-buffered, ap2-realtime or ap1
+    #[arg(long)]
+    protocol_profile: Option<ProtocolProfile>,
+
+    /// Advertised feature set: default_airplay2, minimal_audio or airplay1
+    #[arg(long)]
+    features: Option<FeaturePreset>,
+
+    /// Keep only these feature bits of the preset (e.g. --ftand 9 14 18 19 20 22)
+    #[arg(long, num_args = 1.., value_parser = parse_feature_bit)]
+    ftand: Vec<u8>,
+
+    /// Set these feature bits (e.g. --ftor 16 17)
+    #[arg(long, num_args = 1.., value_parser = parse_feature_bit)]
+    ftor: Vec<u8>,
+
+    /// Toggle these feature bits, applied after --ftand and --ftor (e.g. --ftxor Ft46)
+    #[arg(long, num_args = 1.., value_parser = parse_feature_bit)]
+    ftxor: Vec<u8>,
+
     /// Enable verbose logging (debug level)
     #[arg(short, long)]
     verbose: bool,
@@ -33,6 +134,67 @@ struct Args {
     trace: bool,
 }
 
+impl Args {
+    /// The options given on the command line, as the top configuration layer
+    fn layer(&self) -> FileConfig {
+        let bits = |list: &Vec<u8>| (!list.is_empty()).then(|| list.clone());
+        let mut layer = FileConfig {
+            name: self.name.clone(),
+            interface: self.interface.clone(),
+            port: self.port,
+            state_dir: self.state_dir.clone(),
+            ..FileConfig::default()
+        };
+        layer.features.preset = self.features.map(|p| p.to_string());
+        layer.features.and = bits(&self.ftand);
+        layer.features.or = bits(&self.ftor);
+        layer.features.xor = bits(&self.ftxor);
+        layer.features.protocol_profile = self.protocol_profile.map(|p| p.to_string());
+        layer.audio.output = self.output.as_ref().map(|o| o.to_string());
+        layer.audio.output_device = self.output_device.clone();
+        layer.audio.output_bits = self.output_bits.map(SampleEncoding::bits);
+        layer.audio.drift_correction = self.drift_correction.map(|d| d.to_string());
+        layer.volume.enabled = self.no_volume.then_some(false);
+        layer.volume.curve = self.volume_curve.as_ref().map(|c| c.to_string());
+        layer.volume.initial = self.initial_volume;
+        layer.volume.max = self.max_volume;
+        #[cfg(feature = "alsa-mixer")]
+        {
+            layer.volume.mixer = self.mixer.clone();
+            layer.volume.mixer_device = self.mixer_device.clone();
+        }
+        layer.api.port = self.api_port;
+        layer.api.bind = self.api_bind;
+        layer
+    }
+}
+
+fn parse_volume(s: &str) -> Result<f32, String> {
+    s.parse()
+        .ok()
+        .filter(|db| (-30.0..=0.0).contains(db))
+        .ok_or_else(|| format!("'{}' is not a volume between -30 and 0 dB", s))
+}
+
+fn parse_output_bits(s: &str) -> Result<SampleEncoding, String> {
+    s.parse()
+        .ok()
+        .and_then(SampleEncoding::from_bits)
+        .ok_or_else(|| format!("'{}' is not 16, 24 or 32", s))
+}
+
+/// The configuration file under the environment and command line, over the
+/// defaults
+fn config_source(args: &Args) -> anyhow::Result<ConfigSource> {
+    let mut overrides = FileConfig::from_env(std::env::vars())?;
+    overrides.merge(args.layer());
+    let base = DeviceConfig {
+        state_dir: StateStore::default_dir(),
+        ..DeviceConfig::default()
+    };
+    Ok(ConfigSource::new(args.config.clone(), overrides, base))
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
@@ -46,7 +208,22 @@ async fn main() -> anyhow::Result<()> {
         Level::INFO
     };
 
+    // Log to stderr while loading the configuration, then to stdout unless
+    // it carries audio
+    let log_to_stderr = Arc::new(AtomicBool::new(true));
+    let log_writer = BoxMakeWriter::new({
+        let log_to_stderr = log_to_stderr.clone();
+        move || -> Box<dyn Write> {
+            if log_to_stderr.load(Ordering::Relaxed) {
+                Box::new(std::io::stderr())
+            } else {
+                Box::new(std::io::stdout())
+            }
+        }
+    });
+
     let subscriber = FmtSubscriber::builder()
+        .with_writer(log_writer)
         .with_max_level(log_level)
         .with_target(true)
         .with_thread_ids(true)
@@ -56,21 +233,160 @@ async fn main() -> anyhow::Result<()> {
     tracing::subscriber::set_global_default(subscriber)
         .expect("Failed to set tracing subscriber");
 
+    let source = config_source(&args)?;
+    let (config, layer) = source.load()?;
+
+    if args.print_config {
+        print!("{}", FileConfig::effective(&config, &layer).to_toml()?);
+        return Ok(());
+    }
+
+    if config.output != SinkKind::Stdout {
+        log_to_stderr.store(false, Ordering::Relaxed);
+    }
+
+    if args.list_output_devices {
+        for name in airplay2_receiver::audio::output::output_device_names()? {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+
     info!("Starting AirPlay 2 Receiver");
-    info!("Device name: {}", args.name);
-    info!("Port: {}", args.port);
+    if let Some(ref path) = args.config {
+        info!("Configuration: {}", path.display());
+    }
+    info!("Device name: {}", config.name);
+    info!("Device ID: {}", config.device_id);
+    info!("Port: {}", config.port);
+
+    let features = config.advertised_features();
+    if let Err(conflict) = features.validate() {
+        anyhow::bail!("Feature flags {}: {}", features.to_hex_string(), conflict);
+    }
+    info!(
+        "Protocol: {} (srcvers {})",
+        config.protocol_profile,
+        config.server_version()
+    );
+    info!("Features: {}", features.to_hex_string());
 
-    if let Some(ref iface) = args.interface {
+    if let Some(ref iface) = config.interface {
         info!("Interface: {}", iface);
     }
 
-    if args.no_volume {
+    if let Some(ref device) = config.output_device {
+        info!("Output device: {}", device);
+    }
+
+    let sink = create_sink(&config.output, config.output_device.clone(), config.output_bits)?;
+    info!("Audio output: {}", sink.name());
+    info!("Drift correction: {}", config.drift_correction);
+
+    if !config.volume_enabled {
         info!("Volume control: disabled");
+    } else {
+        info!("Volume curve: {}", config.volume_curve);
+        if let Some(db) = VolumePolicy::from_config(&config).initial_volume() {
+            info!("Initial volume: {} dB", db);
+        }
+    }
+
+    let mut volume: Option<Box<dyn VolumeControl>> = None;
+    #[cfg(feature = "alsa-mixer")]
+    if let (Some(control), true) = (&config.mixer, config.volume_enabled) {
+        let mixer =
+            airplay2_receiver::audio::mixer::AlsaMixer::open(&config.mixer_device, control)?;
+        let (min, max) = mixer.range();
+        info!(
+            "Hardware volume: {} '{}' ({} to {} dB)",
+            config.mixer_device, control, min, max
+        );
+        volume = Some(Box::new(mixer));
     }
+    #[cfg(not(feature = "alsa-mixer"))]
+    if config.mixer.is_some() {
+        warn!("Built without the alsa-mixer feature; using software volume");
+    }
+    if config.volume_enabled && volume.is_none() {
+        volume = Some(Box::new(SoftwareVolume::new(config.volume_curve.clone(), 44100)));
+    }
+    let volume_policy = VolumePolicy::from_config(&config);
+
+    // Not published until an RTSP listener is bound to config.port; until
+    // then renames and status changes have nothing to update
+    let advertiser = match MdnsAdvertiser::for_config(&config) {
+        Ok(advertiser) => Some(Arc::new(advertiser) as Arc<dyn ServiceAdvertiser>),
+        Err(e) => {
+            warn!("mDNS unavailable, not discoverable: {}", e);
+            None
+        }
+    };
+    let config: SharedConfig = Arc::new(RwLock::new(config));
+    let reloader = Arc::new(ConfigReloader::new(
+        source,
+        config.clone(),
+        advertiser.clone(),
+    ));
+    let cancel = CancellationToken::new();
+    tokio::spawn({
+        let reloader = reloader.clone();
+        let cancel = cancel.clone();
+        async move {
+            if let Err(e) = reloader.run_on_hangup(cancel).await {
+                warn!("Cannot reload on SIGHUP: {}", e);
+            }
+        }
+    });
+
+    let sessions = Arc::new(SessionManager::new(config.clone(), advertiser.clone()));
+    let metadata = MetadataPublisher::new(44100);
+    let (playback, playback_status) = tokio::sync::watch::channel(PlaybackStatus::default());
+
+    let api_addr = {
+        let config = config.read().unwrap_or_else(|e| e.into_inner());
+        config
+            .api_port
+            .map(|port| SocketAddr::new(config.api_bind, port))
+    };
+    let api = api_addr.map(|addr| {
+        let (api, mut commands) = ControlApi::new(
+            config.clone(),
+            sessions.clone(),
+            playback_status.clone(),
+            metadata.subscribe(),
+        );
+        tokio::spawn(async move {
+            while let Some(command) = commands.recv().await {
+                match command {
+                    ApiCommand::SetVolume(db) => {
+                        let Some(control) = volume.as_deref_mut() else {
+                            warn!("Volume control is disabled; ignoring {} dB", db);
+                            continue;
+                        };
+                        match volume_policy.apply(control, db) {
+                            Ok(db) => playback.send_modify(|p| p.volume = Some(db)),
+                            Err(e) => warn!("Failed to set volume: {}", e),
+                        }
+                    }
+                }
+            }
+        });
+        let api = api.with_reloader(reloader.clone());
+        tokio::spawn(api.serve(addr, cancel.clone()))
+    });
 
     // TODO: Initialize the receiver
     info!("Receiver initialization not yet implemented");
-    info!("Run 'cargo build' to verify the project structure is set up correctly");
+    if let Some(api) = api {
+        info!("Serving the control API until interrupted");
+        tokio::signal::ctrl_c().await?;
+        cancel.cancel();
+        api.await??;
+    } else {
+        info!("Run 'cargo build' to verify the project structure is set up correctly");
+        cancel.cancel();
+    }
 
     Ok(())
 }
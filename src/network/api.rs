@@ -0,0 +1,489 @@
+//! Local HTTP/JSON control and status API
+//!
+//! Optional (enabled with `--api-port`) and served on its own port, bound to
+//! loopback unless configured otherwise. It reports the active session,
+//! playback state and track metadata, accepts a few actions and streams
+//! changes as server-sent events:
+//!
+//! | Route                 | Description                                 |
+//! |-----------------------|---------------------------------------------|
+//! | `GET /status`         | Device, session, playback and now playing   |
+//! | `GET /now-playing`    | Track metadata                              |
+//! | `GET /artwork`        | Cover art with its own content type         |
+//! | `GET /events`         | `status` event on every change (SSE)        |
+//! | `POST /volume`        | `{"volume": -12.5}` in AirPlay dB           |
+//! | `POST /pause`         | Ask the sender to pause                     |
+//! | `POST /remote/:cmd`   | Send `play`, `pause`, `next`, ... to sender |
+//! | `POST /disconnect`    | End the current session                     |
+//! | `POST /config/reload` | Re-read the configuration, report changes   |
+//!
+//! Volume is handed to the playback side as an [`ApiCommand`]. Pause and
+//! `POST /remote/:cmd` go to the sender, answering 409 when there is no
+//! sender to control or it has said it does not accept the command.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures_util::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+use crate::audio::volume::MAX_DB;
+use crate::config::{ConfigReloader, SharedConfig};
+use crate::network::remote::RemoteCommand;
+use crate::streaming::metadata::NowPlaying;
+use crate::streaming::session::{EndReason, SessionId, SessionManager};
+
+/// API errors
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Action requested through the API
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiCommand {
+    /// Set the volume, in AirPlay dB
+    SetVolume(f32),
+}
+
+/// Playback facts published by the audio pipeline
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PlaybackStatus {
+    /// Negotiated format, e.g. `ALAC/44100/16/2`
+    pub codec: Option<String>,
+    /// Audio queued ahead of the output, in milliseconds
+    pub buffer_ms: u64,
+    /// Packets queued ahead of the output
+    pub buffered_packets: usize,
+    /// Current volume in AirPlay dB
+    pub volume: Option<f32>,
+    /// Whether audio is playing
+    pub playing: bool,
+}
+
+/// Sender part of the status
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionStatus {
+    /// Receiver-assigned session identifier
+    pub id: SessionId,
+    /// Address of the sender's RTSP connection
+    pub address: SocketAddr,
+    /// HAP controller identifier, known after pair-verify
+    pub controller_id: Option<String>,
+    /// `DACP-ID` header, used to find the sender's remote control service
+    pub dacp_id: Option<String>,
+}
+
+/// Track metadata without the artwork bytes
+#[derive(Debug, Clone, Serialize)]
+pub struct NowPlayingStatus {
+    /// Track title
+    pub title: Option<String>,
+    /// Track artist
+    pub artist: Option<String>,
+    /// Album name
+    pub album: Option<String>,
+    /// Genre
+    pub genre: Option<String>,
+    /// Position within the track when last reported, in seconds
+    pub elapsed_secs: Option<f64>,
+    /// Track length in seconds
+    pub duration_secs: Option<f64>,
+    /// Whether `GET /artwork` has an image
+    pub has_artwork: bool,
+}
+
+impl From<&NowPlaying> for NowPlayingStatus {
+    fn from(np: &NowPlaying) -> Self {
+        Self {
+            title: np.title.clone(),
+            artist: np.artist.clone(),
+            album: np.album.clone(),
+            genre: np.genre.clone(),
+            elapsed_secs: np.progress.map(|p| p.elapsed.as_secs_f64()),
+            duration_secs: np.duration().map(|d| d.as_secs_f64()),
+            has_artwork: np.artwork.is_some(),
+        }
+    }
+}
+
+/// Full status document
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    /// Device name visible to senders
+    pub name: String,
+    /// Advertised device identifier
+    pub device_id: String,
+    /// The connected sender, if any
+    pub session: Option<SessionStatus>,
+    /// Audio pipeline state
+    pub playback: PlaybackStatus,
+    /// Current track
+    pub now_playing: NowPlayingStatus,
+}
+
+#[derive(Debug, Deserialize)]
+struct VolumeRequest {
+    volume: f32,
+}
+
+#[derive(Clone)]
+struct ApiState {
+    config: SharedConfig,
+    sessions: Arc<SessionManager>,
+    playback: watch::Receiver<PlaybackStatus>,
+    now_playing: watch::Receiver<NowPlaying>,
+    commands: mpsc::Sender<ApiCommand>,
+    reloader: Option<Arc<ConfigReloader>>,
+}
+
+impl ApiState {
+    async fn status(&self) -> Status {
+        let session = match self.sessions.active_id().await {
+            Some(id) => self.sessions.active_sender().await.map(|s| SessionStatus {
+                id,
+                address: s.address,
+                controller_id: s.controller_id,
+                dacp_id: s.dacp_id,
+            }),
+            None => None,
+        };
+        let (name, device_id) = {
+            let config = self.config.read().unwrap_or_else(|e| e.into_inner());
+            (config.name.clone(), config.device_id.clone())
+        };
+        Status {
+            name,
+            device_id,
+            session,
+            playback: self.playback.borrow().clone(),
+            now_playing: NowPlayingStatus::from(&*self.now_playing.borrow()),
+        }
+    }
+}
+
+/// The control API
+pub struct ControlApi {
+    state: ApiState,
+}
+
+impl ControlApi {
+    /// Create the API; actions arrive on the returned receiver
+    pub fn new(
+        config: SharedConfig,
+        sessions: Arc<SessionManager>,
+        playback: watch::Receiver<PlaybackStatus>,
+        now_playing: watch::Receiver<NowPlaying>,
+    ) -> (Self, mpsc::Receiver<ApiCommand>) {
+        let (commands, rx) = mpsc::channel(16);
+        let state = ApiState {
+            config,
+            sessions,
+            playback,
+            now_playing,
+            commands,
+            reloader: None,
+        };
+        (Self { state }, rx)
+    }
+
+    /// Serve `POST /config/reload` with `reloader`
+    pub fn with_reloader(mut self, reloader: Arc<ConfigReloader>) -> Self {
+        self.state.reloader = Some(reloader);
+        self
+    }
+
+    /// The API routes
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route("/status", get(get_status))
+            .route("/now-playing", get(get_now_playing))
+            .route("/artwork", get(get_artwork))
+            .route("/events", get(get_events))
+            .route("/volume", post(post_volume))
+            .route("/pause", post(post_pause))
+            .route("/remote/:command", post(post_remote))
+            .route("/disconnect", post(post_disconnect))
+            .route("/config/reload", post(post_reload))
+            .with_state(self.state.clone())
+    }
+
+    /// Serve on `addr` until `cancel` fires
+    pub async fn serve(self, addr: SocketAddr, cancel: CancellationToken) -> Result<(), ApiError> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Control API listening on {}", listener.local_addr()?);
+        axum::serve(listener, self.router())
+            .with_graceful_shutdown(cancel.cancelled_owned())
+            .await?;
+        Ok(())
+    }
+}
+
+async fn get_status(State(state): State<ApiState>) -> Json<Status> {
+    Json(state.status().await)
+}
+
+async fn get_now_playing(State(state): State<ApiState>) -> Json<NowPlayingStatus> {
+    Json(NowPlayingStatus::from(&*state.now_playing.borrow()))
+}
+
+async fn get_artwork(State(state): State<ApiState>) -> Response {
+    let artwork = state.now_playing.borrow().artwork.clone();
+    match artwork {
+        Some(art) => ([(header::CONTENT_TYPE, art.mime_type)], art.data).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn post_volume(
+    State(state): State<ApiState>,
+    Json(request): Json<VolumeRequest>,
+) -> StatusCode {
+    let volume = request.volume;
+    if !volume.is_finite() || volume > MAX_DB {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    send_command(&state, ApiCommand::SetVolume(volume)).await
+}
+
+async fn post_pause(State(state): State<ApiState>) -> StatusCode {
+    send_remote(&state, RemoteCommand::Pause).await
+}
+
+async fn post_remote(State(state): State<ApiState>, Path(command): Path<String>) -> StatusCode {
+    let Ok(command) = command.parse::<RemoteCommand>() else {
+        return StatusCode::NOT_FOUND;
+    };
+    send_remote(&state, command).await
+}
+
+async fn send_remote(state: &ApiState, command: RemoteCommand) -> StatusCode {
+    let Some(remote) = state.sessions.active_remote(command).await else {
+        return StatusCode::CONFLICT;
+    };
+    match remote.send(command).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(e) => {
+            debug!("Remote command {:?} failed: {}", command, e);
+            StatusCode::BAD_GATEWAY
+        }
+    }
+}
+
+async fn post_disconnect(State(state): State<ApiState>) -> StatusCode {
+    match state.sessions.active_id().await {
+        Some(id) if state.sessions.end(id, EndReason::Stopped).await => StatusCode::NO_CONTENT,
+        _ => StatusCode::CONFLICT,
+    }
+}
+
+async fn post_reload(State(state): State<ApiState>) -> Response {
+    let Some(reloader) = &state.reloader else {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    };
+    match reloader.reload() {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response(),
+    }
+}
+
+async fn send_command(state: &ApiState, command: ApiCommand) -> StatusCode {
+    debug!("API command: {:?}", command);
+    match state.commands.send(command).await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+async fn get_events(
+    State(state): State<ApiState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let sessions = state.sessions.subscribe();
+    let events = stream::unfold(
+        (state, sessions, true),
+        |(mut state, mut sessions, first)| async move {
+            if !first {
+                // Any of the three changing yields a fresh status
+                tokio::select! {
+                    r = sessions.changed() => r.ok()?,
+                    r = state.playback.changed() => r.ok()?,
+                    r = state.now_playing.changed() => r.ok()?,
+                }
+            }
+            sessions.borrow_and_update();
+            state.playback.borrow_and_update();
+            state.now_playing.borrow_and_update();
+            let event = Event::default()
+                .event("status")
+                .json_data(state.status().await)
+                .unwrap_or_else(|_| Event::default().event("error"));
+            Some((Ok(event), (state, sessions, false)))
+        },
+    );
+    Sse::new(events).keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DeviceConfig;
+    use crate::streaming::metadata::MetadataPublisher;
+    use crate::streaming::session::SenderInfo;
+    use std::sync::RwLock;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    async fn read_until(stream: &mut TcpStream, seen: &mut String, needle: &str) {
+        let mut buf = [0u8; 4096];
+        while !seen.contains(needle) {
+            let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0, "stream closed before {:?}", needle);
+            seen.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_status_and_actions() {
+        let config: SharedConfig = Arc::new(RwLock::new(DeviceConfig::default()));
+        let sessions = Arc::new(SessionManager::new(config.clone(), None));
+        let (playback_tx, playback) = watch::channel(PlaybackStatus::default());
+        let metadata = MetadataPublisher::new(44100);
+        let (api, mut commands) =
+            ControlApi::new(config, sessions.clone(), playback, metadata.subscribe());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let shutdown = cancel.clone().cancelled_owned();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, api.router())
+                .with_graceful_shutdown(shutdown)
+                .await
+        });
+
+        let idle = request(addr, "GET", "/status", "").await;
+        assert!(idle.starts_with("HTTP/1.1 200"), "{}", idle);
+        assert!(idle.contains("\"session\":null"));
+        assert!(request(addr, "GET", "/artwork", "")
+            .await
+            .starts_with("HTTP/1.1 404"));
+        assert!(request(addr, "POST", "/pause", "")
+            .await
+            .starts_with("HTTP/1.1 409"));
+
+        let sender = SenderInfo::new("192.168.1.20:50000".parse().unwrap());
+        sessions.begin(sender).await.unwrap();
+        playback_tx.send_modify(|p| {
+            p.codec = Some("ALAC/44100/16/2".to_string());
+            p.playing = true;
+        });
+        metadata
+            .apply_set_parameter("image/jpeg", &[0xff, 0xd8, 0xff])
+            .unwrap();
+
+        let busy = request(addr, "GET", "/status", "").await;
+        assert!(
+            busy.contains("\"address\":\"192.168.1.20:50000\""),
+            "{}",
+            busy
+        );
+        assert!(busy.contains("\"codec\":\"ALAC/44100/16/2\""));
+        assert!(busy.contains("\"has_artwork\":true"));
+        let art = request(addr, "GET", "/artwork", "").await;
+        assert!(art.contains("content-type: image/jpeg"), "{}", art);
+
+        let volume = request(addr, "POST", "/volume", "{\"volume\":-12.5}").await;
+        assert!(volume.starts_with("HTTP/1.1 202"), "{}", volume);
+        assert_eq!(commands.recv().await, Some(ApiCommand::SetVolume(-12.5)));
+        let loud = request(addr, "POST", "/volume", "{\"volume\":6}").await;
+        assert!(loud.starts_with("HTTP/1.1 422"));
+        assert!(request(addr, "POST", "/pause", "")
+            .await
+            .starts_with("HTTP/1.1 409"));
+
+        let id = sessions.active_id().await.unwrap();
+        let (events, mut sent) = mpsc::unbounded_channel();
+        sessions
+            .with_session(id, |s| {
+                s.set_events(events);
+                s.set_supported_commands(vec![RemoteCommand::PlayPause, RemoteCommand::Pause]);
+            })
+            .await;
+        let pause = request(addr, "POST", "/pause", "").await;
+        assert!(pause.starts_with("HTTP/1.1 204"), "{}", pause);
+        assert!(sent.try_recv().is_ok());
+        let next = request(addr, "POST", "/remote/next", "").await;
+        assert!(next.starts_with("HTTP/1.1 409"), "{}", next);
+        assert!(sent.try_recv().is_err());
+        let toggle = request(addr, "POST", "/remote/playpause", "").await;
+        assert!(toggle.starts_with("HTTP/1.1 204"), "{}", toggle);
+        assert!(sent.try_recv().is_ok());
+
+        let gone = request(addr, "POST", "/disconnect", "").await;
+        assert!(gone.starts_with("HTTP/1.1 204"), "{}", gone);
+        assert_eq!(sessions.active_id().await, None);
+        assert!(request(addr, "POST", "/config/reload", "")
+            .await
+            .starts_with("HTTP/1.1 503"));
+
+        cancel.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let config: SharedConfig = Arc::new(RwLock::new(DeviceConfig::default()));
+        let sessions = Arc::new(SessionManager::new(config.clone(), None));
+        let (_playback_tx, playback) = watch::channel(PlaybackStatus::default());
+        let metadata = MetadataPublisher::new(44100);
+        let (api, _commands) = ControlApi::new(config, sessions, playback, metadata.subscribe());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, api.router()).await });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut seen = String::new();
+        read_until(&mut stream, &mut seen, "event: status").await;
+        metadata
+            .apply_set_parameter("text/parameters", b"progress: 0/44100/441000\r\n")
+            .unwrap();
+        read_until(&mut stream, &mut seen, "\"duration_secs\":10.0").await;
+    }
+}
@@ -0,0 +1,190 @@
+//! Encrypted socket wrapper
+//!
+//! After pair-verify, connections switch to HAP framing: blocks of at most
+//! 1024 plaintext bytes, each sent as a little-endian `u16` length followed
+//! by the ChaCha20-Poly1305 ciphertext and its 16-byte tag. The length is the
+//! additional authenticated data and the nonce is a 64-bit little-endian
+//! counter per direction. Keys are derived from the pair-verify shared secret
+//! with HKDF-SHA512.
+
+use bytes::{Buf, BytesMut};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use hkdf::Hkdf;
+use sha2::Sha512;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest plaintext carried by one frame
+pub const MAX_BLOCK: usize = 1024;
+
+/// Poly1305 tag length
+const TAG_LEN: usize = 16;
+
+/// Encryption errors
+#[derive(Debug, Error)]
+pub enum EncryptionError {
+    #[error("Frame failed authentication")]
+    Decrypt,
+
+    #[error("Connection closed mid-frame")]
+    Truncated,
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Derive a 32-byte channel key from the pair-verify shared secret
+pub fn derive_key(shared_secret: &[u8], salt: &str, info: &str) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha512>::new(Some(salt.as_bytes()), shared_secret)
+        .expand(info.as_bytes(), &mut key)
+        .expect("32 bytes is a valid HKDF-SHA512 output length");
+    key
+}
+
+/// One direction of an encrypted connection
+pub struct FrameCipher {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl FrameCipher {
+    /// Start a direction with `key` and a zero nonce counter
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce.into()
+    }
+
+    /// Append `plaintext` to `out` as one or more frames
+    pub fn encrypt(&mut self, plaintext: &[u8], out: &mut Vec<u8>) {
+        for block in plaintext.chunks(MAX_BLOCK) {
+            let aad = (block.len() as u16).to_le_bytes();
+            let nonce = self.next_nonce();
+            let start = out.len() + aad.len();
+            out.extend_from_slice(&aad);
+            out.extend_from_slice(block);
+            let tag = self
+                .cipher
+                .encrypt_in_place_detached(&nonce, &aad, &mut out[start..])
+                .expect("ChaCha20-Poly1305 encrypts any block up to 1024 bytes");
+            out.extend_from_slice(&tag);
+        }
+    }
+
+    /// Take one complete frame off the front of `buf`
+    ///
+    /// Returns `None` until a whole frame has been buffered.
+    pub fn decrypt(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<u8>>, EncryptionError> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let aad = [buf[0], buf[1]];
+        let len = u16::from_le_bytes(aad) as usize;
+        if buf.len() < 2 + len + TAG_LEN {
+            return Ok(None);
+        }
+        buf.advance(2);
+        let mut block = buf.split_to(len).to_vec();
+        let tag = Tag::clone_from_slice(&buf.split_to(TAG_LEN));
+        let nonce = self.next_nonce();
+        self.cipher
+            .decrypt_in_place_detached(&nonce, &aad, &mut block, &tag)
+            .map_err(|_| EncryptionError::Decrypt)?;
+        Ok(Some(block))
+    }
+}
+
+/// A stream carrying HAP-framed data
+pub struct EncryptedStream<S> {
+    stream: S,
+    encrypt: FrameCipher,
+    decrypt: FrameCipher,
+    incoming: BytesMut,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> EncryptedStream<S> {
+    /// Wrap `stream`, reading with `read_key` and writing with `write_key`
+    pub fn new(stream: S, read_key: &[u8; 32], write_key: &[u8; 32]) -> Self {
+        Self {
+            stream,
+            encrypt: FrameCipher::new(write_key),
+            decrypt: FrameCipher::new(read_key),
+            incoming: BytesMut::new(),
+        }
+    }
+
+    /// Encrypt and send `data`
+    pub async fn write_all(&mut self, data: &[u8]) -> Result<(), EncryptionError> {
+        let mut out = Vec::with_capacity(data.len() + data.len() / MAX_BLOCK * 18 + 18);
+        self.encrypt.encrypt(data, &mut out);
+        self.stream.write_all(&out).await?;
+        Ok(())
+    }
+
+    /// Receive the plaintext of the next frame, `None` once the peer closes
+    pub async fn read(&mut self) -> Result<Option<Vec<u8>>, EncryptionError> {
+        loop {
+            if let Some(block) = self.decrypt.decrypt(&mut self.incoming)? {
+                return Ok(Some(block));
+            }
+            if self.stream.read_buf(&mut self.incoming).await? == 0 {
+                return if self.incoming.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(EncryptionError::Truncated)
+                };
+            }
+        }
+    }
+
+    /// The wrapped stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let a = derive_key(b"shared secret", "Test-Salt", "A-Key");
+        let b = derive_key(b"shared secret", "Test-Salt", "B-Key");
+        assert_ne!(a, b);
+
+        let (left, right) = tokio::io::duplex(8192);
+        let mut left = EncryptedStream::new(left, &a, &b);
+        let mut right = EncryptedStream::new(right, &b, &a);
+
+        let message: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+        left.write_all(&message).await.unwrap();
+        let mut received = Vec::new();
+        while received.len() < message.len() {
+            let block = right.read().await.unwrap().unwrap();
+            assert!(block.len() <= MAX_BLOCK);
+            received.extend(block);
+        }
+        assert_eq!(received, message);
+
+        // A flipped ciphertext bit fails authentication
+        let mut out = Vec::new();
+        FrameCipher::new(&a).encrypt(b"hello", &mut out);
+        out[3] ^= 1;
+        let mut buf = BytesMut::from(&out[..]);
+        assert!(matches!(
+            FrameCipher::new(&a).decrypt(&mut buf),
+            Err(EncryptionError::Decrypt)
+        ));
+    }
+}
@@ -0,0 +1,239 @@
+//! Event channel to the sender
+//!
+//! SETUP phase 1 answers with an `eventPort`; the sender connects to it and
+//! the receiver uses the connection for reverse-direction RTSP requests,
+//! `POST /command` with a binary plist body such as `updateInfo` or
+//! `updateMRSupportedCommands`. The connection is encrypted with keys
+//! derived from the pair-verify secret under `Events-Salt`.
+//!
+//! Other subsystems queue message bodies on the channel's sender; the
+//! channel task frames them as requests and logs the sender's responses.
+
+use std::net::{IpAddr, SocketAddr};
+
+use plist::{Dictionary, Value};
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use super::encrypted::{derive_key, EncryptedStream, EncryptionError};
+use super::remote::RemoteCommand;
+
+/// HKDF salt for the event channel
+const EVENTS_SALT: &str = "Events-Salt";
+
+/// Key the receiver writes with
+const EVENTS_WRITE_INFO: &str = "Events-Write-Encryption-Key";
+
+/// Key the receiver reads with
+const EVENTS_READ_INFO: &str = "Events-Read-Encryption-Key";
+
+/// Event channel errors
+#[derive(Debug, Error)]
+pub enum EventError {
+    #[error("Encryption error: {0}")]
+    Encryption(#[from] EncryptionError),
+
+    #[error("Failed to encode message: {0}")]
+    Plist(#[from] plist::Error),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// A `POST /command` body of type `kind`
+pub fn command(kind: &str, params: Dictionary) -> Dictionary {
+    let mut body = Dictionary::new();
+    body.insert("type".into(), Value::from(kind));
+    body.insert("params".into(), Value::Dictionary(params));
+    body
+}
+
+/// `updateInfo` carrying changed receiver properties
+pub fn update_info(info: Dictionary) -> Dictionary {
+    command("updateInfo", info)
+}
+
+/// `updateMRSupportedCommands` listing the remote commands the receiver uses
+pub fn supported_commands(commands: &[RemoteCommand]) -> Dictionary {
+    let ids = commands
+        .iter()
+        .map(|c| Value::Integer(c.media_remote_id().into()))
+        .collect();
+    let mut params = Dictionary::new();
+    params.insert("mrSupportedCommandsFromSender".into(), Value::Array(ids));
+    command("updateMRSupportedCommands", params)
+}
+
+/// Listener for one session's event connection
+pub struct EventChannel {
+    listener: TcpListener,
+    read_key: [u8; 32],
+    write_key: [u8; 32],
+    messages: mpsc::UnboundedReceiver<Dictionary>,
+}
+
+impl EventChannel {
+    /// Listen on an ephemeral port of `ip`; messages queued on the returned
+    /// sender are delivered once the sender connects
+    pub async fn bind(
+        ip: IpAddr,
+        shared_secret: &[u8],
+    ) -> Result<(Self, mpsc::UnboundedSender<Dictionary>), EventError> {
+        let listener = TcpListener::bind(SocketAddr::new(ip, 0)).await?;
+        let (tx, messages) = mpsc::unbounded_channel();
+        let channel = Self {
+            listener,
+            read_key: derive_key(shared_secret, EVENTS_SALT, EVENTS_READ_INFO),
+            write_key: derive_key(shared_secret, EVENTS_SALT, EVENTS_WRITE_INFO),
+            messages,
+        };
+        Ok((channel, tx))
+    }
+
+    /// Port for the SETUP `eventPort`
+    pub fn port(&self) -> u16 {
+        self.listener.local_addr().map(|a| a.port()).unwrap_or(0)
+    }
+
+    /// Accept the sender's connection and deliver messages until the
+    /// connection closes, every message sender is dropped or `cancel` fires
+    pub async fn run(mut self, cancel: CancellationToken) -> Result<(), EventError> {
+        let (stream, peer) = tokio::select! {
+            r = self.listener.accept() => r?,
+            _ = cancel.cancelled() => return Ok(()),
+        };
+        info!("Event connection from {}", peer);
+        drop(self.listener);
+        let mut stream = EncryptedStream::new(stream, &self.read_key, &self.write_key);
+
+        let mut cseq = 0u32;
+        let mut responses = Vec::new();
+        loop {
+            tokio::select! {
+                message = self.messages.recv() => {
+                    let Some(message) = message else { break };
+                    cseq += 1;
+                    stream.write_all(&encode_request(&message, cseq)?).await?;
+                }
+                block = stream.read() => {
+                    let Some(block) = block? else {
+                        debug!("Event connection from {} closed", peer);
+                        break;
+                    };
+                    responses.extend(block);
+                    while let Some((status, len)) = take_response(&responses) {
+                        if status != 200 {
+                            warn!("Sender answered event with status {}", status);
+                        }
+                        responses.drain(..len);
+                    }
+                }
+                _ = cancel.cancelled() => {
+                    // Deliver what was queued before the session ended,
+                    // such as the stop sent to a preempted sender
+                    while let Ok(message) = self.messages.try_recv() {
+                        cseq += 1;
+                        stream.write_all(&encode_request(&message, cseq)?).await?;
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn encode_request(body: &Dictionary, cseq: u32) -> Result<Vec<u8>, EventError> {
+    let mut plist = Vec::new();
+    plist::to_writer_binary(&mut plist, &Value::Dictionary(body.clone()))?;
+    let mut request = format!(
+        "POST /command RTSP/1.0\r\nCSeq: {}\r\nContent-Type: application/x-apple-binary-plist\r\nContent-Length: {}\r\n\r\n",
+        cseq,
+        plist.len()
+    )
+    .into_bytes();
+    request.extend(plist);
+    Ok(request)
+}
+
+/// Status and total length of a complete response at the front of `data`
+fn take_response(data: &[u8]) -> Option<(u16, usize)> {
+    let end = data.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+    let head = String::from_utf8_lossy(&data[..end]);
+    let status = head.split_whitespace().nth(1)?.parse().ok()?;
+    let body_len = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    (data.len() >= end + body_len).then_some((status, end + body_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_event_delivery() {
+        let secret = [7u8; 32];
+        let (channel, tx) = EventChannel::bind("127.0.0.1".parse().unwrap(), &secret)
+            .await
+            .unwrap();
+        let port = channel.port();
+        assert_ne!(port, 0);
+        tx.send(supported_commands(&[RemoteCommand::PlayPause]))
+            .unwrap();
+        let task = tokio::spawn(channel.run(CancellationToken::new()));
+
+        // The sender's view: keys swapped
+        let socket = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut sender = EncryptedStream::new(
+            socket,
+            &derive_key(&secret, EVENTS_SALT, EVENTS_WRITE_INFO),
+            &derive_key(&secret, EVENTS_SALT, EVENTS_READ_INFO),
+        );
+        let mut request = Vec::new();
+        let (head_len, body_len) = loop {
+            request.extend(sender.read().await.unwrap().unwrap());
+            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&request[..end]).into_owned();
+                let len = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap();
+                if request.len() >= end + 4 + len {
+                    break (end + 4, len);
+                }
+            }
+        };
+        assert!(request.starts_with(b"POST /command RTSP/1.0\r\nCSeq: 1\r\n"));
+        let body: Dictionary = plist::from_bytes(&request[head_len..head_len + body_len]).unwrap();
+        assert_eq!(
+            body.get("type").and_then(Value::as_string),
+            Some("updateMRSupportedCommands")
+        );
+
+        sender
+            .write_all(b"RTSP/1.0 200 OK\r\nCSeq: 1\r\n\r\n")
+            .await
+            .unwrap();
+        drop(sender);
+        task.await.unwrap().unwrap();
+
+        assert_eq!(
+            take_response(b"RTSP/1.0 404 Not Found\r\nContent-Length: 2\r\n\r\nok"),
+            Some((404, 47))
+        );
+        assert_eq!(
+            take_response(b"RTSP/1.0 200 OK\r\nContent-Length: 2\r\n\r\n"),
+            None
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! Request handlers
+//!
+//! Handlers for the POST endpoints that act on the active session:
+//! - `/feedback`: keepalive of buffered sessions
+//! - `/command`: typed sender commands such as `updateMRSupportedCommands`
+//! - `/audioMode`: switch between `default` and `moviePlayback`
+//!
+//! Each takes the request body and returns the RTSP [`Response`] to send.
+
+use plist::{Dictionary, Value};
+use tracing::debug;
+
+use super::remote::RemoteCommand;
+use crate::streaming::metadata::MetadataPublisher;
+use crate::streaming::session::{AudioMode, SessionError, SessionId, SessionManager};
+
+/// RTSP status for a request naming no active session
+pub const RTSP_SESSION_NOT_FOUND: u16 = 454;
+
+/// Content type of binary plist bodies
+pub const BINARY_PLIST: &str = "application/x-apple-binary-plist";
+
+/// Response to an RTSP request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: Option<&'static str>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Empty response with `status`
+    pub fn status(status: u16) -> Self {
+        Self {
+            status,
+            content_type: None,
+            body: Vec::new(),
+        }
+    }
+
+    /// `200 OK` with an empty body
+    pub fn ok() -> Self {
+        Self::status(200)
+    }
+
+    /// `200 OK` with `dict` as a binary plist
+    pub fn plist(dict: Dictionary) -> Self {
+        let mut body = Vec::new();
+        match plist::to_writer_binary(&mut body, &Value::Dictionary(dict)) {
+            Ok(()) => Self {
+                status: 200,
+                content_type: Some(BINARY_PLIST),
+                body,
+            },
+            Err(_) => Self::status(500),
+        }
+    }
+}
+
+impl From<SessionError> for Response {
+    fn from(err: SessionError) -> Self {
+        Self::status(err.status_code())
+    }
+}
+
+/// Command received on `/command`
+#[derive(Debug, Clone, PartialEq)]
+pub enum SenderCommand {
+    /// Remote commands the sender will act on
+    SupportedCommands(Vec<RemoteCommand>),
+    /// Now playing properties
+    NowPlayingInfo(Dictionary),
+    /// Any other type, acknowledged and ignored
+    Other(String),
+}
+
+impl SenderCommand {
+    /// Parse a `/command` body: `{"type": ..., "params": {...}}`
+    pub fn from_plist(dict: &Dictionary) -> Result<Self, SessionError> {
+        let kind = dict
+            .get("type")
+            .and_then(Value::as_string)
+            .ok_or(SessionError::InvalidField("type"))?;
+        let params = dict.get("params").and_then(Value::as_dictionary);
+        match kind {
+            "updateMRSupportedCommands" => {
+                let commands = params
+                    .and_then(|p| p.get("mrSupportedCommandsFromSender"))
+                    .and_then(Value::as_array)
+                    .ok_or(SessionError::InvalidField("mrSupportedCommandsFromSender"))?;
+                Ok(SenderCommand::SupportedCommands(
+                    commands.iter().filter_map(supported_command).collect(),
+                ))
+            }
+            "updateMRNowPlayingInfo" => Ok(SenderCommand::NowPlayingInfo(
+                params
+                    .cloned()
+                    .ok_or(SessionError::InvalidField("params"))?,
+            )),
+            other => Ok(SenderCommand::Other(other.to_string())),
+        }
+    }
+}
+
+/// One entry of `mrSupportedCommandsFromSender`: either a bare command
+/// number or an embedded plist with `kCommandInfoCommandKey`, skipped when
+/// `kCommandInfoEnabledKey` is false
+fn supported_command(entry: &Value) -> Option<RemoteCommand> {
+    let id = match entry {
+        Value::Integer(id) => id.as_unsigned()?,
+        Value::Data(data) => {
+            let info: Dictionary = plist::from_bytes(data).ok()?;
+            let enabled = info
+                .get("kCommandInfoEnabledKey")
+                .and_then(Value::as_boolean)
+                .unwrap_or(true);
+            if !enabled {
+                return None;
+            }
+            info.get("kCommandInfoCommandKey")?.as_unsigned_integer()?
+        }
+        _ => return None,
+    };
+    RemoteCommand::from_media_remote_id(id)
+}
+
+fn parse_body(body: &[u8]) -> Result<Dictionary, SessionError> {
+    plist::from_bytes(body).map_err(|_| SessionError::InvalidField("body"))
+}
+
+/// `POST /feedback`: refresh the idle timer
+///
+/// With `keepAliveSendStatsAsBody` the sender puts its own stats in the
+/// request body; the receiver has nothing to report back.
+pub async fn feedback(sessions: &SessionManager, id: SessionId) -> Response {
+    match sessions.with_session(id, |session| session.touch()).await {
+        Some(()) => Response::ok(),
+        None => Response::status(RTSP_SESSION_NOT_FOUND),
+    }
+}
+
+/// `POST /command`: dispatch a sender command
+pub async fn command(
+    sessions: &SessionManager,
+    id: SessionId,
+    metadata: &MetadataPublisher,
+    body: &[u8],
+) -> Response {
+    let command = match parse_body(body).and_then(|dict| SenderCommand::from_plist(&dict)) {
+        Ok(command) => command,
+        Err(e) => return e.into(),
+    };
+    let found = match command {
+        SenderCommand::SupportedCommands(commands) => sessions
+            .with_session(id, |s| s.set_supported_commands(commands))
+            .await
+            .is_some(),
+        SenderCommand::NowPlayingInfo(info) => {
+            let found = sessions.touch(id).await;
+            if found {
+                metadata.apply_plist(&info);
+            }
+            found
+        }
+        SenderCommand::Other(kind) => {
+            debug!("Ignoring sender command {}", kind);
+            sessions.touch(id).await
+        }
+    };
+    if found {
+        Response::ok()
+    } else {
+        Response::status(RTSP_SESSION_NOT_FOUND)
+    }
+}
+
+/// `POST /audioMode`: `{"audioMode": "default" | "moviePlayback"}`
+pub async fn audio_mode(sessions: &SessionManager, id: SessionId, body: &[u8]) -> Response {
+    let mode = parse_body(body).and_then(|dict| {
+        dict.get("audioMode")
+            .and_then(Value::as_string)
+            .ok_or(SessionError::InvalidField("audioMode"))?
+            .parse::<AudioMode>()
+    });
+    let mode = match mode {
+        Ok(mode) => mode,
+        Err(e) => return e.into(),
+    };
+    match sessions.with_session(id, |s| s.set_audio_mode(mode)).await {
+        Some(()) => Response::ok(),
+        None => Response::status(RTSP_SESSION_NOT_FOUND),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DeviceConfig, SharedConfig};
+    use crate::streaming::session::{SenderInfo, StreamKind};
+    use std::sync::{Arc, RwLock};
+
+    fn to_bytes(dict: Dictionary) -> Vec<u8> {
+        let mut out = Vec::new();
+        plist::to_writer_binary(&mut out, &Value::Dictionary(dict)).unwrap();
+        out
+    }
+
+    #[tokio::test]
+    async fn test_feedback_and_audio_mode() {
+        let config: SharedConfig = Arc::new(RwLock::new(DeviceConfig::default()));
+        let sessions = SessionManager::new(config, None);
+        let id = sessions
+            .begin(SenderInfo::new("192.168.1.20:50000".parse().unwrap()))
+            .await
+            .unwrap();
+        sessions
+            .with_session(id, |s| s.add_stream(1, StreamKind::Buffered, 6000, 6001))
+            .await;
+
+        assert_eq!(feedback(&sessions, id).await, Response::ok());
+        assert_eq!(
+            feedback(&sessions, id + 1).await.status,
+            RTSP_SESSION_NOT_FOUND
+        );
+
+        let mut body = Dictionary::new();
+        body.insert("audioMode".into(), Value::from("moviePlayback"));
+        assert_eq!(audio_mode(&sessions, id, &to_bytes(body)).await.status, 200);
+        let mode = sessions.with_session(id, |s| s.audio_mode()).await;
+        assert_eq!(mode, Some(AudioMode::MoviePlayback));
+        assert_eq!(
+            mode.unwrap().target_latency(),
+            crate::streaming::session::MOVIE_TARGET_LATENCY
+        );
+
+        let mut body = Dictionary::new();
+        body.insert("audioMode".into(), Value::from("karaoke"));
+        assert_eq!(audio_mode(&sessions, id, &to_bytes(body)).await.status, 400);
+    }
+
+    #[tokio::test]
+    async fn test_command_dispatch() {
+        let config: SharedConfig = Arc::new(RwLock::new(DeviceConfig::default()));
+        let sessions = SessionManager::new(config, None);
+        let id = sessions
+            .begin(SenderInfo::new("192.168.1.20:50000".parse().unwrap()))
+            .await
+            .unwrap();
+        let metadata = MetadataPublisher::new(44100);
+
+        let mut disabled = Dictionary::new();
+        disabled.insert("kCommandInfoCommandKey".into(), Value::Integer(4.into()));
+        disabled.insert("kCommandInfoEnabledKey".into(), Value::Boolean(false));
+        let mut enabled = Dictionary::new();
+        enabled.insert("kCommandInfoCommandKey".into(), Value::Integer(2.into()));
+        let mut params = Dictionary::new();
+        params.insert(
+            "mrSupportedCommandsFromSender".into(),
+            Value::Array(vec![
+                Value::Data(to_bytes(enabled)),
+                Value::Data(to_bytes(disabled)),
+                Value::Integer(5.into()),
+                Value::Integer(99.into()),
+            ]),
+        );
+        let body = crate::network::event::command("updateMRSupportedCommands", params);
+        let response = command(&sessions, id, &metadata, &to_bytes(body)).await;
+        assert_eq!(response.status, 200);
+        let supported = sessions
+            .with_session(id, |s| s.supported_commands().to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            supported,
+            vec![RemoteCommand::PlayPause, RemoteCommand::Previous]
+        );
+
+        let mut info = Dictionary::new();
+        info.insert("title".into(), Value::from("Song"));
+        let body = crate::network::event::command("updateMRNowPlayingInfo", info);
+        command(&sessions, id, &metadata, &to_bytes(body)).await;
+        assert_eq!(metadata.now_playing().title.as_deref(), Some("Song"));
+
+        assert_eq!(command(&sessions, id, &metadata, b"junk").await.status, 400);
+    }
+}
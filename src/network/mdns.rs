@@ -0,0 +1,261 @@
+//! mDNS service announcement
+//!
+//! Publishes the `_airplay._tcp` and `_raop._tcp` services (only the latter
+//! under the AirPlay 1 protocol profile). The TXT records
+//! carry the feature and status flags, so they are re-published whenever the
+//! status changes (e.g. a session becomes active and the receiver turns busy).
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use crate::config::DeviceConfig;
+use crate::utils::network::interface_addresses;
+
+/// AirPlay service type
+pub const AIRPLAY_SERVICE_TYPE: &str = "_airplay._tcp.local.";
+
+/// RAOP (AirTunes) service type
+pub const RAOP_SERVICE_TYPE: &str = "_raop._tcp.local.";
+
+/// Manufacturer advertised over mDNS
+pub const MDNS_MANUFACTURER: &str = "OpenAirplay";
+
+/// Model advertised over mDNS
+pub const MDNS_MODEL: &str = "Airplay2-Receiver";
+
+/// mDNS errors
+#[derive(Debug, Error)]
+pub enum MdnsError {
+    /// Error from the mDNS daemon
+    #[error("mDNS error: {0}")]
+    Daemon(#[from] mdns_sd::Error),
+}
+
+/// Something that can (re-)announce the receiver on the network
+///
+/// Implemented by [`MdnsAdvertiser`]; tests substitute a recorder.
+pub trait ServiceAdvertiser: Send + Sync {
+    /// Publish or update the services for `config`
+    fn publish(&self, config: &DeviceConfig) -> Result<(), MdnsError>;
+
+    /// Withdraw all published services
+    fn unpublish(&self) -> Result<(), MdnsError>;
+
+    /// Update the services for `config` if they are published
+    fn republish(&self, config: &DeviceConfig) -> Result<(), MdnsError> {
+        self.publish(config)
+    }
+}
+
+/// TXT records for the `_airplay._tcp` service
+pub fn airplay_txt_records(config: &DeviceConfig) -> HashMap<String, String> {
+    let flags = config.status.to_hex_string();
+    [
+        ("acl", "0".to_string()),
+        ("deviceid", config.device_id.clone()),
+        ("features", config.advertised_features().to_hex_string()),
+        ("flags", flags),
+        ("gcgl", "0".to_string()),
+        ("gid", config.public_id.to_string()),
+        ("manufacturer", MDNS_MANUFACTURER.to_string()),
+        ("model", MDNS_MODEL.to_string()),
+        ("name", config.name.clone()),
+        ("pi", config.public_id.to_string()),
+        ("protovers", crate::AIRPLAY_PROTOCOL_VERSION.to_string()),
+        ("rsf", "0x0".to_string()),
+        ("serialNumber", config.device_id.clone()),
+        ("srcvers", config.server_version().to_string()),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect()
+}
+
+/// TXT records for the `_raop._tcp` service
+pub fn raop_txt_records(config: &DeviceConfig) -> HashMap<String, String> {
+    [
+        ("am", MDNS_MODEL.to_string()),
+        ("ch", "2".to_string()),
+        ("cn", "0,1,2,3".to_string()),
+        ("da", "true".to_string()),
+        ("et", "0,3,5".to_string()),
+        ("ft", config.advertised_features().to_hex_string()),
+        ("md", "0,1,2".to_string()),
+        ("sf", config.status.to_hex_string()),
+        ("sr", "44100".to_string()),
+        ("ss", "16".to_string()),
+        ("tp", "UDP".to_string()),
+        ("vn", "65537".to_string()),
+        ("vs", config.server_version().to_string()),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect()
+}
+
+/// RAOP instance name: `<MAC without separators>@<device name>`
+pub fn raop_instance_name(config: &DeviceConfig) -> String {
+    format!("{}@{}", config.device_id.replace(':', ""), config.name)
+}
+
+/// mDNS advertiser backed by `mdns-sd`
+pub struct MdnsAdvertiser {
+    daemon: ServiceDaemon,
+    addresses: Vec<IpAddr>,
+    hostname: String,
+    registered: Mutex<Vec<String>>,
+}
+
+impl MdnsAdvertiser {
+    /// Create an advertiser for the given addresses
+    ///
+    /// With no addresses, all interface addresses are announced.
+    pub fn new(addresses: Vec<IpAddr>, hostname: impl Into<String>) -> Result<Self, MdnsError> {
+        let mut hostname = hostname.into();
+        if !hostname.ends_with(".local.") {
+            hostname = format!("{}.local.", hostname.trim_end_matches('.'));
+        }
+        Ok(Self {
+            daemon: ServiceDaemon::new()?,
+            addresses,
+            hostname,
+            registered: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Advertiser on `config`'s interface, or all interfaces, with a host
+    /// name derived from the device ID
+    pub fn for_config(config: &DeviceConfig) -> Result<Self, MdnsError> {
+        let addresses = config
+            .interface
+            .as_deref()
+            .map(interface_addresses)
+            .unwrap_or_default();
+        Self::new(addresses, config.device_id.replace(':', ""))
+    }
+
+    fn service(
+        &self,
+        service_type: &str,
+        instance: &str,
+        port: u16,
+        txt: HashMap<String, String>,
+    ) -> Result<ServiceInfo, MdnsError> {
+        let info = if self.addresses.is_empty() {
+            ServiceInfo::new(service_type, instance, &self.hostname, (), port, txt)?
+                .enable_addr_auto()
+        } else {
+            ServiceInfo::new(
+                service_type,
+                instance,
+                &self.hostname,
+                &self.addresses[..],
+                port,
+                txt,
+            )?
+        };
+        Ok(info)
+    }
+}
+
+impl ServiceAdvertiser for MdnsAdvertiser {
+    fn publish(&self, config: &DeviceConfig) -> Result<(), MdnsError> {
+        let mut services = vec![self.service(
+            RAOP_SERVICE_TYPE,
+            &raop_instance_name(config),
+            config.port,
+            raop_txt_records(config),
+        )?];
+        if config.protocol_profile.advertises_airplay() {
+            services.push(self.service(
+                AIRPLAY_SERVICE_TYPE,
+                &config.name,
+                config.port,
+                airplay_txt_records(config),
+            )?);
+        }
+
+        let mut registered = self.registered.lock().unwrap_or_else(|e| e.into_inner());
+        // A rename changes the instance names; withdraw the old ones first
+        for old in registered.drain(..) {
+            if !services.iter().any(|s| s.get_fullname() == old) {
+                debug!("Unregistering stale mDNS service {}", old);
+                if let Err(e) = self.daemon.unregister(&old) {
+                    warn!("Failed to unregister {}: {}", old, e);
+                }
+            }
+        }
+        for service in services {
+            registered.push(service.get_fullname().to_string());
+            self.daemon.register(service)?;
+        }
+        info!(
+            "Published mDNS services for '{}' (flags {})",
+            config.name,
+            config.status.to_hex_string()
+        );
+        Ok(())
+    }
+
+    fn unpublish(&self) -> Result<(), MdnsError> {
+        let mut registered = self.registered.lock().unwrap_or_else(|e| e.into_inner());
+        for name in registered.drain(..) {
+            self.daemon.unregister(&name)?;
+        }
+        Ok(())
+    }
+
+    fn republish(&self, config: &DeviceConfig) -> Result<(), MdnsError> {
+        let published = !self
+            .registered
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty();
+        if !published {
+            debug!("mDNS services not published yet; nothing to update");
+            return Ok(());
+        }
+        self.publish(config)
+    }
+}
+
+impl Drop for MdnsAdvertiser {
+    fn drop(&mut self) {
+        let _ = self.unpublish();
+        let _ = self.daemon.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StatusFlags;
+
+    #[test]
+    fn test_txt_records_follow_status() {
+        let mut config = DeviceConfig {
+            device_id: "AA:BB:CC:DD:EE:FF".to_string(),
+            ..DeviceConfig::default()
+        };
+        let txt = airplay_txt_records(&config);
+        assert_eq!(txt["features"], "0x405f4200,0x300");
+        assert_eq!(txt["flags"], "0x0");
+        assert_eq!(txt["srcvers"], crate::SERVER_VERSION);
+
+        config.status |= StatusFlags::RECV_SESS_ACTIVE;
+        assert_eq!(airplay_txt_records(&config)["flags"], "0x1");
+        assert_eq!(raop_txt_records(&config)["sf"], "0x1");
+        assert_eq!(raop_instance_name(&config), "AABBCCDDEEFF@AirPlay Receiver");
+
+        config.protocol_profile = crate::config::ProtocolProfile::Ap1Raop;
+        let txt = raop_txt_records(&config);
+        assert_eq!(txt["vs"], "200.0");
+        assert_eq!(txt["ft"], config.advertised_features().to_hex_string());
+        assert!(!config.advertised_features().is_airplay2());
+    }
+}
@@ -4,10 +4,22 @@
 //! - HTTP/RTSP server
 //! - Request routing and handling
 //! - mDNS service announcement
+//! - Local control and status API
+//! - Remote control of the sender (MediaRemote and DACP)
 //! - Encrypted socket wrapper
+//! - Event channel to the sender
 
-// TODO: Implement network modules
-// pub mod encrypted;
-// pub mod handler;
-// pub mod mdns;
+pub mod api;
+pub mod encrypted;
+pub mod event;
+pub mod handler;
+pub mod mdns;
+pub mod remote;
+
+pub use api::ControlApi;
+pub use event::EventChannel;
+pub use mdns::{MdnsAdvertiser, ServiceAdvertiser};
+pub use remote::{RemoteCommand, RemoteControl};
+
+// TODO: Implement remaining network modules
 // pub mod server;
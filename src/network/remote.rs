@@ -0,0 +1,396 @@
+//! Remote control of the sender
+//!
+//! Advertising a source version above 360 lets the receiver control
+//! playback on the sender (play/pause, next, previous). Two paths exist:
+//! - MediaRemote (AirPlay 2): a `POST /command` message pushed to the
+//!   sender over the event connection
+//! - DACP (legacy): the sender's `DACP-ID` header names an
+//!   `iTunes_Ctrl_<DACP-ID>._dacp._tcp` service; an HTTP
+//!   `GET /ctrl-int/1/<command>` carrying the `Active-Remote` header to it
+//!   performs the command
+//!
+//! MediaRemote is preferred when the sender opened an event connection.
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use plist::{Dictionary, Value};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tracing::debug;
+
+use super::event;
+use crate::streaming::session::SenderInfo;
+
+/// DACP remote control service type
+pub const DACP_SERVICE_TYPE: &str = "_dacp._tcp.local.";
+
+/// How long to browse for the sender's DACP service
+const DACP_RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a DACP request may take
+const DACP_REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Remote control errors
+#[derive(Debug, Error)]
+pub enum RemoteError {
+    #[error("Unknown remote command: {0}")]
+    UnknownCommand(String),
+
+    #[error("DACP service {0} not found")]
+    NotFound(String),
+
+    #[error("Sender rejected command with status {0}")]
+    Rejected(u16),
+
+    #[error("Event connection closed")]
+    ChannelClosed,
+
+    #[error("Remote control timed out")]
+    Timeout,
+
+    #[error("mDNS error: {0}")]
+    Mdns(#[from] mdns_sd::Error),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Playback command for the sender
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteCommand {
+    Play,
+    Pause,
+    PlayPause,
+    Stop,
+    Next,
+    Previous,
+}
+
+impl RemoteCommand {
+    /// Path segment of the DACP `ctrl-int` request
+    pub fn dacp_command(self) -> &'static str {
+        match self {
+            RemoteCommand::Play => "play",
+            RemoteCommand::Pause => "pause",
+            RemoteCommand::PlayPause => "playpause",
+            RemoteCommand::Stop => "stop",
+            RemoteCommand::Next => "nextitem",
+            RemoteCommand::Previous => "previtem",
+        }
+    }
+
+    /// Command for a MediaRemote command number
+    pub fn from_media_remote_id(id: u64) -> Option<Self> {
+        match id {
+            0 => Some(RemoteCommand::Play),
+            1 => Some(RemoteCommand::Pause),
+            2 => Some(RemoteCommand::PlayPause),
+            3 => Some(RemoteCommand::Stop),
+            4 => Some(RemoteCommand::Next),
+            5 => Some(RemoteCommand::Previous),
+            _ => None,
+        }
+    }
+
+    /// MediaRemote command number
+    pub fn media_remote_id(self) -> u64 {
+        match self {
+            RemoteCommand::Play => 0,
+            RemoteCommand::Pause => 1,
+            RemoteCommand::PlayPause => 2,
+            RemoteCommand::Stop => 3,
+            RemoteCommand::Next => 4,
+            RemoteCommand::Previous => 5,
+        }
+    }
+}
+
+impl FromStr for RemoteCommand {
+    type Err = RemoteError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "play" => Ok(RemoteCommand::Play),
+            "pause" => Ok(RemoteCommand::Pause),
+            "playpause" => Ok(RemoteCommand::PlayPause),
+            "stop" => Ok(RemoteCommand::Stop),
+            "next" | "nextitem" => Ok(RemoteCommand::Next),
+            "previous" | "prev" | "previtem" => Ok(RemoteCommand::Previous),
+            other => Err(RemoteError::UnknownCommand(other.to_string())),
+        }
+    }
+}
+
+/// MediaRemote commands pushed over the event connection
+#[derive(Debug, Clone)]
+pub struct MediaRemote {
+    events: mpsc::UnboundedSender<Dictionary>,
+}
+
+impl MediaRemote {
+    /// Send commands as `POST /command` bodies on `events`
+    pub fn new(events: mpsc::UnboundedSender<Dictionary>) -> Self {
+        Self { events }
+    }
+
+    /// The `POST /command` body for `command`
+    pub fn command_body(command: RemoteCommand) -> Dictionary {
+        let mut params = Dictionary::new();
+        params.insert(
+            "kMRMediaRemoteCommand".into(),
+            Value::Integer(command.media_remote_id().into()),
+        );
+        event::command("sendMediaRemoteCommand", params)
+    }
+
+    fn send(&self, command: RemoteCommand) -> Result<(), RemoteError> {
+        self.events
+            .send(Self::command_body(command))
+            .map_err(|_| RemoteError::ChannelClosed)
+    }
+}
+
+/// DACP commands sent over HTTP
+pub struct DacpRemote {
+    dacp_id: String,
+    active_remote: String,
+    sender_ip: IpAddr,
+    daemon: Option<ServiceDaemon>,
+    address: Mutex<Option<SocketAddr>>,
+}
+
+impl DacpRemote {
+    /// Control the sender at `sender_ip`, resolving its port over `daemon`
+    pub fn new(
+        sender_ip: IpAddr,
+        dacp_id: &str,
+        active_remote: &str,
+        daemon: Option<ServiceDaemon>,
+    ) -> Self {
+        Self {
+            dacp_id: dacp_id.to_string(),
+            active_remote: active_remote.to_string(),
+            sender_ip,
+            daemon,
+            address: Mutex::new(None),
+        }
+    }
+
+    /// Control a DACP service at a known address
+    pub fn with_address(address: SocketAddr, dacp_id: &str, active_remote: &str) -> Self {
+        Self {
+            address: Mutex::new(Some(address)),
+            ..Self::new(address.ip(), dacp_id, active_remote, None)
+        }
+    }
+
+    /// mDNS instance name of the sender's DACP service
+    pub fn instance_name(&self) -> String {
+        format!("iTunes_Ctrl_{}", self.dacp_id)
+    }
+
+    async fn resolve(&self) -> Result<SocketAddr, RemoteError> {
+        let mut cached = self.address.lock().await;
+        if let Some(address) = *cached {
+            return Ok(address);
+        }
+        let daemon = self
+            .daemon
+            .as_ref()
+            .ok_or_else(|| RemoteError::NotFound(self.instance_name()))?;
+        let prefix = format!("{}.", self.instance_name()).to_ascii_lowercase();
+        let events = daemon.browse(DACP_SERVICE_TYPE)?;
+        let found = tokio::time::timeout(DACP_RESOLVE_TIMEOUT, async {
+            while let Ok(event) = events.recv_async().await {
+                if let ServiceEvent::ServiceResolved(info) = event {
+                    if info
+                        .get_fullname()
+                        .to_ascii_lowercase()
+                        .starts_with(&prefix)
+                    {
+                        // The service runs on the sender; prefer the address
+                        // it is streaming from
+                        let ip = if info.get_addresses().contains(&self.sender_ip) {
+                            self.sender_ip
+                        } else {
+                            info.get_addresses().iter().next().copied()?
+                        };
+                        return Some(SocketAddr::new(ip, info.get_port()));
+                    }
+                }
+            }
+            None
+        })
+        .await;
+        let _ = daemon.stop_browse(DACP_SERVICE_TYPE);
+
+        let address = found
+            .ok()
+            .flatten()
+            .ok_or_else(|| RemoteError::NotFound(self.instance_name()))?;
+        debug!("Resolved {} at {}", self.instance_name(), address);
+        *cached = Some(address);
+        Ok(address)
+    }
+
+    async fn send(&self, command: RemoteCommand) -> Result<(), RemoteError> {
+        let address = self.resolve().await?;
+        let result = tokio::time::timeout(DACP_REQUEST_TIMEOUT, self.request(address, command))
+            .await
+            .unwrap_or(Err(RemoteError::Timeout));
+        if matches!(result, Err(RemoteError::Io(_)) | Err(RemoteError::Timeout)) {
+            // The sender may have restarted its DACP server on a new port
+            self.address.lock().await.take();
+        }
+        result
+    }
+
+    async fn request(
+        &self,
+        address: SocketAddr,
+        command: RemoteCommand,
+    ) -> Result<(), RemoteError> {
+        let mut stream = TcpStream::connect(address).await?;
+        let request = format!(
+            "GET /ctrl-int/1/{} HTTP/1.1\r\nHost: {}\r\nActive-Remote: {}\r\nConnection: close\r\n\r\n",
+            command.dacp_command(),
+            address,
+            self.active_remote
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        let status = String::from_utf8_lossy(&response)
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(0);
+        if (200..300).contains(&status) {
+            Ok(())
+        } else {
+            Err(RemoteError::Rejected(status))
+        }
+    }
+}
+
+/// Remote control of a session's sender over whichever path it supports
+pub enum RemoteControl {
+    MediaRemote(MediaRemote),
+    Dacp(DacpRemote),
+}
+
+impl RemoteControl {
+    /// Pick the path for `sender`: MediaRemote when an event connection is
+    /// available, otherwise DACP when the sender sent its DACP headers
+    pub fn for_sender(
+        sender: &SenderInfo,
+        events: Option<mpsc::UnboundedSender<Dictionary>>,
+        daemon: Option<ServiceDaemon>,
+    ) -> Option<Self> {
+        if let Some(events) = events {
+            return Some(RemoteControl::MediaRemote(MediaRemote::new(events)));
+        }
+        let dacp_id = sender.dacp_id.as_deref()?;
+        let active_remote = sender.active_remote.as_deref()?;
+        Some(RemoteControl::Dacp(DacpRemote::new(
+            sender.address.ip(),
+            dacp_id,
+            active_remote,
+            daemon,
+        )))
+    }
+
+    /// Short name of the path for logging
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RemoteControl::MediaRemote(_) => "MediaRemote",
+            RemoteControl::Dacp(_) => "DACP",
+        }
+    }
+
+    /// Send `command` to the sender
+    pub async fn send(&self, command: RemoteCommand) -> Result<(), RemoteError> {
+        debug!("Remote control ({}): {:?}", self.kind(), command);
+        match self {
+            RemoteControl::MediaRemote(remote) => remote.send(command),
+            RemoteControl::Dacp(remote) => remote.send(command).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_dacp_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for status in ["204 No Content", "403 Forbidden"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; 1024];
+                let n = stream.read(&mut buf).await.unwrap();
+                requests.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+
+        let remote = RemoteControl::Dacp(DacpRemote::with_address(
+            address,
+            "14413BE4996FEA4D",
+            "1986535575",
+        ));
+        remote.send(RemoteCommand::PlayPause).await.unwrap();
+        assert!(matches!(
+            remote.send(RemoteCommand::Next).await,
+            Err(RemoteError::Rejected(403))
+        ));
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("GET /ctrl-int/1/playpause HTTP/1.1\r\n"));
+        assert!(requests[0].contains("Active-Remote: 1986535575\r\n"));
+        assert!(requests[1].starts_with("GET /ctrl-int/1/nextitem "));
+    }
+
+    #[tokio::test]
+    async fn test_path_selection() {
+        let mut sender = SenderInfo::new("192.168.1.20:50000".parse().unwrap());
+        assert!(RemoteControl::for_sender(&sender, None, None).is_none());
+
+        sender.dacp_id = Some("14413BE4996FEA4D".to_string());
+        sender.active_remote = Some("1986535575".to_string());
+        let dacp = RemoteControl::for_sender(&sender, None, None).unwrap();
+        assert_eq!(dacp.kind(), "DACP");
+        let RemoteControl::Dacp(ref remote) = dacp else {
+            unreachable!()
+        };
+        assert_eq!(remote.instance_name(), "iTunes_Ctrl_14413BE4996FEA4D");
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let media_remote = RemoteControl::for_sender(&sender, Some(tx), None).unwrap();
+        assert_eq!(media_remote.kind(), "MediaRemote");
+        media_remote.send(RemoteCommand::Pause).await.unwrap();
+        let body = rx.try_recv().unwrap();
+        assert_eq!(
+            body.get("type").and_then(Value::as_string),
+            Some("sendMediaRemoteCommand")
+        );
+
+        assert_eq!(
+            "prev".parse::<RemoteCommand>().unwrap(),
+            RemoteCommand::Previous
+        );
+        assert!("shuffle".parse::<RemoteCommand>().is_err());
+    }
+}
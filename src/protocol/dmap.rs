@@ -0,0 +1,174 @@
+//! DMAP/DXXP tag parsing
+//!
+//! Track metadata arrives as `application/x-dmap-tagged` SET_PARAMETER
+//! bodies: a sequence of items, each a four-character code, a big-endian
+//! `u32` length and that many bytes of value. Container codes (`mlit` for a
+//! listing item and friends) hold further items.
+
+use thiserror::Error;
+
+/// DMAP errors
+#[derive(Debug, Error)]
+pub enum DmapError {
+    /// An item header or value runs past the end of the data
+    #[error("Truncated DMAP item at offset {0}")]
+    Truncated(usize),
+    /// Containers nest deeper than [`MAX_DEPTH`]
+    #[error("DMAP containers nested too deep at offset {0}")]
+    TooDeep(usize),
+}
+
+/// Deepest container nesting accepted; real bodies use two or three levels
+pub const MAX_DEPTH: usize = 16;
+
+/// Container codes whose value is itself a list of items
+const CONTAINERS: &[&[u8; 4]] = &[b"mlit", b"mlcl", b"mcon", b"cmst", b"caci"];
+
+/// Item value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DmapValue {
+    /// Items of a container code
+    Container(Vec<DmapItem>),
+    /// Raw value; its type depends on the code
+    Bytes(Vec<u8>),
+}
+
+/// A single tagged item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmapItem {
+    /// Four-character code, e.g. `minm`
+    pub code: [u8; 4],
+    /// Value, parsed further for container codes
+    pub value: DmapValue,
+}
+
+impl DmapItem {
+    /// The code as text, e.g. `minm`
+    pub fn code_str(&self) -> &str {
+        std::str::from_utf8(&self.code).unwrap_or("????")
+    }
+
+    /// The value as UTF-8 text
+    pub fn as_str(&self) -> Option<&str> {
+        match &self.value {
+            DmapValue::Bytes(b) => std::str::from_utf8(b).ok(),
+            DmapValue::Container(_) => None,
+        }
+    }
+
+    /// The value as a big-endian unsigned integer of 1 to 8 bytes
+    pub fn as_uint(&self) -> Option<u64> {
+        match &self.value {
+            DmapValue::Bytes(b) if (1..=8).contains(&b.len()) => {
+                Some(b.iter().fold(0u64, |acc, &x| acc << 8 | u64::from(x)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Items of a container
+    pub fn children(&self) -> &[DmapItem] {
+        match &self.value {
+            DmapValue::Container(items) => items,
+            DmapValue::Bytes(_) => &[],
+        }
+    }
+}
+
+/// Parse a DMAP body into its top-level items
+pub fn parse(data: &[u8]) -> Result<Vec<DmapItem>, DmapError> {
+    parse_at(data, 0, 0)
+}
+
+fn parse_at(data: &[u8], base: usize, depth: usize) -> Result<Vec<DmapItem>, DmapError> {
+    if depth > MAX_DEPTH {
+        return Err(DmapError::TooDeep(base));
+    }
+    let mut items = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let header = data
+            .get(pos..pos + 8)
+            .ok_or(DmapError::Truncated(base + pos))?;
+        let code: [u8; 4] = [header[0], header[1], header[2], header[3]];
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let start = pos + 8;
+        let body = start
+            .checked_add(len)
+            .and_then(|end| data.get(start..end))
+            .ok_or(DmapError::Truncated(base + pos))?;
+        let value = if CONTAINERS.contains(&&code) {
+            DmapValue::Container(parse_at(body, base + start, depth + 1)?)
+        } else {
+            DmapValue::Bytes(body.to_vec())
+        };
+        items.push(DmapItem { code, value });
+        pos = start + len;
+    }
+    Ok(items)
+}
+
+/// Depth-first search for the first item with `code`
+pub fn find<'a>(items: &'a [DmapItem], code: &[u8; 4]) -> Option<&'a DmapItem> {
+    items.iter().find_map(|item| {
+        if &item.code == code {
+            Some(item)
+        } else {
+            find(item.children(), code)
+        }
+    })
+}
+
+#[cfg(test)]
+pub(crate) fn encode(code: &[u8; 4], value: &[u8]) -> Vec<u8> {
+    let mut out = code.to_vec();
+    out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    out.extend_from_slice(value);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listing_item() {
+        let mut inner = encode(b"minm", b"Song");
+        inner.extend(encode(b"asar", "Ärtist".as_bytes()));
+        inner.extend(encode(b"astm", &215_000u32.to_be_bytes()));
+        inner.extend(encode(b"astn", &3u16.to_be_bytes()));
+        let body = encode(b"mlit", &inner);
+
+        let items = parse(&body).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].code_str(), "mlit");
+        assert_eq!(items[0].children().len(), 4);
+        assert_eq!(find(&items, b"minm").unwrap().as_str(), Some("Song"));
+        assert_eq!(find(&items, b"asar").unwrap().as_str(), Some("Ärtist"));
+        assert_eq!(find(&items, b"astm").unwrap().as_uint(), Some(215_000));
+        assert_eq!(find(&items, b"astn").unwrap().as_uint(), Some(3));
+        assert!(find(&items, b"asal").is_none());
+
+        assert!(matches!(
+            parse(&body[..body.len() - 1]),
+            Err(DmapError::Truncated(0))
+        ));
+        assert!(matches!(parse(&body[..5]), Err(DmapError::Truncated(0))));
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let nested = |depth: usize| {
+            (0..depth).fold(encode(b"minm", b"Song"), |body, _| encode(b"mlit", &body))
+        };
+
+        let items = parse(&nested(MAX_DEPTH)).unwrap();
+        assert_eq!(find(&items, b"minm").unwrap().as_str(), Some("Song"));
+        assert!(matches!(
+            parse(&nested(MAX_DEPTH + 1)),
+            Err(DmapError::TooDeep(offset)) if offset == 8 * (MAX_DEPTH + 1)
+        ));
+        // Rejected before recursing any further
+        assert!(matches!(parse(&nested(1_000)), Err(DmapError::TooDeep(_))));
+    }
+}
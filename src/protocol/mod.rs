@@ -7,9 +7,10 @@
 //! - DMAP/DXXP tags
 //! - RTSP messages
 
-// TODO: Implement protocol modules
-// pub mod dmap;
+pub mod dmap;
+pub mod sdp;
+
+// TODO: Implement remaining protocol modules
 // pub mod plist;
 // pub mod rtsp;
-// pub mod sdp;
 // pub mod tlv8;
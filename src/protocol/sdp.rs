@@ -0,0 +1,139 @@
+//! SDP (Session Description Protocol) parsing
+//!
+//! Realtime senders describe the audio stream in the body of the RTSP
+//! ANNOUNCE request. Only the parts a receiver needs are kept: the media
+//! line and the `a=` attributes (`rtpmap`, `fmtp`, keys and latencies).
+
+use thiserror::Error;
+
+/// SDP errors
+#[derive(Debug, Error)]
+pub enum SdpError {
+    /// The description has no `m=` line
+    #[error("SDP has no media description")]
+    MissingMedia,
+
+    /// An attribute could not be parsed
+    #[error("Invalid SDP attribute: {0}")]
+    InvalidAttribute(String),
+}
+
+/// Parsed `a=rtpmap` attribute, e.g. `96 AppleLossless` or `96 L16/44100/2`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpMap {
+    pub payload_type: u8,
+    pub encoding: String,
+    pub clock_rate: Option<u32>,
+    pub channels: Option<u16>,
+}
+
+impl RtpMap {
+    /// Parse the value of an `a=rtpmap` attribute
+    pub fn parse(value: &str) -> Result<Self, SdpError> {
+        let invalid = || SdpError::InvalidAttribute(format!("rtpmap:{}", value));
+        let (payload_type, format) = value.trim().split_once(' ').ok_or_else(invalid)?;
+        let mut parts = format.trim().split('/');
+        let encoding = parts.next().filter(|e| !e.is_empty()).ok_or_else(invalid)?;
+        let clock_rate = parts
+            .next()
+            .map(|r| r.parse().map_err(|_| invalid()))
+            .transpose()?;
+        let channels = parts
+            .next()
+            .map(|c| c.parse().map_err(|_| invalid()))
+            .transpose()?;
+
+        Ok(Self {
+            payload_type: payload_type.parse().map_err(|_| invalid())?,
+            encoding: encoding.to_string(),
+            clock_rate,
+            channels,
+        })
+    }
+}
+
+/// A parsed session description
+#[derive(Debug, Clone, Default)]
+pub struct SessionDescription {
+    /// The `m=` line, e.g. `audio 0 RTP/AVP 96`
+    pub media: String,
+    /// `a=` attributes in order of appearance
+    pub attributes: Vec<(String, String)>,
+}
+
+impl SessionDescription {
+    /// Parse an SDP body
+    pub fn parse(sdp: &str) -> Result<Self, SdpError> {
+        let mut media = None;
+        let mut attributes = Vec::new();
+
+        for line in sdp.lines().map(str::trim) {
+            if let Some(m) = line.strip_prefix("m=") {
+                media = Some(m.to_string());
+            } else if let Some(attr) = line.strip_prefix("a=") {
+                let (name, value) = attr.split_once(':').unwrap_or((attr, ""));
+                attributes.push((name.to_string(), value.to_string()));
+            }
+        }
+
+        Ok(Self {
+            media: media.ok_or(SdpError::MissingMedia)?,
+            attributes,
+        })
+    }
+
+    /// Value of the first attribute called `name`
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The `a=rtpmap` attribute
+    pub fn rtpmap(&self) -> Option<Result<RtpMap, SdpError>> {
+        self.attribute("rtpmap").map(RtpMap::parse)
+    }
+
+    /// Format parameters from `a=fmtp`, without the leading payload type
+    pub fn fmtp(&self) -> Option<&str> {
+        self.attribute("fmtp").map(|v| {
+            v.trim()
+                .split_once(' ')
+                .map_or("", |(_, params)| params.trim())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_alac_announce() {
+        let sdp = "v=0\r\n\
+                   o=iTunes 3413821438 0 IN IP4 10.0.0.5\r\n\
+                   s=iTunes\r\n\
+                   c=IN IP4 10.0.0.9\r\n\
+                   t=0 0\r\n\
+                   m=audio 0 RTP/AVP 96\r\n\
+                   a=rtpmap:96 AppleLossless\r\n\
+                   a=fmtp:96 352 0 16 40 10 14 2 255 0 0 44100\r\n\
+                   a=min-latency:11025\r\n";
+        let desc = SessionDescription::parse(sdp).unwrap();
+        assert_eq!(desc.media, "audio 0 RTP/AVP 96");
+        assert_eq!(desc.fmtp(), Some("352 0 16 40 10 14 2 255 0 0 44100"));
+        assert_eq!(desc.attribute("min-latency"), Some("11025"));
+
+        let rtpmap = desc.rtpmap().unwrap().unwrap();
+        assert_eq!(rtpmap.payload_type, 96);
+        assert_eq!(rtpmap.encoding, "AppleLossless");
+        assert_eq!(rtpmap.clock_rate, None);
+
+        let l16 = RtpMap::parse("96 L16/44100/2").unwrap();
+        assert_eq!(l16.clock_rate, Some(44100));
+        assert_eq!(l16.channels, Some(2));
+
+        assert!(SessionDescription::parse("v=0\r\n").is_err());
+    }
+}
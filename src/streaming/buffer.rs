@@ -0,0 +1,462 @@
+//! Audio buffer management
+//!
+//! [`AudioBuffer`] holds received audio packets in sequence order until the
+//! output stage consumes them. The same type backs both stream kinds:
+//!
+//! - Realtime streams use a small jitter buffer with 16-bit RTP sequence numbers
+//! - Buffered streams use a large ring (up to [`crate::AIRPLAY_BUFFER_SIZE`])
+//!   with the 24-bit sequence numbers carried by buffered audio packets
+//!
+//! Both support range flushing. A flush drops every packet whose sequence
+//! number lies in `[from, until)`; without `from` everything before `until`
+//! goes. Senders may issue a flush before the packets it covers have arrived
+//! (e.g. a seek inside a buffered stream), so the request stays pending and
+//! keeps dropping matching packets until one at or past `until` is received.
+
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use plist::Dictionary;
+use thiserror::Error;
+use tracing::{debug, trace};
+
+use super::session::plist_u64;
+
+/// Buffer errors
+#[derive(Debug, Error)]
+pub enum BufferError {
+    /// A required flush field is absent
+    #[error("missing or invalid field `{0}`")]
+    InvalidField(&'static str),
+}
+
+/// A received audio packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioPacket {
+    /// Sequence number (16 or 24 bits depending on the stream)
+    pub seq: u32,
+    /// RTP timestamp of the first frame
+    pub timestamp: u32,
+    /// Encoded (possibly still encrypted) payload
+    pub payload: Bytes,
+}
+
+/// One end of a flush range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPoint {
+    /// Sequence number; realtime FLUSH may only carry an RTP timestamp
+    pub seq: Option<u32>,
+    /// RTP timestamp
+    pub timestamp: u32,
+}
+
+/// A FLUSH or FLUSHBUFFERED request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushRequest {
+    /// First packet to drop; `None` drops everything before `until`
+    pub from: Option<FlushPoint>,
+    /// First packet to keep
+    pub until: FlushPoint,
+}
+
+impl FlushRequest {
+    /// Parse a realtime FLUSH `RTP-Info` header (`seq=1234;rtptime=5678`)
+    ///
+    /// The flush is by `rtptime` alone: `seq` is the sender's last sequence
+    /// number, not the first packet to keep.
+    pub fn from_rtp_info(header: &str) -> Result<Self, BufferError> {
+        let timestamp = header
+            .split(';')
+            .find_map(|part| match part.trim().split_once('=') {
+                Some(("rtptime", v)) => v.trim().parse::<u32>().ok(),
+                _ => None,
+            })
+            .ok_or(BufferError::InvalidField("rtptime"))?;
+        Ok(Self {
+            from: None,
+            until: FlushPoint {
+                seq: None,
+                timestamp,
+            },
+        })
+    }
+
+    /// Parse a FLUSHBUFFERED body (`flushFromSeq`, `flushFromTS`, `flushUntilSeq`, `flushUntilTS`)
+    pub fn from_plist(dict: &Dictionary) -> Result<Self, BufferError> {
+        let until = FlushPoint {
+            seq: Some(
+                plist_u64(dict.get("flushUntilSeq"))
+                    .ok_or(BufferError::InvalidField("flushUntilSeq"))? as u32,
+            ),
+            timestamp: plist_u64(dict.get("flushUntilTS"))
+                .ok_or(BufferError::InvalidField("flushUntilTS"))? as u32,
+        };
+        let from = match plist_u64(dict.get("flushFromSeq")) {
+            Some(seq) => Some(FlushPoint {
+                seq: Some(seq as u32),
+                timestamp: plist_u64(dict.get("flushFromTS"))
+                    .ok_or(BufferError::InvalidField("flushFromTS"))?
+                    as u32,
+            }),
+            None => None,
+        };
+        Ok(Self { from, until })
+    }
+}
+
+/// Outcome of [`AudioBuffer::push`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushResult {
+    /// Packet stored
+    Stored,
+    /// Packet already buffered
+    Duplicate,
+    /// Packet dropped by a pending flush
+    Flushed,
+    /// Packet arrived after its slot was consumed
+    Late,
+}
+
+/// Sequence-ordered audio packet buffer with range flushing
+#[derive(Debug)]
+pub struct AudioBuffer {
+    seq_bits: u32,
+    max_packets: usize,
+    max_bytes: usize,
+    /// Packets keyed by extended (unwrapped) sequence number
+    packets: BTreeMap<u64, AudioPacket>,
+    bytes: usize,
+    /// Extended sequence number of the newest packet seen
+    highest: Option<u64>,
+    /// Extended sequence number of the next packet to be consumed
+    next_read: Option<u64>,
+    pending_flush: Option<FlushRequest>,
+    overflowed: u64,
+}
+
+impl AudioBuffer {
+    /// Jitter buffer for realtime streams (16-bit sequence numbers)
+    pub fn realtime(max_packets: usize) -> Self {
+        Self::new(16, max_packets, usize::MAX)
+    }
+
+    /// Ring buffer for buffered streams (24-bit sequence numbers)
+    pub fn buffered(max_bytes: usize) -> Self {
+        Self::new(24, usize::MAX, max_bytes)
+    }
+
+    fn new(seq_bits: u32, max_packets: usize, max_bytes: usize) -> Self {
+        Self {
+            seq_bits,
+            max_packets,
+            max_bytes,
+            packets: BTreeMap::new(),
+            bytes: 0,
+            highest: None,
+            next_read: None,
+            pending_flush: None,
+            overflowed: 0,
+        }
+    }
+
+    /// Number of buffered packets
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    /// Check whether the buffer is empty
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Total buffered payload size in bytes
+    pub fn buffered_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Packets discarded because the buffer was full
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed
+    }
+
+    /// Flush request still waiting for its `until` packet
+    pub fn pending_flush(&self) -> Option<FlushRequest> {
+        self.pending_flush
+    }
+
+    /// Signed distance from `b` to `a` in this buffer's sequence space
+    fn seq_diff(&self, a: u32, b: u32) -> i64 {
+        let shift = 64 - self.seq_bits;
+        ((((a as u64).wrapping_sub(b as u64)) << shift) as i64) >> shift
+    }
+
+    /// Check whether `packet` lies before `point`
+    fn is_before(&self, packet: &AudioPacket, point: &FlushPoint) -> bool {
+        match point.seq {
+            Some(seq) => self.seq_diff(packet.seq, seq) < 0,
+            None => (packet.timestamp.wrapping_sub(point.timestamp) as i32) < 0,
+        }
+    }
+
+    fn covers(&self, request: &FlushRequest, packet: &AudioPacket) -> bool {
+        if !self.is_before(packet, &request.until) {
+            return false;
+        }
+        match &request.from {
+            Some(from) => !self.is_before(packet, from),
+            None => true,
+        }
+    }
+
+    fn extend_seq(&self, seq: u32) -> u64 {
+        match self.highest {
+            Some(highest) => {
+                let modulus = 1u64 << self.seq_bits;
+                let reference = (highest % modulus) as u32;
+                highest.wrapping_add_signed(self.seq_diff(seq, reference))
+            }
+            // Start well away from zero so that reordered packets can go backwards
+            None => (1u64 << 40) + seq as u64,
+        }
+    }
+
+    /// Insert a received packet
+    pub fn push(&mut self, packet: AudioPacket) -> PushResult {
+        if let Some(flush) = self.pending_flush {
+            if self.covers(&flush, &packet) {
+                trace!("Dropping packet {} (pending flush)", packet.seq);
+                return PushResult::Flushed;
+            }
+            if !self.is_before(&packet, &flush.until) {
+                debug!("Flush complete at packet {}", packet.seq);
+                self.pending_flush = None;
+            }
+        }
+
+        let ext = self.extend_seq(packet.seq);
+        if self.next_read.is_some_and(|next| ext < next) {
+            return PushResult::Late;
+        }
+        if self.packets.contains_key(&ext) {
+            return PushResult::Duplicate;
+        }
+
+        self.highest = Some(self.highest.map_or(ext, |h| h.max(ext)));
+        self.bytes += packet.payload.len();
+        self.packets.insert(ext, packet);
+
+        while self.packets.len() > self.max_packets || self.bytes > self.max_bytes {
+            if let Some((ext, oldest)) = self.packets.pop_first() {
+                self.bytes -= oldest.payload.len();
+                self.next_read = Some(ext + 1);
+                self.overflowed += 1;
+            }
+        }
+
+        PushResult::Stored
+    }
+
+    /// Oldest buffered packet
+    pub fn peek(&self) -> Option<&AudioPacket> {
+        self.packets.values().next()
+    }
+
+    /// Remove and return the oldest buffered packet
+    pub fn pop(&mut self) -> Option<AudioPacket> {
+        let (ext, packet) = self.packets.pop_first()?;
+        self.bytes -= packet.payload.len();
+        self.next_read = Some(ext + 1);
+        Some(packet)
+    }
+
+    /// Apply a FLUSH/FLUSHBUFFERED request
+    ///
+    /// Returns the number of buffered packets dropped.
+    pub fn flush(&mut self, request: FlushRequest) -> usize {
+        let before = self.packets.len();
+        let covered: Vec<u64> = self
+            .packets
+            .iter()
+            .filter(|(_, p)| self.covers(&request, p))
+            .map(|(ext, _)| *ext)
+            .collect();
+        for ext in covered {
+            if let Some(packet) = self.packets.remove(&ext) {
+                self.bytes -= packet.payload.len();
+            }
+        }
+
+        // The flush is complete once a packet at or past `until` has been seen
+        let until_seen = self
+            .packets
+            .values()
+            .any(|p| !self.is_before(p, &request.until));
+        self.pending_flush = (!until_seen).then_some(request);
+
+        let dropped = before - self.packets.len();
+        debug!(
+            "Flushed {} packets (pending: {})",
+            dropped,
+            self.pending_flush.is_some()
+        );
+        dropped
+    }
+
+    /// Drop everything, e.g. on TEARDOWN
+    pub fn clear(&mut self) {
+        self.packets.clear();
+        self.bytes = 0;
+        self.highest = None;
+        self.next_read = None;
+        self.pending_flush = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(seq: u32) -> AudioPacket {
+        AudioPacket {
+            seq,
+            timestamp: seq.wrapping_mul(352),
+            payload: Bytes::from(vec![0u8; 16]),
+        }
+    }
+
+    fn point(seq: u32) -> FlushPoint {
+        FlushPoint {
+            seq: Some(seq),
+            timestamp: seq.wrapping_mul(352),
+        }
+    }
+
+    fn seqs(buffer: &mut AudioBuffer) -> Vec<u32> {
+        std::iter::from_fn(|| buffer.pop()).map(|p| p.seq).collect()
+    }
+
+    #[test]
+    fn test_ordering_and_wrap() {
+        let mut buffer = AudioBuffer::realtime(64);
+        for seq in [65534, 1, 65535, 0] {
+            assert_eq!(buffer.push(packet(seq)), PushResult::Stored);
+        }
+        assert_eq!(buffer.push(packet(1)), PushResult::Duplicate);
+        assert_eq!(seqs(&mut buffer), vec![65534, 65535, 0, 1]);
+        assert_eq!(buffer.push(packet(65535)), PushResult::Late);
+    }
+
+    #[test]
+    fn test_flush_until_only() {
+        let mut buffer = AudioBuffer::buffered(crate::AIRPLAY_BUFFER_SIZE);
+        for seq in 100..110 {
+            buffer.push(packet(seq));
+        }
+        let dropped = buffer.flush(FlushRequest {
+            from: None,
+            until: point(105),
+        });
+        assert_eq!(dropped, 5);
+        assert!(buffer.pending_flush().is_none());
+        assert_eq!(seqs(&mut buffer), (105..110).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_flush_from_and_until() {
+        let mut buffer = AudioBuffer::buffered(crate::AIRPLAY_BUFFER_SIZE);
+        for seq in 0..20 {
+            buffer.push(packet(seq));
+        }
+        buffer.flush(FlushRequest {
+            from: Some(point(5)),
+            until: point(15),
+        });
+        assert_eq!(seqs(&mut buffer), (0..5).chain(15..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_flush_before_data_arrives() {
+        let mut buffer = AudioBuffer::buffered(crate::AIRPLAY_BUFFER_SIZE);
+        for seq in 0..10 {
+            buffer.push(packet(seq));
+        }
+
+        // Skip from 8 to 30 while only 0..10 has arrived
+        buffer.flush(FlushRequest {
+            from: Some(point(8)),
+            until: point(30),
+        });
+        assert!(buffer.pending_flush().is_some());
+
+        for seq in 10..35 {
+            let expected = if seq < 30 {
+                PushResult::Flushed
+            } else {
+                PushResult::Stored
+            };
+            assert_eq!(buffer.push(packet(seq)), expected, "seq {}", seq);
+        }
+        assert!(buffer.pending_flush().is_none());
+        assert_eq!(seqs(&mut buffer), (0..8).chain(30..35).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_flush_entirely_in_future() {
+        let mut buffer = AudioBuffer::buffered(crate::AIRPLAY_BUFFER_SIZE);
+        buffer.push(packet(0));
+        assert_eq!(
+            buffer.flush(FlushRequest {
+                from: Some(point(4)),
+                until: point(6),
+            }),
+            0
+        );
+        for seq in 1..8 {
+            buffer.push(packet(seq));
+        }
+        assert_eq!(seqs(&mut buffer), vec![0, 1, 2, 3, 6, 7]);
+    }
+
+    #[test]
+    fn test_realtime_flush_by_rtptime() {
+        let request = FlushRequest::from_rtp_info("seq=0;rtptime=1760").unwrap();
+        assert_eq!(request.until.timestamp, 1760);
+        assert_eq!(request.until.seq, None);
+
+        let mut buffer = AudioBuffer::realtime(64);
+        for seq in 0..8 {
+            buffer.push(packet(seq));
+        }
+        buffer.flush(request);
+        assert_eq!(seqs(&mut buffer), vec![5, 6, 7]);
+        assert!(FlushRequest::from_rtp_info("seq=4").is_err());
+    }
+
+    #[test]
+    fn test_flushbuffered_plist() {
+        let mut dict = Dictionary::new();
+        dict.insert("flushUntilSeq".into(), 300u64.into());
+        dict.insert("flushUntilTS".into(), 105_600u64.into());
+        let request = FlushRequest::from_plist(&dict).unwrap();
+        assert_eq!(request.from, None);
+        assert_eq!(request.until.seq, Some(300));
+
+        dict.insert("flushFromSeq".into(), 200u64.into());
+        dict.insert("flushFromTS".into(), 70_400u64.into());
+        let request = FlushRequest::from_plist(&dict).unwrap();
+        assert_eq!(request.from.and_then(|f| f.seq), Some(200));
+
+        assert!(FlushRequest::from_plist(&Dictionary::new()).is_err());
+    }
+
+    #[test]
+    fn test_byte_capacity_drops_oldest() {
+        let mut buffer = AudioBuffer::buffered(64);
+        for seq in 0..6 {
+            buffer.push(packet(seq));
+        }
+        assert_eq!(buffer.len(), 4);
+        assert_eq!(buffer.overflowed(), 2);
+        assert_eq!(buffer.peek().map(|p| p.seq), Some(2));
+    }
+}
@@ -0,0 +1,161 @@
+//! Timing clock abstraction
+//!
+//! AirPlay 2 senders express playback deadlines on a network timeline: the PTP
+//! grandmaster for buffered sessions, or the sender's NTP clock for realtime
+//! ones. A [`MasterClock`] maps that timeline onto the local monotonic clock so
+//! that the rest of the receiver only ever deals with [`Instant`]s.
+
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+/// Process-wide origin for converting [`Instant`]s to plain nanosecond counts
+static LOCAL_EPOCH: OnceLock<Instant> = OnceLock::new();
+
+fn local_epoch() -> Instant {
+    *LOCAL_EPOCH.get_or_init(Instant::now)
+}
+
+/// Convert a local instant into signed nanoseconds relative to the process epoch
+pub fn local_nanos(instant: Instant) -> i128 {
+    let epoch = local_epoch();
+    if instant >= epoch {
+        (instant - epoch).as_nanos() as i128
+    } else {
+        -((epoch - instant).as_nanos() as i128)
+    }
+}
+
+/// Convert signed nanoseconds relative to the process epoch back into an instant
+///
+/// Returns `None` if the value cannot be represented on this platform.
+pub fn instant_from_local_nanos(nanos: i128) -> Option<Instant> {
+    let epoch = local_epoch();
+    let magnitude = Duration::from_nanos(u64::try_from(nanos.unsigned_abs()).ok()?);
+    if nanos >= 0 {
+        epoch.checked_add(magnitude)
+    } else {
+        epoch.checked_sub(magnitude)
+    }
+}
+
+/// A clock that reports a remote master's time in local monotonic terms
+pub trait MasterClock: Send + Sync {
+    /// Identity of the timeline being followed
+    ///
+    /// For PTP this is the grandmaster clock identity, which senders echo back
+    /// as `networkTimeTimelineID`. Returns `None` while the clock is unlocked.
+    fn timeline_id(&self) -> Option<u64>;
+
+    /// Master time (nanoseconds on the master timeline) at the given local instant
+    fn master_time_at(&self, local: Instant) -> Option<u64>;
+
+    /// Local instant at which the master timeline reaches `master_nanos`
+    fn local_time_of(&self, master_nanos: u64) -> Option<Instant>;
+
+    /// Current master time
+    fn now(&self) -> Option<u64> {
+        self.master_time_at(Instant::now())
+    }
+
+    /// Check whether the clock is synchronised to a master
+    fn is_locked(&self) -> bool {
+        self.timeline_id().is_some()
+    }
+}
+
+/// Measured relationship between the local clock and a master timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockOffset {
+    /// Timeline the offset refers to
+    pub timeline_id: u64,
+    /// `master - local`, in nanoseconds
+    pub offset_nanos: i128,
+    /// When the offset was last measured
+    pub updated: Instant,
+}
+
+/// A [`MasterClock`] driven by externally measured offsets
+///
+/// Timing protocols (PTP, NTP) feed their filtered measurements into this type
+/// and hand it out behind an `Arc` to whoever needs to schedule playback.
+#[derive(Debug, Default)]
+pub struct OffsetClock {
+    state: RwLock<Option<ClockOffset>>,
+}
+
+impl OffsetClock {
+    /// Create an unlocked clock
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a new offset measurement for `timeline_id`
+    pub fn update(&self, timeline_id: u64, offset_nanos: i128) {
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        *state = Some(ClockOffset {
+            timeline_id,
+            offset_nanos,
+            updated: Instant::now(),
+        });
+    }
+
+    /// Drop synchronisation, e.g. when the master disappears
+    pub fn reset(&self) {
+        *self.state.write().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// Latest offset measurement, if locked
+    pub fn offset(&self) -> Option<ClockOffset> {
+        *self.state.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl MasterClock for OffsetClock {
+    fn timeline_id(&self) -> Option<u64> {
+        self.offset().map(|o| o.timeline_id)
+    }
+
+    fn master_time_at(&self, local: Instant) -> Option<u64> {
+        let offset = self.offset()?;
+        u64::try_from(local_nanos(local) + offset.offset_nanos).ok()
+    }
+
+    fn local_time_of(&self, master_nanos: u64) -> Option<Instant> {
+        let offset = self.offset()?;
+        instant_from_local_nanos(master_nanos as i128 - offset.offset_nanos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_nanos_round_trip() {
+        let now = Instant::now();
+        let later = now + Duration::from_millis(250);
+        assert_eq!(local_nanos(later) - local_nanos(now), 250_000_000);
+        assert_eq!(instant_from_local_nanos(local_nanos(later)), Some(later));
+    }
+
+    #[test]
+    fn test_offset_clock_conversion() {
+        let clock = OffsetClock::new();
+        assert!(!clock.is_locked());
+        assert_eq!(clock.now(), None);
+
+        clock.update(0xABCD, 10_000_000_000);
+        assert_eq!(clock.timeline_id(), Some(0xABCD));
+
+        let local = Instant::now();
+        let master = clock.master_time_at(local).unwrap();
+        assert_eq!(clock.local_time_of(master), Some(local));
+        assert_eq!(
+            clock.local_time_of(master + 1_000_000),
+            Some(local + Duration::from_millis(1))
+        );
+
+        clock.reset();
+        assert!(!clock.is_locked());
+    }
+}
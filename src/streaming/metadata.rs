@@ -0,0 +1,406 @@
+//! Track metadata
+//!
+//! Senders describe what is playing in several ways, all folded into one
+//! [`NowPlaying`] value:
+//! - DMAP text tags (`application/x-dmap-tagged` SET_PARAMETER)
+//! - Artwork (`image/jpeg` or `image/png` SET_PARAMETER)
+//! - `progress: start/current/end` RTP timestamps (`text/parameters`)
+//! - Buffered-audio plists from `/setup` and SETRATEANCHORTIME
+//!
+//! [`MetadataPublisher`] owns the state and consumers (a REST API, a
+//! display) follow it through a `tokio::sync::watch` channel.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use plist::{Dictionary, Value};
+use thiserror::Error;
+use tokio::sync::watch;
+
+use super::session::SetRateAnchorTime;
+use crate::protocol::dmap::{self, DmapError, DmapItem};
+
+/// Metadata errors
+#[derive(Debug, Error)]
+pub enum MetadataError {
+    #[error(transparent)]
+    Dmap(#[from] DmapError),
+
+    #[error("Invalid progress: {0}")]
+    InvalidProgress(String),
+
+    #[error("Unsupported metadata type: {0}")]
+    UnsupportedType(String),
+}
+
+/// Cover art
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artwork {
+    /// `image/jpeg` or `image/png`
+    pub mime_type: String,
+    /// Encoded image
+    pub data: Bytes,
+}
+
+/// Playback position within the current track
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// RTP time of the start of the track
+    pub start_rtp: u32,
+    /// RTP time of the end of the track
+    pub end_rtp: u32,
+    /// Position when last reported
+    pub elapsed: Duration,
+    /// Track length
+    pub duration: Duration,
+}
+
+impl Progress {
+    /// Parse a `progress: start/current/end` body for audio at `sample_rate`
+    pub fn parse(body: &str, sample_rate: u32) -> Result<Self, MetadataError> {
+        let invalid = || MetadataError::InvalidProgress(body.trim().to_string());
+        let value = body
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("progress:"))
+            .ok_or_else(invalid)?;
+        let times = value
+            .trim()
+            .split('/')
+            .map(|t| t.parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [start_rtp, current, end_rtp] = times[..] else {
+            return Err(invalid());
+        };
+        if sample_rate == 0 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            start_rtp,
+            end_rtp,
+            elapsed: rtp_span(start_rtp, current, sample_rate),
+            duration: rtp_span(start_rtp, end_rtp, sample_rate),
+        })
+    }
+}
+
+/// Time between two RTP timestamps, allowing for wraparound
+fn rtp_span(from: u32, to: u32, sample_rate: u32) -> Duration {
+    let frames = u64::from(to.wrapping_sub(from));
+    Duration::from_nanos(frames * 1_000_000_000 / u64::from(sample_rate))
+}
+
+/// What is playing right now
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NowPlaying {
+    /// Track title (`minm`)
+    pub title: Option<String>,
+    /// Track artist (`asar`)
+    pub artist: Option<String>,
+    /// Album name (`asal`)
+    pub album: Option<String>,
+    /// Album artist (`asaa`)
+    pub album_artist: Option<String>,
+    /// Genre (`asgn`)
+    pub genre: Option<String>,
+    /// Composer (`ascp`)
+    pub composer: Option<String>,
+    /// Position on the album (`astn`)
+    pub track_number: Option<u32>,
+    /// Track length from the DMAP `astm` tag
+    pub track_length: Option<Duration>,
+    /// Sender's persistent track identifier
+    pub persistent_id: Option<u64>,
+    /// Cover art for the current track
+    pub artwork: Option<Artwork>,
+    /// Position from the last `progress` update
+    pub progress: Option<Progress>,
+    /// Whether audio is advancing (SETRATEANCHORTIME rate is non-zero)
+    pub playing: bool,
+}
+
+impl NowPlaying {
+    /// Whether anything is known about the track
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.artist.is_none() && self.album.is_none()
+    }
+
+    /// Track length, from progress or the DMAP `astm` tag
+    pub fn duration(&self) -> Option<Duration> {
+        self.progress.map(|p| p.duration).or(self.track_length)
+    }
+
+    fn apply_dmap(&mut self, items: &[DmapItem]) {
+        let text = |code: &[u8; 4]| {
+            dmap::find(items, code)
+                .and_then(DmapItem::as_str)
+                .map(str::to_string)
+        };
+        let persistent_id = dmap::find(items, b"mper").and_then(DmapItem::as_uint);
+        let title = text(b"minm");
+
+        // A different track invalidates artwork and position
+        if (persistent_id.is_some() && persistent_id != self.persistent_id)
+            || (persistent_id.is_none() && title.is_some() && title != self.title)
+        {
+            self.artwork = None;
+            self.progress = None;
+        }
+
+        self.title = title;
+        self.artist = text(b"asar");
+        self.album = text(b"asal");
+        self.album_artist = text(b"asaa");
+        self.genre = text(b"asgn");
+        self.composer = text(b"ascp");
+        self.track_number = dmap::find(items, b"astn")
+            .and_then(DmapItem::as_uint)
+            .map(|n| n as u32);
+        self.track_length = dmap::find(items, b"astm")
+            .and_then(DmapItem::as_uint)
+            .map(Duration::from_millis);
+        self.persistent_id = persistent_id;
+    }
+
+    fn apply_plist(&mut self, dict: &Dictionary) {
+        let text = |key: &str| dict.get(key).and_then(Value::as_string).map(str::to_string);
+        if let Some(title) = text("title") {
+            if Some(&title) != self.title.as_ref() {
+                self.artwork = None;
+                self.progress = None;
+            }
+            self.title = Some(title);
+        }
+        if let Some(artist) = text("artist") {
+            self.artist = Some(artist);
+        }
+        if let Some(album) = text("album") {
+            self.album = Some(album);
+        }
+        if let Some(genre) = text("genre") {
+            self.genre = Some(genre);
+        }
+        if let Some(data) = dict.get("artworkData").and_then(Value::as_data) {
+            let mime_type = text("artworkMIMEType").unwrap_or_else(|| "image/jpeg".to_string());
+            self.artwork = (!data.is_empty()).then(|| Artwork {
+                mime_type,
+                data: Bytes::copy_from_slice(data),
+            });
+        }
+    }
+}
+
+/// Owner of the [`NowPlaying`] state
+#[derive(Debug)]
+pub struct MetadataPublisher {
+    sample_rate: u32,
+    tx: watch::Sender<NowPlaying>,
+}
+
+impl MetadataPublisher {
+    /// Create a publisher for a stream at `sample_rate`
+    pub fn new(sample_rate: u32) -> Self {
+        let (tx, _) = watch::channel(NowPlaying::default());
+        Self { sample_rate, tx }
+    }
+
+    /// Follow changes
+    pub fn subscribe(&self) -> watch::Receiver<NowPlaying> {
+        self.tx.subscribe()
+    }
+
+    /// Current state
+    pub fn now_playing(&self) -> NowPlaying {
+        self.tx.borrow().clone()
+    }
+
+    /// Update the sample rate used for `progress` (on a new stream)
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// Handle a SET_PARAMETER body by content type
+    ///
+    /// `text/parameters` bodies without a `progress:` line (such as volume)
+    /// are left to other handlers and ignored here.
+    pub fn apply_set_parameter(
+        &self,
+        content_type: &str,
+        body: &[u8],
+    ) -> Result<(), MetadataError> {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        match mime {
+            "application/x-dmap-tagged" => self.apply_dmap(body),
+            "image/jpeg" | "image/png" => {
+                self.set_artwork(Some(Artwork {
+                    mime_type: mime.to_string(),
+                    data: Bytes::copy_from_slice(body),
+                }));
+                Ok(())
+            }
+            "image/none" => {
+                self.set_artwork(None);
+                Ok(())
+            }
+            "text/parameters" => {
+                let text = String::from_utf8_lossy(body);
+                if text
+                    .lines()
+                    .any(|l| l.trim_start().starts_with("progress:"))
+                {
+                    self.apply_progress(&text)?;
+                }
+                Ok(())
+            }
+            other => Err(MetadataError::UnsupportedType(other.to_string())),
+        }
+    }
+
+    /// Apply DMAP track tags
+    pub fn apply_dmap(&self, body: &[u8]) -> Result<(), MetadataError> {
+        let items = dmap::parse(body)?;
+        self.tx.send_modify(|np| np.apply_dmap(&items));
+        Ok(())
+    }
+
+    /// Replace or clear artwork (empty images clear it)
+    pub fn set_artwork(&self, artwork: Option<Artwork>) {
+        let artwork = artwork.filter(|a| !a.data.is_empty());
+        self.tx.send_modify(|np| np.artwork = artwork);
+    }
+
+    /// Apply a `progress: start/current/end` body
+    pub fn apply_progress(&self, body: &str) -> Result<(), MetadataError> {
+        let progress = Progress::parse(body, self.sample_rate)?;
+        self.tx.send_modify(|np| np.progress = Some(progress));
+        Ok(())
+    }
+
+    /// Apply metadata keys from a buffered-audio `/setup` plist
+    ///
+    /// Recognised keys are `title`, `artist`, `album`, `genre`,
+    /// `artworkData` and `artworkMIMEType`.
+    pub fn apply_plist(&self, dict: &Dictionary) {
+        self.tx.send_if_modified(|np| {
+            let before = np.clone();
+            np.apply_plist(dict);
+            *np != before
+        });
+    }
+
+    /// Follow a SETRATEANCHORTIME: play state and, with an anchor inside
+    /// the current track, its position
+    pub fn apply_rate(&self, request: &SetRateAnchorTime) {
+        let sample_rate = self.sample_rate.max(1);
+        self.tx.send_if_modified(|np| {
+            let before = np.clone();
+            np.playing = request.rate != 0.0;
+            if let (Some(anchor), Some(progress)) = (request.anchor, np.progress.as_mut()) {
+                let elapsed = rtp_span(progress.start_rtp, anchor.rtp_time, sample_rate);
+                if elapsed <= progress.duration {
+                    progress.elapsed = elapsed;
+                }
+            }
+            *np != before
+        });
+    }
+
+    /// Forget everything, e.g. at TEARDOWN
+    pub fn clear(&self) {
+        self.tx.send_replace(NowPlaying::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::dmap::encode;
+    use crate::streaming::session::Anchor;
+
+    fn track(persistent_id: u64, title: &str) -> Vec<u8> {
+        let mut inner = encode(b"mper", &persistent_id.to_be_bytes());
+        inner.extend(encode(b"minm", title.as_bytes()));
+        inner.extend(encode(b"asar", b"Artist"));
+        inner.extend(encode(b"asal", b"Album"));
+        inner.extend(encode(b"astm", &200_000u32.to_be_bytes()));
+        encode(b"mlit", &inner)
+    }
+
+    #[tokio::test]
+    async fn test_now_playing_updates() {
+        let publisher = MetadataPublisher::new(44100);
+        let mut rx = publisher.subscribe();
+
+        publisher
+            .apply_set_parameter("application/x-dmap-tagged", &track(1, "First"))
+            .unwrap();
+        publisher
+            .apply_set_parameter("image/png", &[0x89, b'P', b'N', b'G'])
+            .unwrap();
+        publisher
+            .apply_set_parameter("text/parameters", b"progress: 1000/45100/442000\r\n")
+            .unwrap();
+        // Volume is not metadata
+        publisher
+            .apply_set_parameter("text/parameters", b"volume: -10.0\r\n")
+            .unwrap();
+
+        rx.changed().await.unwrap();
+        let np = rx.borrow_and_update().clone();
+        assert_eq!(np.title.as_deref(), Some("First"));
+        assert_eq!(np.album.as_deref(), Some("Album"));
+        assert_eq!(np.persistent_id, Some(1));
+        assert_eq!(np.artwork.as_ref().unwrap().mime_type, "image/png");
+        let progress = np.progress.unwrap();
+        assert_eq!(progress.elapsed, Duration::from_secs(1));
+        assert_eq!(progress.duration, Duration::from_secs(10));
+        assert_eq!(np.track_length, Some(Duration::from_secs(200)));
+
+        publisher.apply_rate(&SetRateAnchorTime {
+            rate: 1.0,
+            anchor: Some(Anchor {
+                rtp_time: 1000 + 44100 * 4,
+                network_time_nanos: 0,
+                timeline_id: 0,
+            }),
+        });
+        let np = publisher.now_playing();
+        assert!(np.playing);
+        assert_eq!(np.progress.unwrap().elapsed, Duration::from_secs(4));
+
+        // Next track drops the old artwork and position
+        publisher.apply_dmap(&track(2, "Second")).unwrap();
+        let np = publisher.now_playing();
+        assert_eq!(np.title.as_deref(), Some("Second"));
+        assert!(np.artwork.is_none() && np.progress.is_none());
+        assert_eq!(np.duration(), Some(Duration::from_secs(200)));
+
+        assert!(publisher
+            .apply_set_parameter("application/json", b"{}")
+            .is_err());
+        assert!(publisher
+            .apply_set_parameter("text/parameters", b"progress: 1/2\r\n")
+            .is_err());
+    }
+
+    #[test]
+    fn test_progress_wraps() {
+        let progress = Progress::parse("progress: 4294923196/0/88200\r\n", 44100).unwrap();
+        assert_eq!(progress.elapsed, Duration::from_secs(1));
+        assert_eq!(progress.duration, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_buffered_plist() {
+        let publisher = MetadataPublisher::new(48000);
+        let mut dict = Dictionary::new();
+        dict.insert("title".into(), Value::from("Buffered"));
+        dict.insert("artist".into(), Value::from("Someone"));
+        dict.insert("artworkData".into(), Value::Data(vec![0xff, 0xd8]));
+        publisher.apply_plist(&dict);
+
+        let np = publisher.now_playing();
+        assert_eq!(np.title.as_deref(), Some("Buffered"));
+        assert_eq!(np.artist.as_deref(), Some("Someone"));
+        assert_eq!(np.artwork.unwrap().mime_type, "image/jpeg");
+    }
+}
@@ -5,9 +5,22 @@
 //! - Stream session management
 //! - Audio buffer management
 //! - Timing synchronization (NTP/PTP)
+//! - Track metadata (now playing)
 
-// TODO: Implement streaming modules
-// pub mod buffer;
+pub mod buffer;
+pub mod clock;
+pub mod metadata;
+pub mod ntp;
+pub mod ptp;
+pub mod session;
+
+pub use buffer::{AudioBuffer, FlushRequest};
+pub use clock::{MasterClock, OffsetClock};
+pub use metadata::{MetadataPublisher, NowPlaying};
+pub use ntp::NtpClock;
+pub use ptp::{PtpConfig, PtpFollower};
+pub use session::{PlaybackTimeline, SetRateAnchorTime};
+
+// TODO: Implement remaining streaming modules
 // pub mod rtp;
-// pub mod session;
 // pub mod stream;
@@ -0,0 +1,227 @@
+//! NTP timing for realtime sessions
+//!
+//! Realtime AirPlay sessions (srcvers <= 355) synchronise over the RTP timing
+//! channel using NTP-style request/reply packets. Each completed exchange gives
+//! four timestamps from which the clock offset is derived and fed into an
+//! [`OffsetClock`] by [`NtpClock`].
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use tracing::debug;
+
+use super::clock::OffsetClock;
+
+/// Number of exchanges kept for the minimum-round-trip filter
+const SAMPLE_WINDOW: usize = 8;
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970)
+pub const NTP_UNIX_EPOCH_DELTA: u64 = 2_208_988_800;
+
+/// 64-bit NTP timestamp (32.32 fixed point seconds)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct NtpTimestamp {
+    pub seconds: u32,
+    pub fraction: u32,
+}
+
+impl NtpTimestamp {
+    /// Parse from the 8-byte big-endian wire format
+    pub fn from_bytes(bytes: [u8; 8]) -> Self {
+        Self {
+            seconds: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            fraction: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        }
+    }
+
+    /// Serialize to the 8-byte big-endian wire format
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut out = [0u8; 8];
+        out[..4].copy_from_slice(&self.seconds.to_be_bytes());
+        out[4..].copy_from_slice(&self.fraction.to_be_bytes());
+        out
+    }
+
+    /// Build a timestamp from nanoseconds
+    pub fn from_nanos(nanos: u64) -> Self {
+        let seconds = nanos / 1_000_000_000;
+        let rem = nanos % 1_000_000_000;
+        Self {
+            seconds: seconds as u32,
+            fraction: ((rem << 32) / 1_000_000_000) as u32,
+        }
+    }
+
+    /// Convert to nanoseconds
+    pub fn as_nanos(&self) -> u64 {
+        self.seconds as u64 * 1_000_000_000 + fraction_to_nanos(self.fraction as u64, 32)
+    }
+}
+
+/// Convert a binary fraction of a second with `bits` bits of precision to nanoseconds
+///
+/// AirPlay uses 32-bit fractions in NTP packets and 64-bit fractions in the
+/// `networkTimeFrac` field of SETRATEANCHORTIME.
+pub fn fraction_to_nanos(fraction: u64, bits: u32) -> u64 {
+    ((fraction as u128 * 1_000_000_000) >> bits) as u64
+}
+
+/// One NTP request/reply exchange
+///
+/// `t1`/`t4` are local send/receive times in
+/// [`local_nanos`](super::clock::local_nanos) units and `t2`/`t3` the remote
+/// receive/transmit times, all in nanoseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingExchange {
+    pub t1: i128,
+    pub t2: i128,
+    pub t3: i128,
+    pub t4: i128,
+}
+
+impl TimingExchange {
+    /// Remote minus local clock offset, in nanoseconds
+    pub fn offset_nanos(&self) -> i128 {
+        ((self.t2 - self.t1) + (self.t3 - self.t4)) / 2
+    }
+
+    /// Round-trip delay excluding remote processing time, in nanoseconds
+    pub fn round_trip_nanos(&self) -> i128 {
+        (self.t4 - self.t1) - (self.t3 - self.t2)
+    }
+}
+
+/// Master clock following a sender's NTP timeline
+///
+/// Realtime anchors name the sender's clock by `networkTimeTimelineID`; the
+/// offset of the exchange with the shortest round trip among the last few is
+/// published under that timeline.
+#[derive(Debug)]
+pub struct NtpClock {
+    timeline_id: u64,
+    clock: Arc<OffsetClock>,
+    samples: VecDeque<TimingExchange>,
+}
+
+impl NtpClock {
+    /// Follow the sender timeline `timeline_id`; unlocked until the first
+    /// exchange
+    pub fn new(timeline_id: u64) -> Self {
+        Self {
+            timeline_id,
+            clock: Arc::new(OffsetClock::new()),
+            samples: VecDeque::with_capacity(SAMPLE_WINDOW),
+        }
+    }
+
+    /// The clock to schedule playback with
+    pub fn clock(&self) -> Arc<OffsetClock> {
+        self.clock.clone()
+    }
+
+    /// Take a completed exchange into account
+    pub fn record(&mut self, exchange: TimingExchange) {
+        if exchange.round_trip_nanos() < 0 {
+            debug!("Discarding NTP exchange with negative round trip");
+            return;
+        }
+        if self.samples.len() == SAMPLE_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(exchange);
+        if let Some(best) = self.samples.iter().min_by_key(|e| e.round_trip_nanos()) {
+            self.clock.update(self.timeline_id, best.offset_nanos());
+        }
+    }
+
+    /// Forget all exchanges, e.g. when the sender goes away
+    pub fn reset(&mut self) {
+        self.samples.clear();
+        self.clock.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streaming::clock::{local_nanos, MasterClock};
+    use crate::streaming::session::{Anchor, PlaybackTimeline, SetRateAnchorTime};
+    use std::time::Instant;
+
+    #[test]
+    fn test_ntp_timestamp_conversion() {
+        let ts = NtpTimestamp::from_nanos(3_500_000_000);
+        assert_eq!(ts.seconds, 3);
+        assert_eq!(ts.fraction, 0x8000_0000);
+        assert_eq!(ts.as_nanos(), 3_500_000_000);
+        assert_eq!(NtpTimestamp::from_bytes(ts.to_bytes()), ts);
+
+        assert_eq!(fraction_to_nanos(1 << 63, 64), 500_000_000);
+    }
+
+    #[test]
+    fn test_exchange_offset() {
+        // Remote clock is 1 s ahead, 10 ms each way, 2 ms processing
+        let exchange = TimingExchange {
+            t1: 0,
+            t2: 1_010_000_000,
+            t3: 1_012_000_000,
+            t4: 22_000_000,
+        };
+        assert_eq!(exchange.offset_nanos(), 1_000_000_000);
+        assert_eq!(exchange.round_trip_nanos(), 20_000_000);
+    }
+
+    #[test]
+    fn test_anchors_resolve_on_ntp_clock() {
+        const TIMELINE: u64 = 0x1122_3344_5566_7788;
+        const SENDER_AHEAD: i128 = 5_000_000_000;
+        let now = Instant::now();
+        let local = local_nanos(now);
+
+        let mut ntp = NtpClock::new(TIMELINE);
+        let mut timeline = PlaybackTimeline::new(44100, ntp.clock()).unwrap();
+        let anchor = Anchor {
+            rtp_time: 1000,
+            network_time_nanos: (local + SENDER_AHEAD + 1_000_000_000) as u64,
+            timeline_id: TIMELINE,
+        };
+        timeline.apply(&SetRateAnchorTime {
+            rate: 1.0,
+            anchor: Some(anchor),
+        });
+        assert_eq!(timeline.start_point(1000, now), None);
+
+        // 10 ms each way, 1 ms of sender processing
+        ntp.record(TimingExchange {
+            t1: local,
+            t2: local + SENDER_AHEAD + 10_000_000,
+            t3: local + SENDER_AHEAD + 11_000_000,
+            t4: local + 21_000_000,
+        });
+        // A slower, asymmetric exchange does not replace it
+        ntp.record(TimingExchange {
+            t1: local,
+            t2: local + SENDER_AHEAD + 90_000_000,
+            t3: local + SENDER_AHEAD + 91_000_000,
+            t4: local + 101_000_000,
+        });
+        assert_eq!(ntp.clock().timeline_id(), Some(TIMELINE));
+        let start = timeline.start_point(1000, now).unwrap();
+        assert_eq!(start.silence_frames, 44100);
+        assert_eq!(start.skip_frames, 0);
+
+        // Anchors on another timeline stay unresolved
+        timeline.apply(&SetRateAnchorTime {
+            rate: 1.0,
+            anchor: Some(Anchor {
+                timeline_id: TIMELINE + 1,
+                ..anchor
+            }),
+        });
+        assert_eq!(timeline.start_point(1000, now), None);
+
+        ntp.reset();
+        assert!(!ntp.clock().is_locked());
+    }
+}
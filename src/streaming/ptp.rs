@@ -0,0 +1,1141 @@
+//! PTP (IEEE 1588-2008) follower clock
+//!
+//! Buffered AirPlay 2 sessions (Ft41) timestamp audio against a PTP grandmaster
+//! elected among the devices named in SETPEERS. This module implements a
+//! userspace follower for the subset of PTPv2 that senders actually use:
+//!
+//! - Announce messages feed a best-master selection restricted to the peers
+//! - Sync/Follow_Up (one- or two-step) provide the master's transmit time
+//! - Delay_Req/Delay_Resp measure the path delay
+//!
+//! The protocol state machine ([`PtpFollowerCore`]) is free of I/O so it can be
+//! driven directly in tests; [`PtpFollower`] wires it to the UDP event (319)
+//! and general (320) ports. Results are published through an [`OffsetClock`].
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, trace, warn};
+
+use super::clock::{local_nanos, OffsetClock};
+
+/// Standard PTP event port (Sync, Delay_Req)
+pub const PTP_EVENT_PORT: u16 = 319;
+
+/// Standard PTP general port (Announce, Follow_Up, Delay_Resp)
+pub const PTP_GENERAL_PORT: u16 = 320;
+
+/// Default PTP primary multicast group
+pub const PTP_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 129);
+
+/// Length of the common PTP message header
+const HEADER_LEN: usize = 34;
+
+/// Number of path measurements kept for the minimum-delay filter
+const SAMPLE_WINDOW: usize = 8;
+
+/// PTP errors
+#[derive(Debug, Error)]
+pub enum PtpError {
+    /// Message shorter than its type requires
+    #[error("PTP message truncated ({0} bytes)")]
+    Truncated(usize),
+    /// Not a PTPv2 message
+    #[error("unsupported PTP version {0}")]
+    UnsupportedVersion(u8),
+    /// Message type this follower does not handle
+    #[error("unsupported PTP message type {0:#x}")]
+    UnsupportedType(u8),
+    /// Socket error
+    #[error("PTP socket error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// PTP message types handled by the follower
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Sync = 0x0,
+    DelayReq = 0x1,
+    FollowUp = 0x8,
+    DelayResp = 0x9,
+    Announce = 0xB,
+}
+
+impl MessageType {
+    fn from_nibble(value: u8) -> Result<Self, PtpError> {
+        match value {
+            0x0 => Ok(Self::Sync),
+            0x1 => Ok(Self::DelayReq),
+            0x8 => Ok(Self::FollowUp),
+            0x9 => Ok(Self::DelayResp),
+            0xB => Ok(Self::Announce),
+            other => Err(PtpError::UnsupportedType(other)),
+        }
+    }
+
+    /// Control field value mandated for PTPv1 compatibility
+    fn control_field(self) -> u8 {
+        match self {
+            Self::Sync => 0x00,
+            Self::DelayReq => 0x01,
+            Self::FollowUp => 0x02,
+            Self::DelayResp => 0x03,
+            Self::Announce => 0x05,
+        }
+    }
+}
+
+/// Clock identity plus port number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct PortIdentity {
+    pub clock_identity: u64,
+    pub port_number: u16,
+}
+
+impl PortIdentity {
+    fn read(buf: &[u8]) -> Self {
+        Self {
+            clock_identity: u64::from_be_bytes(buf[0..8].try_into().unwrap()),
+            port_number: u16::from_be_bytes([buf[8], buf[9]]),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.clock_identity.to_be_bytes());
+        out.extend_from_slice(&self.port_number.to_be_bytes());
+    }
+}
+
+/// PTP timestamp (48-bit seconds, 32-bit nanoseconds)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct PtpTimestamp {
+    pub seconds: u64,
+    pub nanoseconds: u32,
+}
+
+impl PtpTimestamp {
+    /// Build a timestamp from nanoseconds since the PTP epoch
+    pub fn from_nanos(nanos: u64) -> Self {
+        Self {
+            seconds: nanos / 1_000_000_000,
+            nanoseconds: (nanos % 1_000_000_000) as u32,
+        }
+    }
+
+    /// Nanoseconds since the PTP epoch; `None` past the `u64` range, which
+    /// 48-bit wire seconds can reach
+    pub fn as_nanos(&self) -> Option<u64> {
+        self.seconds
+            .checked_mul(1_000_000_000)?
+            .checked_add(self.nanoseconds as u64)
+    }
+
+    fn read(buf: &[u8]) -> Self {
+        let mut secs = [0u8; 8];
+        secs[2..].copy_from_slice(&buf[0..6]);
+        Self {
+            seconds: u64::from_be_bytes(secs),
+            nanoseconds: u32::from_be_bytes(buf[6..10].try_into().unwrap()),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.seconds.to_be_bytes()[2..]);
+        out.extend_from_slice(&self.nanoseconds.to_be_bytes());
+    }
+}
+
+/// Common PTP message header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub message_type: MessageType,
+    pub domain: u8,
+    pub flags: u16,
+    /// Correction field in units of 2^-16 ns
+    pub correction: i64,
+    pub source: PortIdentity,
+    pub sequence_id: u16,
+    pub log_message_interval: i8,
+}
+
+impl Header {
+    /// Two-step flag (a Follow_Up carries the precise origin timestamp)
+    pub const FLAG_TWO_STEP: u16 = 0x0200;
+
+    /// Create a header with default flags for the given message
+    pub fn new(message_type: MessageType, source: PortIdentity, sequence_id: u16) -> Self {
+        Self {
+            message_type,
+            domain: 0,
+            flags: 0,
+            correction: 0,
+            source,
+            sequence_id,
+            log_message_interval: 0x7f,
+        }
+    }
+
+    /// Correction field in whole nanoseconds
+    pub fn correction_nanos(&self) -> i128 {
+        (self.correction >> 16) as i128
+    }
+}
+
+/// Announce message body (grandmaster dataset)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Announce {
+    pub origin: PtpTimestamp,
+    pub current_utc_offset: i16,
+    pub priority1: u8,
+    pub clock_class: u8,
+    pub clock_accuracy: u8,
+    pub offset_scaled_log_variance: u16,
+    pub priority2: u8,
+    pub grandmaster_identity: u64,
+    pub steps_removed: u16,
+    pub time_source: u8,
+}
+
+impl Announce {
+    /// Compare two grandmaster datasets (IEEE 1588 dataset comparison)
+    ///
+    /// Returns `Ordering::Less` when `self` is the better master.
+    pub fn compare(&self, other: &Self) -> Ordering {
+        (
+            self.priority1,
+            self.clock_class,
+            self.clock_accuracy,
+            self.offset_scaled_log_variance,
+            self.priority2,
+            self.grandmaster_identity,
+            self.steps_removed,
+        )
+            .cmp(&(
+                other.priority1,
+                other.clock_class,
+                other.clock_accuracy,
+                other.offset_scaled_log_variance,
+                other.priority2,
+                other.grandmaster_identity,
+                other.steps_removed,
+            ))
+    }
+}
+
+/// Message-specific body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Body {
+    Sync {
+        origin: PtpTimestamp,
+    },
+    DelayReq {
+        origin: PtpTimestamp,
+    },
+    FollowUp {
+        precise_origin: PtpTimestamp,
+    },
+    DelayResp {
+        receive: PtpTimestamp,
+        requesting: PortIdentity,
+    },
+    Announce(Announce),
+}
+
+/// A parsed PTPv2 message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtpMessage {
+    pub header: Header,
+    pub body: Body,
+}
+
+impl PtpMessage {
+    /// Parse a PTPv2 message from a UDP payload
+    pub fn parse(buf: &[u8]) -> Result<Self, PtpError> {
+        if buf.len() < HEADER_LEN {
+            return Err(PtpError::Truncated(buf.len()));
+        }
+        let version = buf[1] & 0x0f;
+        if version != 2 {
+            return Err(PtpError::UnsupportedVersion(version));
+        }
+        let message_type = MessageType::from_nibble(buf[0] & 0x0f)?;
+        let header = Header {
+            message_type,
+            domain: buf[4],
+            flags: u16::from_be_bytes([buf[6], buf[7]]),
+            correction: i64::from_be_bytes(buf[8..16].try_into().unwrap()),
+            source: PortIdentity::read(&buf[20..30]),
+            sequence_id: u16::from_be_bytes([buf[30], buf[31]]),
+            log_message_interval: buf[33] as i8,
+        };
+
+        let body_len = match message_type {
+            MessageType::Sync | MessageType::DelayReq | MessageType::FollowUp => 10,
+            MessageType::DelayResp => 20,
+            MessageType::Announce => 30,
+        };
+        if buf.len() < HEADER_LEN + body_len {
+            return Err(PtpError::Truncated(buf.len()));
+        }
+        let b = &buf[HEADER_LEN..];
+
+        let body = match message_type {
+            MessageType::Sync => Body::Sync {
+                origin: PtpTimestamp::read(b),
+            },
+            MessageType::DelayReq => Body::DelayReq {
+                origin: PtpTimestamp::read(b),
+            },
+            MessageType::FollowUp => Body::FollowUp {
+                precise_origin: PtpTimestamp::read(b),
+            },
+            MessageType::DelayResp => Body::DelayResp {
+                receive: PtpTimestamp::read(b),
+                requesting: PortIdentity::read(&b[10..20]),
+            },
+            MessageType::Announce => Body::Announce(Announce {
+                origin: PtpTimestamp::read(b),
+                current_utc_offset: i16::from_be_bytes([b[10], b[11]]),
+                priority1: b[13],
+                clock_class: b[14],
+                clock_accuracy: b[15],
+                offset_scaled_log_variance: u16::from_be_bytes([b[16], b[17]]),
+                priority2: b[18],
+                grandmaster_identity: u64::from_be_bytes(b[19..27].try_into().unwrap()),
+                steps_removed: u16::from_be_bytes([b[27], b[28]]),
+                time_source: b[29],
+            }),
+        };
+
+        Ok(Self { header, body })
+    }
+
+    /// Serialize the message into a UDP payload
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64);
+        out.push(self.header.message_type as u8);
+        out.push(2);
+        out.extend_from_slice(&[0, 0]); // length, patched below
+        out.push(self.header.domain);
+        out.push(0);
+        out.extend_from_slice(&self.header.flags.to_be_bytes());
+        out.extend_from_slice(&self.header.correction.to_be_bytes());
+        out.extend_from_slice(&[0; 4]);
+        self.header.source.write(&mut out);
+        out.extend_from_slice(&self.header.sequence_id.to_be_bytes());
+        out.push(self.header.message_type.control_field());
+        out.push(self.header.log_message_interval as u8);
+
+        match &self.body {
+            Body::Sync { origin } | Body::DelayReq { origin } => origin.write(&mut out),
+            Body::FollowUp { precise_origin } => precise_origin.write(&mut out),
+            Body::DelayResp {
+                receive,
+                requesting,
+            } => {
+                receive.write(&mut out);
+                requesting.write(&mut out);
+            }
+            Body::Announce(a) => {
+                a.origin.write(&mut out);
+                out.extend_from_slice(&a.current_utc_offset.to_be_bytes());
+                out.push(0);
+                out.push(a.priority1);
+                out.push(a.clock_class);
+                out.push(a.clock_accuracy);
+                out.extend_from_slice(&a.offset_scaled_log_variance.to_be_bytes());
+                out.push(a.priority2);
+                out.extend_from_slice(&a.grandmaster_identity.to_be_bytes());
+                out.extend_from_slice(&a.steps_removed.to_be_bytes());
+                out.push(a.time_source);
+            }
+        }
+
+        let len = out.len() as u16;
+        out[2..4].copy_from_slice(&len.to_be_bytes());
+        out
+    }
+
+    /// Check whether this is the first half of a two-step exchange
+    pub fn is_two_step(&self) -> bool {
+        self.header.flags & Header::FLAG_TWO_STEP != 0
+    }
+}
+
+/// A completed path measurement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathSample {
+    /// `local - master`, in nanoseconds
+    pub offset_nanos: i128,
+    /// One-way path delay, in nanoseconds
+    pub delay_nanos: i128,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ForeignMaster {
+    source: PortIdentity,
+    announce: Announce,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SyncHalf {
+    sequence_id: u16,
+    /// Where the Sync came from; the Delay_Req is sent back there
+    source: Option<SocketAddr>,
+    /// Local receive time of the Sync
+    t2: Option<i128>,
+    /// Master transmit time, including the Follow_Up correction
+    t1: Option<i128>,
+    /// Correction carried by the Sync itself
+    sync_correction: i128,
+}
+
+/// What the follower wants sent in response to a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outgoing {
+    pub destination: SocketAddr,
+    pub message: PtpMessage,
+}
+
+/// Sans-I/O PTP follower state machine
+#[derive(Debug)]
+pub struct PtpFollowerCore {
+    identity: PortIdentity,
+    announce_timeout: Duration,
+    peers: HashSet<IpAddr>,
+    masters: HashMap<IpAddr, ForeignMaster>,
+    selected: Option<IpAddr>,
+    sync: Option<SyncHalf>,
+    /// (sequence id, local transmit time) of the outstanding Delay_Req
+    delay_req: Option<(u16, i128)>,
+    /// Completed (t1, t2) for the exchange awaiting a Delay_Resp
+    sync_times: Option<(i128, i128)>,
+    next_delay_seq: u16,
+    samples: VecDeque<PathSample>,
+}
+
+impl PtpFollowerCore {
+    /// Create a follower with the given clock identity
+    pub fn new(clock_identity: u64, announce_timeout: Duration) -> Self {
+        Self {
+            identity: PortIdentity {
+                clock_identity,
+                port_number: 1,
+            },
+            announce_timeout,
+            peers: HashSet::new(),
+            masters: HashMap::new(),
+            selected: None,
+            sync: None,
+            delay_req: None,
+            sync_times: None,
+            next_delay_seq: 0,
+            samples: VecDeque::with_capacity(SAMPLE_WINDOW),
+        }
+    }
+
+    /// Replace the set of addresses eligible to become master (from SETPEERS)
+    pub fn set_peers(&mut self, peers: impl IntoIterator<Item = IpAddr>) {
+        self.peers = peers.into_iter().collect();
+        self.masters.retain(|addr, _| self.peers.contains(addr));
+        self.reselect(Instant::now());
+    }
+
+    /// Address of the currently selected master
+    pub fn selected_master(&self) -> Option<IpAddr> {
+        self.selected
+    }
+
+    /// Grandmaster identity of the selected master (the AirPlay timeline ID)
+    pub fn grandmaster_identity(&self) -> Option<u64> {
+        self.selected
+            .and_then(|addr| self.masters.get(&addr))
+            .map(|m| m.announce.grandmaster_identity)
+    }
+
+    /// Best filtered measurement: the sample with the smallest path delay
+    pub fn best_sample(&self) -> Option<PathSample> {
+        self.samples.iter().min_by_key(|s| s.delay_nanos).copied()
+    }
+
+    /// Drop masters whose Announce messages have stopped
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.announce_timeout;
+        self.masters
+            .retain(|_, m| now.duration_since(m.last_seen) < timeout);
+        self.reselect(now);
+    }
+
+    fn reselect(&mut self, now: Instant) {
+        let best = self
+            .masters
+            .iter()
+            .filter(|(addr, m)| {
+                self.peers.contains(addr) && now.duration_since(m.last_seen) < self.announce_timeout
+            })
+            .min_by(|(_, a), (_, b)| a.announce.compare(&b.announce))
+            .map(|(addr, _)| *addr);
+
+        if best != self.selected {
+            match best {
+                Some(addr) => info!("PTP master selected: {}", addr),
+                None => info!("PTP master lost"),
+            }
+            self.selected = best;
+            self.sync = None;
+            self.delay_req = None;
+            self.sync_times = None;
+            self.samples.clear();
+        }
+    }
+
+    /// Process a received message
+    ///
+    /// `rx_local` is the local receive time in [`local_nanos`] units. Returns
+    /// a message to send (a Delay_Req) and, if an exchange completed, the new
+    /// path sample.
+    pub fn handle(
+        &mut self,
+        message: &PtpMessage,
+        from: SocketAddr,
+        rx_local: i128,
+        now: Instant,
+    ) -> (Option<Outgoing>, Option<PathSample>) {
+        match message.body {
+            Body::Announce(announce) => {
+                if !self.peers.contains(&from.ip()) {
+                    trace!("Ignoring PTP Announce from non-peer {}", from);
+                    return (None, None);
+                }
+                self.masters.insert(
+                    from.ip(),
+                    ForeignMaster {
+                        source: message.header.source,
+                        announce,
+                        last_seen: now,
+                    },
+                );
+                self.reselect(now);
+                (None, None)
+            }
+            Body::Sync { origin } => {
+                if !self.is_from_master(message, from) {
+                    return (None, None);
+                }
+                let seq = message.header.sequence_id;
+                let correction = message.header.correction_nanos();
+                let t1 = if message.is_two_step() {
+                    // The Follow_Up may have overtaken its Sync
+                    match self.sync {
+                        Some(half) if half.sequence_id == seq && half.t2.is_none() => half.t1,
+                        _ => None,
+                    }
+                } else {
+                    let Some(origin) = origin.as_nanos() else {
+                        debug!("Discarding PTP Sync with out-of-range origin");
+                        return (None, None);
+                    };
+                    Some(origin as i128)
+                };
+                self.sync = Some(SyncHalf {
+                    sequence_id: seq,
+                    source: Some(from),
+                    t2: Some(rx_local),
+                    t1,
+                    sync_correction: correction,
+                });
+                (self.try_complete_sync(), None)
+            }
+            Body::FollowUp { precise_origin } => {
+                if !self.is_from_master(message, from) {
+                    return (None, None);
+                }
+                let seq = message.header.sequence_id;
+                let Some(origin) = precise_origin.as_nanos() else {
+                    debug!("Discarding PTP Follow_Up with out-of-range origin");
+                    return (None, None);
+                };
+                let t1 = origin as i128 + message.header.correction_nanos();
+                match self.sync.as_mut() {
+                    Some(half) if half.sequence_id == seq => half.t1 = Some(t1),
+                    _ => {
+                        self.sync = Some(SyncHalf {
+                            sequence_id: seq,
+                            source: None,
+                            t2: None,
+                            t1: Some(t1),
+                            sync_correction: 0,
+                        });
+                    }
+                }
+                (self.try_complete_sync(), None)
+            }
+            Body::DelayResp {
+                receive,
+                requesting,
+            } => {
+                if !self.is_from_master(message, from) || requesting != self.identity {
+                    return (None, None);
+                }
+                let Some((seq, t3)) = self.delay_req else {
+                    return (None, None);
+                };
+                let Some((t1, t2)) = self.sync_times else {
+                    return (None, None);
+                };
+                if seq != message.header.sequence_id {
+                    return (None, None);
+                }
+                self.delay_req = None;
+
+                let Some(receive) = receive.as_nanos() else {
+                    debug!("Discarding PTP Delay_Resp with out-of-range receive time");
+                    return (None, None);
+                };
+                let t4 = receive as i128 - message.header.correction_nanos();
+                let master_to_slave = t2 - t1;
+                let slave_to_master = t4 - t3;
+                let sample = PathSample {
+                    offset_nanos: (master_to_slave - slave_to_master) / 2,
+                    delay_nanos: (master_to_slave + slave_to_master) / 2,
+                };
+                if sample.delay_nanos < 0 {
+                    debug!("Discarding PTP sample with negative path delay");
+                    return (None, None);
+                }
+                if self.samples.len() == SAMPLE_WINDOW {
+                    self.samples.pop_front();
+                }
+                self.samples.push_back(sample);
+                (None, Some(sample))
+            }
+            Body::DelayReq { .. } => (None, None),
+        }
+    }
+
+    fn try_complete_sync(&mut self) -> Option<Outgoing> {
+        let half = self.sync?;
+        let (t1, t2, source) = (half.t1?, half.t2?, half.source?);
+        self.sync = None;
+        self.sync_times = Some((t1 + half.sync_correction, t2));
+        Some(self.delay_request(source))
+    }
+
+    fn delay_request(&mut self, destination: SocketAddr) -> Outgoing {
+        let seq = self.next_delay_seq;
+        self.next_delay_seq = self.next_delay_seq.wrapping_add(1);
+        // The transmit time is recorded by the caller right before sending
+        self.delay_req = Some((seq, 0));
+        Outgoing {
+            destination,
+            message: PtpMessage {
+                header: Header::new(MessageType::DelayReq, self.identity, seq),
+                body: Body::DelayReq {
+                    origin: PtpTimestamp::default(),
+                },
+            },
+        }
+    }
+
+    /// Record the local transmit time of the outstanding Delay_Req
+    pub fn mark_delay_request_sent(&mut self, tx_local: i128) {
+        if let Some((_, t3)) = self.delay_req.as_mut() {
+            *t3 = tx_local;
+        }
+    }
+
+    fn is_from_master(&self, message: &PtpMessage, from: SocketAddr) -> bool {
+        match self.selected.and_then(|addr| self.masters.get(&addr)) {
+            Some(master) => {
+                self.selected == Some(from.ip())
+                    && master.source.clock_identity == message.header.source.clock_identity
+            }
+            None => false,
+        }
+    }
+}
+
+/// PTP follower configuration
+#[derive(Debug, Clone)]
+pub struct PtpConfig {
+    /// Local address to bind both sockets to
+    pub bind_addr: IpAddr,
+    /// Event port (319 by default; use 0 for unprivileged tests)
+    pub event_port: u16,
+    /// General port (320 by default; use 0 for unprivileged tests)
+    pub general_port: u16,
+    /// Join the PTP multicast group on both sockets
+    pub join_multicast: bool,
+    /// How long a master stays eligible without Announce messages
+    pub announce_timeout: Duration,
+    /// Our clock identity
+    pub clock_identity: u64,
+}
+
+impl Default for PtpConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            event_port: PTP_EVENT_PORT,
+            general_port: PTP_GENERAL_PORT,
+            join_multicast: true,
+            announce_timeout: Duration::from_secs(6),
+            clock_identity: rand::random(),
+        }
+    }
+}
+
+/// Running PTP follower task
+pub struct PtpFollower {
+    clock: Arc<OffsetClock>,
+    peers: watch::Sender<Vec<IpAddr>>,
+    event_addr: SocketAddr,
+    general_addr: SocketAddr,
+    cancel: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+impl PtpFollower {
+    /// Bind the PTP sockets and start following
+    pub async fn start(config: PtpConfig) -> Result<Self, PtpError> {
+        let event = UdpSocket::bind(SocketAddr::new(config.bind_addr, config.event_port)).await?;
+        let general =
+            UdpSocket::bind(SocketAddr::new(config.bind_addr, config.general_port)).await?;
+
+        if config.join_multicast {
+            for socket in [&event, &general] {
+                if let Err(e) = socket.join_multicast_v4(PTP_MULTICAST_ADDR, Ipv4Addr::UNSPECIFIED)
+                {
+                    warn!("Failed to join PTP multicast group: {}", e);
+                }
+            }
+        }
+
+        let event_addr = event.local_addr()?;
+        let general_addr = general.local_addr()?;
+        info!(
+            "PTP follower listening on {} (event) and {} (general)",
+            event_addr, general_addr
+        );
+
+        let clock = Arc::new(OffsetClock::new());
+        let (peers, peers_rx) = watch::channel(Vec::new());
+        let cancel = CancellationToken::new();
+        let core = PtpFollowerCore::new(config.clock_identity, config.announce_timeout);
+
+        let task = tokio::spawn(run(
+            core,
+            event,
+            general,
+            peers_rx,
+            clock.clone(),
+            cancel.clone(),
+        ));
+
+        Ok(Self {
+            clock,
+            peers,
+            event_addr,
+            general_addr,
+            cancel,
+            task: Some(task),
+        })
+    }
+
+    /// Clock synchronised to the selected grandmaster
+    pub fn clock(&self) -> Arc<OffsetClock> {
+        self.clock.clone()
+    }
+
+    /// Update the eligible masters from a SETPEERS request
+    pub fn set_peers(&self, peers: impl IntoIterator<Item = IpAddr>) {
+        self.peers.send_replace(peers.into_iter().collect());
+    }
+
+    /// Bound event socket address
+    pub fn event_addr(&self) -> SocketAddr {
+        self.event_addr
+    }
+
+    /// Bound general socket address
+    pub fn general_addr(&self) -> SocketAddr {
+        self.general_addr
+    }
+
+    /// Stop the follower and wait for its task to exit
+    pub async fn shutdown(mut self) {
+        self.cancel.cancel();
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for PtpFollower {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+async fn run(
+    mut core: PtpFollowerCore,
+    event: UdpSocket,
+    general: UdpSocket,
+    mut peers: watch::Receiver<Vec<IpAddr>>,
+    clock: Arc<OffsetClock>,
+    cancel: CancellationToken,
+) {
+    let mut event_buf = [0u8; 128];
+    let mut general_buf = [0u8; 128];
+    let mut housekeeping = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        let (len, from, is_event) = tokio::select! {
+            _ = cancel.cancelled() => break,
+            changed = peers.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let list = peers.borrow_and_update().clone();
+                debug!("PTP peers updated: {:?}", list);
+                core.set_peers(list);
+                if core.selected_master().is_none() {
+                    clock.reset();
+                }
+                continue;
+            }
+            _ = housekeeping.tick() => {
+                core.expire(Instant::now());
+                if core.selected_master().is_none() {
+                    clock.reset();
+                }
+                continue;
+            }
+            result = event.recv_from(&mut event_buf) => match result {
+                Ok((len, from)) => (len, from, true),
+                Err(e) => { warn!("PTP event socket error: {}", e); continue; }
+            },
+            result = general.recv_from(&mut general_buf) => match result {
+                Ok((len, from)) => (len, from, false),
+                Err(e) => { warn!("PTP general socket error: {}", e); continue; }
+            },
+        };
+
+        let now = Instant::now();
+        let rx_local = local_nanos(now);
+        let payload = if is_event {
+            &event_buf[..len]
+        } else {
+            &general_buf[..len]
+        };
+        let message = match PtpMessage::parse(payload) {
+            Ok(m) => m,
+            Err(e) => {
+                trace!("Ignoring PTP datagram from {}: {}", from, e);
+                continue;
+            }
+        };
+
+        let (outgoing, sample) = core.handle(&message, from, rx_local, now);
+
+        if let Some(out) = outgoing {
+            let bytes = out.message.encode();
+            core.mark_delay_request_sent(local_nanos(Instant::now()));
+            if let Err(e) = event.send_to(&bytes, out.destination).await {
+                warn!("Failed to send PTP Delay_Req to {}: {}", out.destination, e);
+            }
+        }
+
+        if sample.is_some() {
+            if let (Some(best), Some(gm)) = (core.best_sample(), core.grandmaster_identity()) {
+                trace!(
+                    "PTP offset {} ns, path delay {} ns",
+                    best.offset_nanos,
+                    best.delay_nanos
+                );
+                clock.update(gm, -best.offset_nanos);
+            }
+        }
+    }
+
+    debug!("PTP follower stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streaming::clock::MasterClock;
+
+    const GM_IDENTITY: u64 = 0x0011_22ff_fe33_4455;
+
+    fn announce(priority1: u8, grandmaster_identity: u64) -> Announce {
+        Announce {
+            origin: PtpTimestamp::default(),
+            current_utc_offset: 37,
+            priority1,
+            clock_class: 248,
+            clock_accuracy: 0xfe,
+            offset_scaled_log_variance: 0xffff,
+            priority2: 128,
+            grandmaster_identity,
+            steps_removed: 0,
+            time_source: 0xa0,
+        }
+    }
+
+    fn message(message_type: MessageType, source: u64, seq: u16, body: Body) -> PtpMessage {
+        PtpMessage {
+            header: Header::new(
+                message_type,
+                PortIdentity {
+                    clock_identity: source,
+                    port_number: 1,
+                },
+                seq,
+            ),
+            body,
+        }
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let messages = [
+            message(
+                MessageType::Sync,
+                GM_IDENTITY,
+                7,
+                Body::Sync {
+                    origin: PtpTimestamp::from_nanos(1_234_567_890_123),
+                },
+            ),
+            message(
+                MessageType::DelayResp,
+                GM_IDENTITY,
+                9,
+                Body::DelayResp {
+                    receive: PtpTimestamp::from_nanos(42),
+                    requesting: PortIdentity {
+                        clock_identity: 5,
+                        port_number: 1,
+                    },
+                },
+            ),
+            message(
+                MessageType::Announce,
+                GM_IDENTITY,
+                1,
+                Body::Announce(announce(128, GM_IDENTITY)),
+            ),
+        ];
+
+        for msg in messages {
+            let bytes = msg.encode();
+            assert_eq!(
+                u16::from_be_bytes([bytes[2], bytes[3]]) as usize,
+                bytes.len()
+            );
+            assert_eq!(PtpMessage::parse(&bytes).unwrap(), msg);
+        }
+
+        assert!(matches!(
+            PtpMessage::parse(&[0u8; 10]),
+            Err(PtpError::Truncated(10))
+        ));
+    }
+
+    #[test]
+    fn test_best_master_selection_among_peers() {
+        let a: IpAddr = "10.0.0.2".parse().unwrap();
+        let b: IpAddr = "10.0.0.3".parse().unwrap();
+        let outsider: IpAddr = "10.0.0.9".parse().unwrap();
+        let now = Instant::now();
+
+        let mut core = PtpFollowerCore::new(1, Duration::from_secs(6));
+        core.set_peers([a, b]);
+
+        let send = |core: &mut PtpFollowerCore, from: IpAddr, priority1: u8, gm: u64| {
+            let msg = message(
+                MessageType::Announce,
+                gm,
+                0,
+                Body::Announce(announce(priority1, gm)),
+            );
+            core.handle(&msg, SocketAddr::new(from, 320), 0, now);
+        };
+
+        send(&mut core, outsider, 0, 0x99);
+        assert_eq!(core.selected_master(), None);
+
+        send(&mut core, a, 200, 0xAA);
+        assert_eq!(core.selected_master(), Some(a));
+
+        send(&mut core, b, 100, 0xBB);
+        assert_eq!(core.selected_master(), Some(b));
+        assert_eq!(core.grandmaster_identity(), Some(0xBB));
+
+        core.set_peers([a]);
+        assert_eq!(core.selected_master(), Some(a));
+
+        core.expire(now + Duration::from_secs(10));
+        assert_eq!(core.selected_master(), None);
+    }
+
+    #[test]
+    fn test_out_of_range_timestamps_are_dropped() {
+        let master = SocketAddr::new("10.0.0.2".parse().unwrap(), 320);
+        let now = Instant::now();
+        let huge = PtpTimestamp {
+            seconds: 0xFFFF_FFFF_FFFF,
+            nanoseconds: 999_999_999,
+        };
+        assert_eq!(huge.as_nanos(), None);
+
+        let mut core = PtpFollowerCore::new(1, Duration::from_secs(6));
+        core.set_peers([master.ip()]);
+        let announce_msg = message(
+            MessageType::Announce,
+            GM_IDENTITY,
+            0,
+            Body::Announce(announce(128, GM_IDENTITY)),
+        );
+        core.handle(&announce_msg, master, 0, now);
+        assert_eq!(core.selected_master(), Some(master.ip()));
+
+        let sync =
+            |seq, origin| message(MessageType::Sync, GM_IDENTITY, seq, Body::Sync { origin });
+        assert_eq!(core.handle(&sync(1, huge), master, 10, now), (None, None));
+
+        let mut two_step = sync(2, PtpTimestamp::default());
+        two_step.header.flags = Header::FLAG_TWO_STEP;
+        assert_eq!(core.handle(&two_step, master, 10, now), (None, None));
+        let follow_up = message(
+            MessageType::FollowUp,
+            GM_IDENTITY,
+            2,
+            Body::FollowUp {
+                precise_origin: huge,
+            },
+        );
+        assert_eq!(core.handle(&follow_up, master, 10, now), (None, None));
+
+        // A valid exchange still goes through, but its Delay_Resp is dropped
+        let (request, _) = core.handle(&sync(3, PtpTimestamp::from_nanos(5)), master, 10, now);
+        let request = request.expect("a valid Sync asks for the path delay");
+        core.mark_delay_request_sent(20);
+        let resp = message(
+            MessageType::DelayResp,
+            GM_IDENTITY,
+            request.message.header.sequence_id,
+            Body::DelayResp {
+                receive: huge,
+                requesting: request.message.header.source,
+            },
+        );
+        assert_eq!(core.handle(&resp, master, 30, now), (None, None));
+        assert_eq!(core.best_sample(), None);
+    }
+
+    #[tokio::test]
+    async fn test_follows_scripted_grandmaster() {
+        const MASTER_OFFSET: i128 = 1_700_000_000_000_000_000;
+        let master_now = || (local_nanos(Instant::now()) + MASTER_OFFSET) as u64;
+
+        let follower = PtpFollower::start(PtpConfig {
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            event_port: 0,
+            general_port: 0,
+            join_multicast: false,
+            announce_timeout: Duration::from_secs(6),
+            clock_identity: 0x42,
+        })
+        .await
+        .unwrap();
+        follower.set_peers([IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let gm_event = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gm_general = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let source = PortIdentity {
+            clock_identity: GM_IDENTITY,
+            port_number: 1,
+        };
+
+        let mut announce_header = Header::new(MessageType::Announce, source, 0);
+        announce_header.log_message_interval = 0;
+        let announce_msg = PtpMessage {
+            header: announce_header,
+            body: Body::Announce(announce(128, GM_IDENTITY)),
+        };
+        gm_general
+            .send_to(&announce_msg.encode(), follower.general_addr())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let mut buf = [0u8; 128];
+        for seq in 0..4u16 {
+            let mut sync_header = Header::new(MessageType::Sync, source, seq);
+            sync_header.flags = Header::FLAG_TWO_STEP;
+            let sync = PtpMessage {
+                header: sync_header,
+                body: Body::Sync {
+                    origin: PtpTimestamp::default(),
+                },
+            };
+            let t1 = master_now();
+            gm_event
+                .send_to(&sync.encode(), follower.event_addr())
+                .await
+                .unwrap();
+            let follow_up = PtpMessage {
+                header: Header::new(MessageType::FollowUp, source, seq),
+                body: Body::FollowUp {
+                    precise_origin: PtpTimestamp::from_nanos(t1),
+                },
+            };
+            gm_general
+                .send_to(&follow_up.encode(), follower.general_addr())
+                .await
+                .unwrap();
+
+            let (len, from) =
+                tokio::time::timeout(Duration::from_secs(2), gm_event.recv_from(&mut buf))
+                    .await
+                    .expect("follower should send Delay_Req")
+                    .unwrap();
+            let t4 = master_now();
+            let req = PtpMessage::parse(&buf[..len]).unwrap();
+            assert_eq!(req.header.message_type, MessageType::DelayReq);
+
+            let resp = PtpMessage {
+                header: Header::new(MessageType::DelayResp, source, req.header.sequence_id),
+                body: Body::DelayResp {
+                    receive: PtpTimestamp::from_nanos(t4),
+                    requesting: req.header.source,
+                },
+            };
+            assert_eq!(from, follower.event_addr());
+            gm_general
+                .send_to(&resp.encode(), follower.general_addr())
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let clock = follower.clock();
+        assert_eq!(clock.timeline_id(), Some(GM_IDENTITY));
+        let error = clock.now().unwrap() as i128 - master_now() as i128;
+        assert!(error.abs() < 5_000_000, "clock error {} ns", error);
+
+        follower.shutdown().await;
+    }
+}
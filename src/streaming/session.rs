@@ -0,0 +1,1372 @@
+//! Playback session timing
+//!
+//! SETRATEANCHORTIME ties an RTP timestamp to an instant on the sender's
+//! network timeline (`networkTimeSecs`/`networkTimeFrac` on the timeline named
+//! by `networkTimeTimelineID`). Combined with the active [`MasterClock`] this
+//! yields "play sample N at local instant T", which is all the output stage
+//! needs to start sample-accurately.
+//!
+//! A rate of 0 pauses playback. Pausing only stops the timeline: buffered
+//! audio is left untouched so that a later anchor can resume from it.
+//!
+//! [`SessionManager`] owns the single active [`Session`] together with its
+//! streams, keys, clocks and tasks, and keeps `RECV_SESS_ACTIVE` (and thus the
+//! advertised mDNS status) in step with it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use plist::{Dictionary, Value};
+use thiserror::Error;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use super::clock::MasterClock;
+use super::ntp::fraction_to_nanos;
+use super::ptp::PtpFollower;
+use crate::config::{DeviceConfig, SessionPolicy, SharedConfig, StatusFlags};
+use crate::network::remote::{MediaRemote, RemoteCommand, RemoteControl};
+use crate::network::ServiceAdvertiser;
+
+/// Session errors
+#[derive(Debug, Error)]
+pub enum SessionError {
+    /// A required plist field is absent or has the wrong type
+    #[error("missing or invalid field `{0}`")]
+    InvalidField(&'static str),
+    /// Another sender owns the active session
+    #[error("receiver busy with {0}")]
+    Busy(SocketAddr),
+}
+
+/// RTSP status used to turn away a second sender
+pub const RTSP_NOT_ENOUGH_BANDWIDTH: u16 = 453;
+
+impl SessionError {
+    /// RTSP status code to answer the request with
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::InvalidField(_) => 400,
+            Self::Busy(_) => RTSP_NOT_ENOUGH_BANDWIDTH,
+        }
+    }
+}
+
+/// Mapping between an RTP timestamp and the network timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Anchor {
+    /// RTP timestamp of the anchored sample
+    pub rtp_time: u32,
+    /// Network time at which that sample must be played, in nanoseconds
+    pub network_time_nanos: u64,
+    /// Timeline (clock identity) the network time refers to
+    pub timeline_id: u64,
+}
+
+/// Parsed SETRATEANCHORTIME request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SetRateAnchorTime {
+    /// Playback rate (0 = paused, 1 = playing)
+    pub rate: f64,
+    /// Anchor, absent in plain pause requests
+    pub anchor: Option<Anchor>,
+}
+
+impl SetRateAnchorTime {
+    /// Parse the request body plist
+    pub fn from_plist(dict: &Dictionary) -> Result<Self, SessionError> {
+        let rate = match dict.get("rate") {
+            Some(Value::Real(r)) => *r,
+            Some(v) => plist_u64(Some(v)).ok_or(SessionError::InvalidField("rate"))? as f64,
+            None => return Err(SessionError::InvalidField("rate")),
+        };
+
+        let anchor = match plist_u64(dict.get("networkTimeSecs")) {
+            Some(secs) => {
+                let rtp_time =
+                    plist_u64(dict.get("rtpTime")).ok_or(SessionError::InvalidField("rtpTime"))?;
+                let frac = plist_u64(dict.get("networkTimeFrac")).unwrap_or(0);
+                let timeline_id = plist_u64(dict.get("networkTimeTimelineID"))
+                    .ok_or(SessionError::InvalidField("networkTimeTimelineID"))?;
+                let network_time_nanos = secs
+                    .checked_mul(1_000_000_000)
+                    .and_then(|nanos| nanos.checked_add(fraction_to_nanos(frac, 64)))
+                    .ok_or(SessionError::InvalidField("networkTimeSecs"))?;
+                Some(Anchor {
+                    rtp_time: rtp_time as u32,
+                    network_time_nanos,
+                    timeline_id,
+                })
+            }
+            None => None,
+        };
+
+        Ok(Self { rate, anchor })
+    }
+}
+
+/// Read an integer plist value regardless of its signedness
+pub(crate) fn plist_u64(value: Option<&Value>) -> Option<u64> {
+    let value = value?;
+    value
+        .as_unsigned_integer()
+        .or_else(|| value.as_signed_integer().map(|v| v as u64))
+}
+
+/// Effect of applying a SETRATEANCHORTIME request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateChange {
+    /// Playback started (or resumed) at a new anchor
+    Started,
+    /// Playback continues with a new anchor
+    Reanchored,
+    /// Playback paused; buffered audio is retained
+    Paused,
+    /// Nothing changed
+    Unchanged,
+}
+
+/// Where to begin output relative to the first buffered frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StartPoint {
+    /// Frames of silence to emit before the first buffered frame
+    pub silence_frames: u32,
+    /// Buffered frames to drop because their play time has passed
+    pub skip_frames: u32,
+}
+
+/// Converts RTP timestamps into local play times for one stream
+pub struct PlaybackTimeline {
+    sample_rate: u32,
+    clock: Arc<dyn MasterClock>,
+    anchor: Option<Anchor>,
+    rate: f64,
+}
+
+impl PlaybackTimeline {
+    /// Create a paused timeline for a stream at `sample_rate`
+    ///
+    /// The rate comes from the stream's SETUP (`sr`) and must not be zero.
+    pub fn new(sample_rate: u32, clock: Arc<dyn MasterClock>) -> Result<Self, SessionError> {
+        if sample_rate == 0 {
+            return Err(SessionError::InvalidField("sr"));
+        }
+        Ok(Self {
+            sample_rate,
+            clock,
+            anchor: None,
+            rate: 0.0,
+        })
+    }
+
+    /// Switch to a different timing clock (e.g. NTP to PTP)
+    pub fn set_clock(&mut self, clock: Arc<dyn MasterClock>) {
+        self.clock = clock;
+    }
+
+    /// Stream sample rate
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Current anchor
+    pub fn anchor(&self) -> Option<Anchor> {
+        self.anchor
+    }
+
+    /// Check whether output should be running
+    pub fn is_playing(&self) -> bool {
+        self.rate > 0.0 && self.anchor.is_some()
+    }
+
+    /// Apply a SETRATEANCHORTIME request
+    pub fn apply(&mut self, request: &SetRateAnchorTime) -> RateChange {
+        let was_playing = self.is_playing();
+        if let Some(anchor) = request.anchor {
+            self.anchor = Some(anchor);
+        }
+        self.rate = request.rate;
+
+        if request.rate != 0.0 && request.rate != 1.0 {
+            warn!("Unsupported playback rate {}, playing at 1.0", request.rate);
+        }
+
+        let change = match (was_playing, self.is_playing()) {
+            (false, true) => RateChange::Started,
+            (true, false) => RateChange::Paused,
+            (true, true) if request.anchor.is_some() => RateChange::Reanchored,
+            _ => RateChange::Unchanged,
+        };
+        debug!("SETRATEANCHORTIME rate={} -> {:?}", request.rate, change);
+        change
+    }
+
+    /// Network time at which `rtp_time` is due, in nanoseconds
+    fn network_time_of(&self, anchor: &Anchor, rtp_time: u32) -> i128 {
+        let frames = rtp_time.wrapping_sub(anchor.rtp_time) as i32 as i128;
+        anchor.network_time_nanos as i128 + frames * 1_000_000_000 / self.sample_rate as i128
+    }
+
+    fn active_anchor(&self) -> Option<Anchor> {
+        let anchor = self.anchor?;
+        if self.clock.timeline_id() != Some(anchor.timeline_id) {
+            debug!(
+                "Anchor timeline {:#x} does not match clock {:?}",
+                anchor.timeline_id,
+                self.clock.timeline_id()
+            );
+            return None;
+        }
+        Some(anchor)
+    }
+
+    /// Local instant at which the frame with `rtp_time` must be played
+    ///
+    /// Returns `None` if there is no anchor or the clock is not locked to the
+    /// anchor's timeline.
+    pub fn local_time_of(&self, rtp_time: u32) -> Option<Instant> {
+        let anchor = self.active_anchor()?;
+        let network = u64::try_from(self.network_time_of(&anchor, rtp_time)).ok()?;
+        self.clock.local_time_of(network)
+    }
+
+    /// RTP timestamp that should be playing at the given local instant
+    pub fn rtp_time_at(&self, local: Instant) -> Option<u32> {
+        let anchor = self.active_anchor()?;
+        let network = self.clock.master_time_at(local)? as i128;
+        let elapsed = network - anchor.network_time_nanos as i128;
+        let frames = (elapsed * self.sample_rate as i128).div_euclid(1_000_000_000);
+        Some(anchor.rtp_time.wrapping_add(frames as i64 as u32))
+    }
+
+    /// Work out how to start output so that `first_rtp` lands on its deadline
+    ///
+    /// Returns `None` while paused or unsynchronised.
+    pub fn start_point(&self, first_rtp: u32, now: Instant) -> Option<StartPoint> {
+        if !self.is_playing() {
+            return None;
+        }
+        let due = self.local_time_of(first_rtp)?;
+        let frames = |d: Duration| {
+            ((d.as_nanos() * self.sample_rate as u128 + 500_000_000) / 1_000_000_000) as u32
+        };
+        Some(if due >= now {
+            StartPoint {
+                silence_frames: frames(due - now),
+                skip_frames: 0,
+            }
+        } else {
+            StartPoint {
+                silence_frames: 0,
+                skip_frames: frames(now - due),
+            }
+        })
+    }
+}
+
+impl fmt::Debug for PlaybackTimeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PlaybackTimeline")
+            .field("sample_rate", &self.sample_rate)
+            .field("anchor", &self.anchor)
+            .field("rate", &self.rate)
+            .field("timeline", &self.clock.timeline_id())
+            .finish()
+    }
+}
+
+/// Identifier of a playback session
+pub type SessionId = u64;
+
+/// Stream type requested in SETUP (`type` field)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    /// Realtime (NTP-timed) audio
+    Realtime = 96,
+    /// Buffered (PTP-timed) audio
+    Buffered = 103,
+}
+
+/// Latency aimed for in the default audio mode
+pub const DEFAULT_TARGET_LATENCY: Duration = Duration::from_secs(2);
+
+/// Latency aimed for while the sender plays video
+pub const MOVIE_TARGET_LATENCY: Duration = Duration::from_millis(500);
+
+/// Playback mode set through `/audioMode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AudioMode {
+    /// Audio-only playback
+    #[default]
+    Default,
+    /// Audio for video; the sender delays the picture by our latency, so it
+    /// should be short
+    MoviePlayback,
+}
+
+impl AudioMode {
+    /// Latency the playback pipeline aims for
+    pub fn target_latency(self) -> Duration {
+        match self {
+            AudioMode::Default => DEFAULT_TARGET_LATENCY,
+            AudioMode::MoviePlayback => MOVIE_TARGET_LATENCY,
+        }
+    }
+}
+
+impl FromStr for AudioMode {
+    type Err = SessionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(AudioMode::Default),
+            "moviePlayback" => Ok(AudioMode::MoviePlayback),
+            _ => Err(SessionError::InvalidField("audioMode")),
+        }
+    }
+}
+
+/// Identity of the sender owning a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SenderInfo {
+    /// Address of the RTSP connection
+    pub address: SocketAddr,
+    /// HAP controller identifier, known after pair-verify
+    pub controller_id: Option<String>,
+    /// `DACP-ID` header
+    pub dacp_id: Option<String>,
+    /// `Active-Remote` header
+    pub active_remote: Option<String>,
+}
+
+impl SenderInfo {
+    /// Sender known only by its connection address
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            controller_id: None,
+            dacp_id: None,
+            active_remote: None,
+        }
+    }
+
+    /// Check whether two connections come from the same sender
+    ///
+    /// Controller identifiers are compared when both are known, otherwise the
+    /// IP address decides (senders reconnect from a new port).
+    pub fn is_same_sender(&self, other: &SenderInfo) -> bool {
+        match (&self.controller_id, &other.controller_id) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.address.ip() == other.address.ip(),
+        }
+    }
+}
+
+/// Key material negotiated for a session
+#[derive(Default)]
+pub struct SessionKeys {
+    /// Pair-verify shared secret
+    pub shared_secret: Vec<u8>,
+    /// Audio stream key from SETUP (`shk`)
+    pub stream_key: Option<Vec<u8>>,
+}
+
+impl fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionKeys")
+            .field("shared_secret", &"<redacted>")
+            .field(
+                "stream_key",
+                &self.stream_key.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+impl Drop for SessionKeys {
+    fn drop(&mut self) {
+        self.shared_secret.fill(0);
+        if let Some(key) = self.stream_key.as_mut() {
+            key.fill(0);
+        }
+    }
+}
+
+/// A stream set up within a session
+#[derive(Debug)]
+pub struct StreamState {
+    /// Stream identifier from SETUP
+    pub id: u64,
+    /// Stream type
+    pub kind: StreamKind,
+    /// Local audio data port
+    pub data_port: u16,
+    /// Local control port
+    pub control_port: u16,
+    /// Cancelled when the stream is torn down
+    pub cancel: CancellationToken,
+}
+
+/// Why a session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndReason {
+    /// Sender sent TEARDOWN
+    Teardown,
+    /// RTSP connection dropped
+    Disconnected,
+    /// No sender activity within the idle timeout
+    IdleTimeout,
+    /// Another sender took over
+    Preempted,
+    /// Stopped from the receiver side (e.g. the control API)
+    Stopped,
+}
+
+/// Message the receiver wants delivered to a session's sender
+///
+/// Sent as a MediaRemote command over the event connection
+/// ([`Session::set_events`]), or over DACP when that is all the sender offers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SenderNotice {
+    /// Another sender took over; stop streaming
+    Preempted,
+}
+
+impl SenderNotice {
+    /// Remote command carrying the notice
+    pub fn command(self) -> RemoteCommand {
+        match self {
+            SenderNotice::Preempted => RemoteCommand::Stop,
+        }
+    }
+}
+
+/// The single active playback session
+pub struct Session {
+    id: SessionId,
+    sender: SenderInfo,
+    started: Instant,
+    last_activity: Instant,
+    streams: HashMap<u64, StreamState>,
+    keys: SessionKeys,
+    clock: Option<Arc<dyn MasterClock>>,
+    ptp: Option<PtpFollower>,
+    remote: Option<Arc<RemoteControl>>,
+    events: Option<mpsc::UnboundedSender<Dictionary>>,
+    supported_commands: Vec<RemoteCommand>,
+    audio_mode: AudioMode,
+    cancel: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Session {
+    fn new(id: SessionId, sender: SenderInfo) -> Self {
+        let now = Instant::now();
+        Self {
+            id,
+            sender,
+            started: now,
+            last_activity: now,
+            streams: HashMap::new(),
+            keys: SessionKeys::default(),
+            clock: None,
+            ptp: None,
+            remote: None,
+            events: None,
+            supported_commands: Vec::new(),
+            audio_mode: AudioMode::Default,
+            cancel: CancellationToken::new(),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Session identifier
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// Sender owning the session
+    pub fn sender(&self) -> &SenderInfo {
+        &self.sender
+    }
+
+    /// Mutable sender details (filled in as headers and pairing arrive)
+    pub fn sender_mut(&mut self) -> &mut SenderInfo {
+        &mut self.sender
+    }
+
+    /// When the session started
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    /// Record sender activity, resetting the idle timer
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    /// Last sender activity
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
+
+    /// Streams in this session
+    pub fn streams(&self) -> impl Iterator<Item = &StreamState> {
+        self.streams.values()
+    }
+
+    /// Register a stream; its token is tied to the session's lifetime
+    pub fn add_stream(
+        &mut self,
+        id: u64,
+        kind: StreamKind,
+        data_port: u16,
+        control_port: u16,
+    ) -> CancellationToken {
+        let cancel = self.cancel.child_token();
+        self.streams.insert(
+            id,
+            StreamState {
+                id,
+                kind,
+                data_port,
+                control_port,
+                cancel: cancel.clone(),
+            },
+        );
+        cancel
+    }
+
+    /// Tear down a single stream, cancelling its tasks
+    pub fn remove_stream(&mut self, id: u64) -> Option<StreamState> {
+        let stream = self.streams.remove(&id)?;
+        stream.cancel.cancel();
+        Some(stream)
+    }
+
+    /// Session keys
+    pub fn keys(&self) -> &SessionKeys {
+        &self.keys
+    }
+
+    /// Mutable session keys
+    pub fn keys_mut(&mut self) -> &mut SessionKeys {
+        &mut self.keys
+    }
+
+    /// Active timing clock
+    pub fn clock(&self) -> Option<Arc<dyn MasterClock>> {
+        self.clock.clone()
+    }
+
+    /// Use `clock` for timing (e.g. an NTP clock for realtime streams)
+    pub fn set_clock(&mut self, clock: Arc<dyn MasterClock>) {
+        self.clock = Some(clock);
+    }
+
+    /// Hand a PTP follower to the session and use it for timing
+    pub fn set_ptp(&mut self, follower: PtpFollower) {
+        self.clock = Some(follower.clock());
+        self.ptp = Some(follower);
+    }
+
+    /// PTP follower, if one is running
+    pub fn ptp(&self) -> Option<&PtpFollower> {
+        self.ptp.as_ref()
+    }
+
+    /// Register the remote control path to the sender
+    pub fn set_remote(&mut self, remote: RemoteControl) {
+        info!("Session {} remote control via {}", self.id, remote.kind());
+        self.remote = Some(Arc::new(remote));
+    }
+
+    /// Remote control of the sender, if it supports one
+    pub fn remote(&self) -> Option<Arc<RemoteControl>> {
+        self.remote.clone()
+    }
+
+    /// Register the queue of the sender's event connection
+    ///
+    /// Remote control moves to MediaRemote commands over it.
+    pub fn set_events(&mut self, events: mpsc::UnboundedSender<Dictionary>) {
+        self.set_remote(RemoteControl::MediaRemote(MediaRemote::new(events.clone())));
+        self.events = Some(events);
+    }
+
+    /// Queue for `POST /command` messages to the sender, once it connected
+    /// to the event port
+    pub fn events(&self) -> Option<mpsc::UnboundedSender<Dictionary>> {
+        self.events.clone()
+    }
+
+    /// Record the remote commands the sender currently accepts
+    pub fn set_supported_commands(&mut self, commands: Vec<RemoteCommand>) {
+        debug!("Session {} sender accepts {:?}", self.id, commands);
+        self.supported_commands = commands;
+    }
+
+    /// Remote commands the sender accepts, empty until it says
+    pub fn supported_commands(&self) -> &[RemoteCommand] {
+        &self.supported_commands
+    }
+
+    /// Whether the sender accepts `command`; assumed until it says otherwise
+    pub fn accepts(&self, command: RemoteCommand) -> bool {
+        self.supported_commands.is_empty() || self.supported_commands.contains(&command)
+    }
+
+    /// Switch the playback mode
+    pub fn set_audio_mode(&mut self, mode: AudioMode) {
+        if self.audio_mode != mode {
+            info!("Session {} audio mode {:?}", self.id, mode);
+            self.audio_mode = mode;
+        }
+    }
+
+    /// Current playback mode
+    pub fn audio_mode(&self) -> AudioMode {
+        self.audio_mode
+    }
+
+    /// Tell the sender about `notice`, preferring the event connection
+    fn notify(&self, notice: SenderNotice) {
+        let command = notice.command();
+        if let Some(events) = &self.events {
+            if events.send(MediaRemote::command_body(command)).is_ok() {
+                return;
+            }
+            debug!("Event connection of session {} is gone", self.id);
+        }
+        let Some(remote) = self.remote.clone() else {
+            debug!("No back-channel to the sender of session {}", self.id);
+            return;
+        };
+        // Not a session task: it must outlive the session's shutdown
+        let id = self.id;
+        tokio::spawn(async move {
+            if let Err(e) = remote.send(command).await {
+                debug!(
+                    "Failed to send {:?} to session {} sender: {}",
+                    notice, id, e
+                );
+            }
+        });
+    }
+
+    /// Token cancelled when the session ends
+    pub fn cancellation(&self) -> CancellationToken {
+        self.cancel.child_token()
+    }
+
+    /// Spawn a task owned by the session
+    ///
+    /// The task receives a token that is cancelled on teardown and is awaited
+    /// before the session is considered gone.
+    pub fn spawn<F, Fut>(&mut self, f: F)
+    where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.retain(|t| !t.is_finished());
+        self.tasks.push(tokio::spawn(f(self.cancel.child_token())));
+    }
+
+    async fn shutdown(mut self) {
+        self.cancel.cancel();
+        if let Some(ptp) = self.ptp.take() {
+            ptp.shutdown().await;
+        }
+        for task in self.tasks.drain(..) {
+            let abort = task.abort_handle();
+            if tokio::time::timeout(TASK_SHUTDOWN_TIMEOUT, task)
+                .await
+                .is_err()
+            {
+                warn!("Session task did not stop in time, aborting");
+                abort.abort();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("id", &self.id)
+            .field("sender", &self.sender)
+            .field("streams", &self.streams)
+            .field("tasks", &self.tasks.len())
+            .finish()
+    }
+}
+
+/// How long teardown waits for session tasks before aborting them
+const TASK_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Decision on a session request while another session is active
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// Same sender reconnecting: replace its session
+    Replace,
+    /// Stop the current sender and admit the newcomer
+    Preempt,
+    /// Refuse the newcomer (453 Not Enough Bandwidth)
+    Reject,
+}
+
+/// Decide whether `newcomer` may take over from `current`
+///
+/// Priority controllers always win; a non-priority newcomer can never take
+/// over from a priority controller.
+pub fn admit(
+    policy: SessionPolicy,
+    priority_controllers: &[String],
+    current: &SenderInfo,
+    newcomer: &SenderInfo,
+) -> Admission {
+    if current.is_same_sender(newcomer) {
+        return Admission::Replace;
+    }
+    let is_priority = |s: &SenderInfo| {
+        s.controller_id.as_ref().is_some_and(|id| {
+            priority_controllers
+                .iter()
+                .any(|p| p.eq_ignore_ascii_case(id))
+        })
+    };
+    if is_priority(newcomer) {
+        return Admission::Preempt;
+    }
+    if is_priority(current) {
+        return Admission::Reject;
+    }
+    match policy {
+        SessionPolicy::Reject => Admission::Reject,
+        SessionPolicy::Preempt => Admission::Preempt,
+    }
+}
+
+/// Owns the single active session and the `RECV_SESS_ACTIVE` status flag
+pub struct SessionManager {
+    config: SharedConfig,
+    advertiser: Option<Arc<dyn ServiceAdvertiser>>,
+    active: Mutex<Option<Session>>,
+    next_id: AtomicU64,
+    changes: watch::Sender<Option<SessionId>>,
+}
+
+impl SessionManager {
+    /// Create a manager; `advertiser` is re-published on status changes
+    pub fn new(config: SharedConfig, advertiser: Option<Arc<dyn ServiceAdvertiser>>) -> Self {
+        Self {
+            config,
+            advertiser,
+            active: Mutex::new(None),
+            next_id: AtomicU64::new(1),
+            changes: watch::channel(None).0,
+        }
+    }
+
+    /// Follow the active session identifier as sessions start and end
+    pub fn subscribe(&self) -> watch::Receiver<Option<SessionId>> {
+        self.changes.subscribe()
+    }
+
+    /// Start a session for `sender`
+    ///
+    /// A reconnecting sender replaces its own session. Any other sender is
+    /// admitted or refused according to the configured [`SessionPolicy`] and
+    /// priority controller list.
+    pub async fn begin(&self, sender: SenderInfo) -> Result<SessionId, SessionError> {
+        let mut active = self.active.lock().await;
+        let mut replaced = None;
+        let mut notice = None;
+        if let Some(current) = active.as_ref() {
+            let admission = {
+                let config = self.read_config();
+                admit(
+                    config.session_policy,
+                    &config.priority_controllers,
+                    &current.sender,
+                    &sender,
+                )
+            };
+            match admission {
+                Admission::Reject => {
+                    info!(
+                        "Rejecting session from {}: busy with {}",
+                        sender.address, current.sender.address
+                    );
+                    return Err(SessionError::Busy(current.sender.address));
+                }
+                Admission::Replace => {
+                    debug!(
+                        "Sender {} reconnected, replacing session {}",
+                        sender.address, current.id
+                    );
+                    replaced = active.take();
+                }
+                Admission::Preempt => {
+                    info!(
+                        "Session {} ({}) preempted by {}",
+                        current.id, current.sender.address, sender.address
+                    );
+                    notice = Some(SenderNotice::Preempted);
+                    replaced = active.take();
+                }
+            }
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        info!("Session {} started by {}", id, sender.address);
+        *active = Some(Session::new(id, sender));
+        self.set_active_flag(true);
+        self.changes.send_replace(Some(id));
+        drop(active);
+
+        // As in `end`, the old sender is told and its tasks stopped without
+        // holding up other callers
+        if let Some(old) = replaced {
+            if let Some(notice) = notice {
+                old.notify(notice);
+            }
+            old.shutdown().await;
+        }
+        Ok(id)
+    }
+
+    /// Run `f` against the session if `id` is still active
+    pub async fn with_session<R>(
+        &self,
+        id: SessionId,
+        f: impl FnOnce(&mut Session) -> R,
+    ) -> Option<R> {
+        let mut active = self.active.lock().await;
+        active.as_mut().filter(|s| s.id == id).map(f)
+    }
+
+    /// Record sender activity for `id`
+    pub async fn touch(&self, id: SessionId) -> bool {
+        self.with_session(id, Session::touch).await.is_some()
+    }
+
+    /// Identifier of the active session
+    pub async fn active_id(&self) -> Option<SessionId> {
+        self.active.lock().await.as_ref().map(|s| s.id)
+    }
+
+    /// Sender of the active session
+    pub async fn active_sender(&self) -> Option<SenderInfo> {
+        self.active.lock().await.as_ref().map(|s| s.sender.clone())
+    }
+
+    /// Remote control of the active session's sender, if it accepts `command`
+    pub async fn active_remote(&self, command: RemoteCommand) -> Option<Arc<RemoteControl>> {
+        self.active
+            .lock()
+            .await
+            .as_ref()
+            .filter(|s| s.accepts(command))
+            .and_then(|s| s.remote())
+    }
+
+    /// Check whether a session is active
+    pub async fn is_busy(&self) -> bool {
+        self.active.lock().await.is_some()
+    }
+
+    /// End session `id`, stopping all of its tasks
+    ///
+    /// The receiver is idle again as soon as the session is removed, so a new
+    /// sender may be admitted while the old tasks are still shutting down.
+    pub async fn end(&self, id: SessionId, reason: EndReason) -> bool {
+        let session = {
+            let mut active = self.active.lock().await;
+            let session = match active.as_ref() {
+                Some(s) if s.id == id => active.take(),
+                _ => None,
+            };
+            // Cleared under the lock so a concurrent `begin` cannot be undone
+            if session.is_some() {
+                self.set_active_flag(false);
+                self.changes.send_replace(None);
+            }
+            session
+        };
+        let Some(session) = session else {
+            return false;
+        };
+        info!("Session {} ended ({:?})", id, reason);
+        session.shutdown().await;
+        true
+    }
+
+    /// End the active session if it has been idle past the configured timeout
+    pub async fn expire_idle(&self, now: Instant) -> bool {
+        let timeout = self.read_config().session_idle_timeout;
+        let expired = self
+            .active
+            .lock()
+            .await
+            .as_ref()
+            .filter(|s| now.saturating_duration_since(s.last_activity) >= timeout)
+            .map(|s| s.id);
+        match expired {
+            Some(id) => self.end(id, EndReason::IdleTimeout).await,
+            None => false,
+        }
+    }
+
+    /// Periodically expire idle sessions until `cancel` fires
+    pub fn spawn_idle_monitor(self: &Arc<Self>, cancel: CancellationToken) -> JoinHandle<()> {
+        let manager = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = tick.tick() => {
+                        manager.expire_idle(Instant::now()).await;
+                    }
+                }
+            }
+        })
+    }
+
+    fn read_config(&self) -> std::sync::RwLockReadGuard<'_, DeviceConfig> {
+        self.config.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set_active_flag(&self, active: bool) {
+        let snapshot = {
+            let mut config = self.config.write().unwrap_or_else(|e| e.into_inner());
+            if config.status.contains(StatusFlags::RECV_SESS_ACTIVE) == active {
+                return;
+            }
+            config.status.set(StatusFlags::RECV_SESS_ACTIVE, active);
+            config.clone()
+        };
+        if let Some(advertiser) = &self.advertiser {
+            if let Err(e) = advertiser.republish(&snapshot) {
+                warn!("Failed to re-publish mDNS status: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streaming::clock::OffsetClock;
+
+    const TIMELINE: u64 = 0x1234;
+
+    fn locked_clock() -> Arc<OffsetClock> {
+        let clock = Arc::new(OffsetClock::new());
+        clock.update(TIMELINE, 5_000_000_000_000);
+        clock
+    }
+
+    fn play_request(rtp_time: u32, network_time_nanos: u64) -> SetRateAnchorTime {
+        SetRateAnchorTime {
+            rate: 1.0,
+            anchor: Some(Anchor {
+                rtp_time,
+                network_time_nanos,
+                timeline_id: TIMELINE,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_parse_plist() {
+        let mut dict = Dictionary::new();
+        dict.insert("rate".into(), Value::Integer(1.into()));
+        dict.insert("rtpTime".into(), Value::Integer(4_000_000_000u64.into()));
+        dict.insert("networkTimeSecs".into(), Value::Integer(100.into()));
+        dict.insert(
+            "networkTimeFrac".into(),
+            Value::Integer((1u64 << 62).into()),
+        );
+        dict.insert(
+            "networkTimeTimelineID".into(),
+            Value::Integer(u64::MAX.into()),
+        );
+
+        let request = SetRateAnchorTime::from_plist(&dict).unwrap();
+        assert_eq!(request.rate, 1.0);
+        assert_eq!(
+            request.anchor,
+            Some(Anchor {
+                rtp_time: 4_000_000_000,
+                network_time_nanos: 100_250_000_000,
+                timeline_id: u64::MAX,
+            })
+        );
+
+        let mut pause = Dictionary::new();
+        pause.insert("rate".into(), Value::Real(0.0));
+        let request = SetRateAnchorTime::from_plist(&pause).unwrap();
+        assert_eq!(request.rate, 0.0);
+        assert!(request.anchor.is_none());
+
+        assert!(SetRateAnchorTime::from_plist(&Dictionary::new()).is_err());
+
+        dict.insert("networkTimeSecs".into(), Value::Integer(u64::MAX.into()));
+        assert!(matches!(
+            SetRateAnchorTime::from_plist(&dict),
+            Err(SessionError::InvalidField("networkTimeSecs"))
+        ));
+    }
+
+    #[test]
+    fn test_anchor_maps_rtp_to_local_time() {
+        let clock = locked_clock();
+        assert!(matches!(
+            PlaybackTimeline::new(0, clock.clone()),
+            Err(SessionError::InvalidField("sr"))
+        ));
+        let mut timeline = PlaybackTimeline::new(44100, clock.clone()).unwrap();
+
+        let now = Instant::now();
+        let anchor_net = clock.master_time_at(now).unwrap() + 2_000_000_000;
+        assert_eq!(
+            timeline.apply(&play_request(u32::MAX - 100, anchor_net)),
+            RateChange::Started
+        );
+
+        // One second of audio later, across an RTP wrap
+        let later = (u32::MAX - 100).wrapping_add(44100);
+        assert_eq!(
+            timeline.local_time_of(later),
+            Some(now + Duration::from_secs(3))
+        );
+        assert_eq!(
+            timeline.rtp_time_at(now + Duration::from_secs(3)),
+            Some(later)
+        );
+    }
+
+    #[test]
+    fn test_start_point_silence_and_skip() {
+        let clock = locked_clock();
+        let mut timeline = PlaybackTimeline::new(44100, clock.clone()).unwrap();
+        let now = Instant::now();
+        let net_now = clock.master_time_at(now).unwrap();
+        timeline.apply(&play_request(10_000, net_now + 100_000_000));
+
+        // Head of buffer is the anchor frame: wait 100 ms = 4410 frames
+        assert_eq!(
+            timeline.start_point(10_000, now),
+            Some(StartPoint {
+                silence_frames: 4410,
+                skip_frames: 0,
+            })
+        );
+
+        // Head of buffer is 8820 frames early: 100 ms already passed
+        assert_eq!(
+            timeline.start_point(10_000 - 8820, now),
+            Some(StartPoint {
+                silence_frames: 0,
+                skip_frames: 4410,
+            })
+        );
+    }
+
+    #[test]
+    fn test_pause_keeps_anchor() {
+        let clock = locked_clock();
+        let mut timeline = PlaybackTimeline::new(48000, clock.clone()).unwrap();
+        timeline.apply(&play_request(0, clock.now().unwrap()));
+        assert!(timeline.is_playing());
+
+        let pause = SetRateAnchorTime {
+            rate: 0.0,
+            anchor: None,
+        };
+        assert_eq!(timeline.apply(&pause), RateChange::Paused);
+        assert!(!timeline.is_playing());
+        assert!(timeline.anchor().is_some());
+        assert_eq!(timeline.start_point(0, Instant::now()), None);
+        assert_eq!(timeline.apply(&pause), RateChange::Unchanged);
+    }
+
+    #[test]
+    fn test_timeline_mismatch_is_unscheduled() {
+        let clock = Arc::new(OffsetClock::new());
+        clock.update(0x9999, 0);
+        let mut timeline = PlaybackTimeline::new(44100, clock.clone()).unwrap();
+        timeline.apply(&play_request(0, 1_000_000_000));
+        assert_eq!(timeline.local_time_of(0), None);
+
+        timeline.set_clock(locked_clock());
+        assert!(timeline.local_time_of(0).is_some());
+    }
+
+    #[derive(Default)]
+    struct RecordingAdvertiser {
+        published: std::sync::Mutex<Vec<StatusFlags>>,
+    }
+
+    impl ServiceAdvertiser for RecordingAdvertiser {
+        fn publish(&self, config: &DeviceConfig) -> Result<(), crate::network::mdns::MdnsError> {
+            self.published.lock().unwrap().push(config.status);
+            Ok(())
+        }
+
+        fn unpublish(&self) -> Result<(), crate::network::mdns::MdnsError> {
+            Ok(())
+        }
+    }
+
+    fn manager() -> (Arc<SessionManager>, SharedConfig, Arc<RecordingAdvertiser>) {
+        let config: SharedConfig = Arc::new(std::sync::RwLock::new(DeviceConfig::default()));
+        let advertiser = Arc::new(RecordingAdvertiser::default());
+        let manager = SessionManager::new(config.clone(), Some(advertiser.clone()));
+        (Arc::new(manager), config, advertiser)
+    }
+
+    fn sender(addr: &str) -> SenderInfo {
+        SenderInfo::new(addr.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_session_sets_and_clears_active_flag() {
+        let (manager, config, advertiser) = manager();
+        let id = manager.begin(sender("10.0.0.2:50000")).await.unwrap();
+        assert!(config
+            .read()
+            .unwrap()
+            .status
+            .contains(StatusFlags::RECV_SESS_ACTIVE));
+
+        assert!(manager.end(id, EndReason::Teardown).await);
+        assert!(!config
+            .read()
+            .unwrap()
+            .status
+            .contains(StatusFlags::RECV_SESS_ACTIVE));
+        assert!(!manager.end(id, EndReason::Teardown).await);
+
+        assert_eq!(
+            *advertiser.published.lock().unwrap(),
+            vec![StatusFlags::RECV_SESS_ACTIVE, StatusFlags::empty()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_other_sender_sees_busy() {
+        let (manager, _, _) = manager();
+        let first = manager.begin(sender("10.0.0.2:50000")).await.unwrap();
+
+        assert!(matches!(
+            manager.begin(sender("10.0.0.3:50000")).await,
+            Err(SessionError::Busy(_))
+        ));
+
+        // The same phone reconnecting from a new port replaces its session
+        let second = manager.begin(sender("10.0.0.2:50001")).await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(manager.active_id().await, Some(second));
+    }
+
+    #[tokio::test]
+    async fn test_teardown_stops_session_tasks() {
+        let (manager, _, _) = manager();
+        let id = manager.begin(sender("10.0.0.2:50000")).await.unwrap();
+
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        let stream_token = manager
+            .with_session(id, |session| {
+                session.spawn(|cancel| async move {
+                    cancel.cancelled().await;
+                    let _ = done_tx.send(());
+                });
+                session.add_stream(1, StreamKind::Buffered, 6000, 6001)
+            })
+            .await
+            .unwrap();
+
+        manager.end(id, EndReason::Disconnected).await;
+        assert!(stream_token.is_cancelled());
+        tokio::time::timeout(Duration::from_secs(1), done_rx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!manager.is_busy().await);
+    }
+
+    #[tokio::test]
+    async fn test_begin_during_slow_end() {
+        let (manager, config, _) = manager();
+        let first = manager.begin(sender("10.0.0.2:50000")).await.unwrap();
+        manager
+            .with_session(first, |session| {
+                // Ignores cancellation, so shutdown waits for it
+                session.spawn(|_| tokio::time::sleep(Duration::from_millis(300)))
+            })
+            .await;
+
+        let ending = tokio::spawn({
+            let manager = manager.clone();
+            async move { manager.end(first, EndReason::Teardown).await }
+        });
+        while manager.is_busy().await {
+            tokio::task::yield_now().await;
+        }
+        let second = manager.begin(sender("10.0.0.3:50000")).await.unwrap();
+        assert!(ending.await.unwrap());
+
+        assert_eq!(manager.active_id().await, Some(second));
+        assert_eq!(*manager.subscribe().borrow(), Some(second));
+        assert!(config
+            .read()
+            .unwrap()
+            .status
+            .contains(StatusFlags::RECV_SESS_ACTIVE));
+    }
+
+    /// A session whose task ignores cancellation, so shutting it down is slow
+    async fn slow_session(manager: &SessionManager, address: &str) -> SessionId {
+        let id = manager.begin(sender(address)).await.unwrap();
+        manager
+            .with_session(id, |session| {
+                session.spawn(|_| tokio::time::sleep(Duration::from_millis(300)))
+            })
+            .await;
+        id
+    }
+
+    /// Begin a session for `address` in the background and check that the
+    /// manager serves it while the old session is still shutting down
+    async fn assert_begin_does_not_block(
+        manager: &Arc<SessionManager>,
+        old: SessionId,
+        address: &str,
+    ) {
+        let mut changes = manager.subscribe();
+        let beginning = tokio::spawn({
+            let manager = manager.clone();
+            let sender = sender(address);
+            async move { manager.begin(sender).await }
+        });
+        let new = loop {
+            changes.changed().await.unwrap();
+            match *changes.borrow_and_update() {
+                Some(id) if id != old => break id,
+                _ => {}
+            }
+        };
+
+        let served = tokio::time::timeout(
+            Duration::from_millis(100),
+            manager.with_session(new, |session| session.touch()),
+        )
+        .await;
+        assert_eq!(served, Ok(Some(())));
+        assert!(!beginning.is_finished());
+        assert_eq!(beginning.await.unwrap().unwrap(), new);
+    }
+
+    #[tokio::test]
+    async fn test_replace_does_not_hold_the_lock() {
+        let (manager, _, _) = manager();
+        let first = slow_session(&manager, "10.0.0.2:50000").await;
+        assert_begin_does_not_block(&manager, first, "10.0.0.2:50001").await;
+    }
+
+    #[tokio::test]
+    async fn test_preempt_does_not_hold_the_lock() {
+        let (manager, config, _) = manager();
+        config.write().unwrap().session_policy = SessionPolicy::Preempt;
+        let first = slow_session(&manager, "10.0.0.2:50000").await;
+        assert_begin_does_not_block(&manager, first, "10.0.0.3:50000").await;
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (manager, config, _) = manager();
+        config.write().unwrap().session_idle_timeout = Duration::from_secs(5);
+        let id = manager.begin(sender("10.0.0.2:50000")).await.unwrap();
+
+        assert!(!manager.expire_idle(Instant::now()).await);
+        assert!(manager.touch(id).await);
+        assert!(
+            manager
+                .expire_idle(Instant::now() + Duration::from_secs(6))
+                .await
+        );
+        assert_eq!(manager.active_id().await, None);
+    }
+
+    #[test]
+    fn test_admission_policy() {
+        let mut phone_a = sender("10.0.0.2:50000");
+        phone_a.controller_id = Some("AAAA".to_string());
+        let mut phone_b = sender("10.0.0.3:50000");
+        phone_b.controller_id = Some("BBBB".to_string());
+        let none: Vec<String> = Vec::new();
+        let b_first = vec!["bbbb".to_string()];
+
+        assert_eq!(
+            admit(SessionPolicy::Reject, &none, &phone_a, &phone_a.clone()),
+            Admission::Replace
+        );
+        assert_eq!(
+            admit(SessionPolicy::Reject, &none, &phone_a, &phone_b),
+            Admission::Reject
+        );
+        assert_eq!(
+            admit(SessionPolicy::Preempt, &none, &phone_a, &phone_b),
+            Admission::Preempt
+        );
+        assert_eq!(
+            admit(SessionPolicy::Reject, &b_first, &phone_a, &phone_b),
+            Admission::Preempt
+        );
+        assert_eq!(
+            admit(SessionPolicy::Preempt, &b_first, &phone_b, &phone_a),
+            Admission::Reject
+        );
+        assert_eq!(SessionError::Busy(phone_a.address).status_code(), 453);
+    }
+
+    #[tokio::test]
+    async fn test_preempt_notifies_old_sender() {
+        let (manager, config, _) = manager();
+        config.write().unwrap().session_policy = SessionPolicy::Preempt;
+
+        let first = manager.begin(sender("10.0.0.2:50000")).await.unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let token = manager
+            .with_session(first, |session| {
+                session.set_events(tx);
+                session.cancellation()
+            })
+            .await
+            .unwrap();
+
+        let second = manager.begin(sender("10.0.0.3:50000")).await.unwrap();
+        assert_eq!(
+            rx.recv().await,
+            Some(MediaRemote::command_body(RemoteCommand::Stop))
+        );
+        assert!(token.is_cancelled());
+        assert_eq!(manager.active_id().await, Some(second));
+        assert!(config
+            .read()
+            .unwrap()
+            .status
+            .contains(StatusFlags::RECV_SESS_ACTIVE));
+    }
+}
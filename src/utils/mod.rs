@@ -5,6 +5,7 @@
 //! - Logging setup
 //! - Helper functions
 
+pub mod network;
+
 // TODO: Implement utility modules
 // pub mod logger;
-// pub mod network;
@@ -0,0 +1,185 @@
+//! Network interfaces and the device identifier
+//!
+//! Senders cache receivers by `deviceid`, so it has to be unique on the LAN
+//! and stable across restarts. It is the MAC address of the interface the
+//! receiver binds to: `--interface`, or else the interface of the default
+//! route. Where no MAC is available, as in many containers, a random locally
+//! administered one is generated once and kept in the state directory.
+//!
+//! The default route and MAC addresses are read from `/proc` and `/sys`, so
+//! only Linux finds them; other systems always use the stored identifier.
+
+use std::net::IpAddr;
+use std::path::Path;
+
+use tracing::{info, warn};
+
+use crate::config::StateStore;
+
+/// State file holding the generated device identifier
+pub const DEVICE_ID_STATE: &str = "device-id";
+
+/// Kernel routing table
+const ROUTE_TABLE: &str = "/proc/net/route";
+
+/// A 48-bit MAC address
+pub type MacAddress = [u8; 6];
+
+/// `AA:BB:CC:DD:EE:FF`
+pub fn format_mac(mac: &MacAddress) -> String {
+    mac.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Parse six colon-separated hex octets
+pub fn parse_mac(s: &str) -> Option<MacAddress> {
+    let mut mac = [0u8; 6];
+    let mut octets = s.trim().split(':');
+    for byte in mac.iter_mut() {
+        let octet = octets.next()?;
+        if octet.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(octet, 16).ok()?;
+    }
+    octets.next().is_none().then_some(mac)
+}
+
+/// Random unicast MAC with the locally administered bit set
+pub fn random_mac() -> MacAddress {
+    let mut mac: MacAddress = rand::random();
+    mac[0] = (mac[0] | 0x02) & !0x01;
+    mac
+}
+
+/// Interface name for `spec`, which is either a name or one of its addresses
+pub fn resolve_interface(spec: &str) -> Option<String> {
+    let Ok(ip) = spec.parse::<IpAddr>() else {
+        return Some(spec.to_string());
+    };
+    get_if_addrs::get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find(|iface| iface.ip() == ip)
+        .map(|iface| iface.name)
+}
+
+/// Addresses of `spec`, which is either an interface name or one of its
+/// addresses
+pub fn interface_addresses(spec: &str) -> Vec<IpAddr> {
+    if let Ok(ip) = spec.parse::<IpAddr>() {
+        return vec![ip];
+    }
+    get_if_addrs::get_if_addrs()
+        .map(|ifaces| {
+            ifaces
+                .into_iter()
+                .filter(|iface| iface.name == spec)
+                .map(|iface| iface.ip())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Interface of the IPv4 default route; always `None` off Linux
+pub fn default_route_interface() -> Option<String> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    parse_default_route(&std::fs::read_to_string(ROUTE_TABLE).ok()?)
+}
+
+fn parse_default_route(table: &str) -> Option<String> {
+    table.lines().skip(1).find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let iface = fields.next()?;
+        (fields.next()? == "00000000").then(|| iface.to_string())
+    })
+}
+
+/// Hardware address of `name`; `None` for interfaces without one and off
+/// Linux
+pub fn interface_mac(name: &str) -> Option<MacAddress> {
+    if !cfg!(target_os = "linux") || name.contains('/') {
+        return None;
+    }
+    let address = std::fs::read_to_string(Path::new("/sys/class/net").join(name).join("address"));
+    parse_mac(&address.ok()?).filter(|mac| mac.iter().any(|&b| b != 0))
+}
+
+/// Device identifier for a receiver bound to `interface`
+///
+/// Falls back to the identifier persisted in `store`, generating one on
+/// first use. Without a store the fallback changes on every start.
+pub fn device_id(interface: Option<&str>, store: Option<&StateStore>) -> MacAddress {
+    let iface = match interface {
+        Some(spec) => resolve_interface(spec),
+        None => default_route_interface(),
+    };
+    if let Some(mac) = iface.as_deref().and_then(interface_mac) {
+        info!(
+            "Device ID from {}: {}",
+            iface.unwrap_or_default(),
+            format_mac(&mac)
+        );
+        return mac;
+    }
+    if !cfg!(target_os = "linux") {
+        warn!("Interface MAC addresses are only read on Linux; using a stored device ID");
+    }
+
+    let Some(store) = store else {
+        warn!("No interface MAC and no state directory; using a temporary device ID");
+        return random_mac();
+    };
+    match store.read(DEVICE_ID_STATE) {
+        Ok(Some(saved)) => match parse_mac(&saved) {
+            Some(mac) => return mac,
+            None => warn!("Ignoring invalid saved device ID {:?}", saved),
+        },
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read saved device ID: {}", e),
+    }
+    let mac = random_mac();
+    info!("Generated device ID {}", format_mac(&mac));
+    if let Err(e) = store.write(DEVICE_ID_STATE, &format_mac(&mac)) {
+        warn!("Failed to save device ID: {}", e);
+    }
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_id_fallback_is_persisted() {
+        assert_eq!(
+            parse_mac("aa:bb:cc:dd:ee:0f").map(|mac| format_mac(&mac)),
+            Some("AA:BB:CC:DD:EE:0F".to_string())
+        );
+        assert_eq!(parse_mac("aa:bb:cc:dd:ee"), None);
+        assert_eq!(parse_mac("aa:bb:cc:dd:ee:ff:00"), None);
+
+        let mac = random_mac();
+        assert_eq!(mac[0] & 0x03, 0x02);
+
+        let table = "Iface\tDestination\tGateway\n\
+                     eth0\t000200C0\t00000000\n\
+                     wlan0\t00000000\t010200C0\n";
+        assert_eq!(parse_default_route(table).as_deref(), Some("wlan0"));
+
+        let dir = std::env::temp_dir().join(format!("airplay2-device-id-{}", std::process::id()));
+        let store = StateStore::new(&dir);
+        let first = device_id(Some("nonexistent0"), Some(&store));
+        assert_eq!(first[0] & 0x03, 0x02);
+        assert_eq!(device_id(Some("nonexistent0"), Some(&store)), first);
+        assert_eq!(
+            store.read(DEVICE_ID_STATE).unwrap(),
+            Some(format_mac(&first))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}